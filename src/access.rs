@@ -0,0 +1,160 @@
+use crate::config::Config;
+use anyhow::{anyhow, Context, Result};
+use std::net::IpAddr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// Can browse the station list, but not change what is played.
+    ReadOnly(),
+    Control(),
+}
+
+/// A range of IP addresses, such as `192.168.0.0/16`. A plain address is a range of one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Cidr> {
+        let (addr, prefix) = match text.find('/') {
+            Some(pos) => (&text[..pos], Some(&text[pos + 1..])),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address range: {}", text))?;
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix.map(|x| x.parse::<u32>()) {
+            None => max_prefix,
+            Some(Ok(prefix)) if prefix <= max_prefix => prefix,
+            Some(_) => return Err(anyhow!("invalid prefix length in {}", text)),
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            _ => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u32, prefix: u32) -> bool {
+    prefix == 0 || (net ^ ip) >> (bits - prefix) == 0
+}
+
+/// Who may use the client. Configured in the `[access]` section:
+///
+/// ```text
+/// [access]
+/// bind = 127.0.0.1
+/// allow = 192.168.0.0/16
+/// password = secret
+/// read-only-password = guest
+/// ```
+///
+/// Without `allow` entries every address is allowed. Without passwords every user gets the
+/// control role; otherwise the password a user types decides their role.
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    allow: Vec<Cidr>,
+    password: Option<String>,
+    read_only_password: Option<String>,
+}
+
+impl AccessPolicy {
+    pub fn new(config: &Config) -> Result<AccessPolicy> {
+        let mut policy = AccessPolicy::default();
+        for (key, value) in config.section("access") {
+            match key {
+                "allow" => policy.allow.push(Cidr::parse(value)?),
+                "password" => policy.password = Some(value.to_string()),
+                "read-only-password" => policy.read_only_password = Some(value.to_string()),
+                "bind" => (),
+                _ => return Err(anyhow!("unknown access setting: {}", key)),
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip))
+    }
+
+    /// The role of users who haven't typed a password, if they don't need one.
+    pub fn default_role(&self) -> Option<Role> {
+        match (&self.password, &self.read_only_password) {
+            (None, None) => Some(Role::Control()),
+            _ => None,
+        }
+    }
+
+    pub fn role_for(&self, password: &str) -> Option<Role> {
+        if self.password.as_deref() == Some(password) {
+            Some(Role::Control())
+        } else if self.read_only_password.as_deref() == Some(password) {
+            Some(Role::ReadOnly())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_ranges_contain_addresses() {
+        let net = Cidr::parse("192.168.0.0/16").unwrap();
+        assert!(net.contains("192.168.7.1".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.7.1".parse().unwrap()));
+        assert!(!net.contains("192.169.0.1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!(Cidr::parse("::1").unwrap().contains("::1".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("example.com").is_err());
+    }
+
+    #[test]
+    fn passwords_decide_roles() {
+        let config = Config::parse("[access]\npassword = a\nread-only-password = b\n").unwrap();
+        let policy = AccessPolicy::new(&config).unwrap();
+        assert_eq!(policy.default_role(), None);
+        assert_eq!(policy.role_for("a"), Some(Role::Control()));
+        assert_eq!(policy.role_for("b"), Some(Role::ReadOnly()));
+        assert_eq!(policy.role_for("c"), None);
+        assert_eq!(
+            AccessPolicy::default().default_role(),
+            Some(Role::Control())
+        );
+    }
+
+    #[test]
+    fn allowlist_is_optional() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        assert!(AccessPolicy::default().allows(localhost));
+        let config = Config::parse("[access]\nallow = 10.0.0.0/8\n").unwrap();
+        assert!(!AccessPolicy::new(&config).unwrap().allows(localhost));
+    }
+}
@@ -0,0 +1,343 @@
+use crate::config::Config;
+use crate::events::{EventModel, EventProxy, EventTelnet, EventWeb};
+use anyhow::{anyhow, Context, Result};
+use crossbeam::crossbeam_channel::{bounded, Receiver, RecvError, Sender, TrySendError};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// What happens to a message published to a full queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overflow {
+    /// The publisher waits, which slows down whatever produces the messages.
+    Block(),
+    DropNewest(),
+    DropOldest(),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueueSettings {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl QueueSettings {
+    /// Parses `<capacity> <overflow>`, e.g. `64 drop-oldest`.
+    fn parse(text: &str) -> Result<QueueSettings> {
+        let mut words = text.split_whitespace();
+        let capacity = words
+            .next()
+            .unwrap_or("")
+            .parse::<usize>()
+            .ok()
+            .filter(|x| *x > 0)
+            .ok_or_else(|| anyhow!("invalid capacity: {}", text))?;
+        let overflow = match words.next() {
+            Some("block") | None => Overflow::Block(),
+            Some("drop-newest") => Overflow::DropNewest(),
+            Some("drop-oldest") => Overflow::DropOldest(),
+            Some(other) => return Err(anyhow!("invalid overflow policy: {}", other)),
+        };
+        Ok(QueueSettings { capacity, overflow })
+    }
+}
+
+struct Queue<T> {
+    sender: Sender<T>,
+    /// Lets the publisher drop the oldest message. It's weak, so that the queue disconnects
+    /// when its subscription is dropped.
+    receiver: Weak<Receiver<T>>,
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Queue<T> {
+        Queue {
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+struct Shared<T> {
+    name: &'static str,
+    settings: QueueSettings,
+    queues: Mutex<Vec<Queue<T>>>,
+    dropped: AtomicU64,
+    peak: AtomicUsize,
+}
+
+/// Messages of one type, delivered to every subscriber through its own bounded queue.
+/// Messages published while nobody is subscribed are lost.
+pub struct Topic<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Topic<T> {
+    fn clone(&self) -> Topic<T> {
+        Topic {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// The receiving end of a subscription. Clones share the queue.
+pub struct Subscription<T> {
+    receiver: Arc<Receiver<T>>,
+}
+
+impl<T> Clone for Subscription<T> {
+    fn clone(&self) -> Subscription<T> {
+        Subscription {
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+impl<T> Subscription<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.receiver.recv()
+    }
+
+    #[cfg(any(test, feature = "async"))]
+    pub fn recv_timeout(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<T, crossbeam::crossbeam_channel::RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+
+/// A snapshot of a topic's queues.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TopicStats {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub subscribers: usize,
+    /// Messages waiting in the fullest queue.
+    pub depth: usize,
+    /// The largest depth seen so far.
+    pub peak: usize,
+    pub dropped: u64,
+}
+
+impl<T: Clone> Topic<T> {
+    pub fn new(name: &'static str, settings: QueueSettings) -> Topic<T> {
+        Topic {
+            shared: Arc::new(Shared {
+                name,
+                settings,
+                queues: Mutex::new(vec![]),
+                dropped: AtomicU64::new(0),
+                peak: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> Subscription<T> {
+        let (sender, receiver) = bounded(self.shared.settings.capacity);
+        let receiver = Arc::new(receiver);
+        self.shared.queues.lock().unwrap().push(Queue {
+            sender,
+            receiver: Arc::downgrade(&receiver),
+        });
+        Subscription { receiver }
+    }
+
+    pub fn publish(&self, msg: T) {
+        // The lock isn't held while sending, since that may block.
+        let queues = self.shared.queues.lock().unwrap().clone();
+        let mut disconnected = vec![];
+        for queue in queues.iter() {
+            if !self.send(queue, msg.clone()) {
+                disconnected.push(queue.sender.clone());
+            }
+            self.shared
+                .peak
+                .fetch_max(queue.sender.len(), Ordering::Relaxed);
+        }
+        if !disconnected.is_empty() {
+            self.shared
+                .queues
+                .lock()
+                .unwrap()
+                .retain(|x| !disconnected.iter().any(|y| y.same_channel(&x.sender)));
+        }
+    }
+
+    /// Tells whether the subscriber is still there.
+    fn send(&self, queue: &Queue<T>, msg: T) -> bool {
+        let msg = match self.shared.settings.overflow {
+            Overflow::Block() => return queue.sender.send(msg).is_ok(),
+            _ => match queue.sender.try_send(msg) {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(msg)) => msg,
+            },
+        };
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        if self.shared.settings.overflow == Overflow::DropOldest() {
+            if let Some(receiver) = queue.receiver.upgrade() {
+                let _ = receiver.try_recv();
+            }
+            // Another message may have taken the free slot, in which case this one is lost too.
+            let _ = queue.sender.try_send(msg);
+        }
+        true
+    }
+
+    pub fn stats(&self) -> TopicStats {
+        let queues = self.shared.queues.lock().unwrap();
+        TopicStats {
+            name: self.shared.name,
+            capacity: self.shared.settings.capacity,
+            subscribers: queues.len(),
+            depth: queues.iter().map(|x| x.sender.len()).max().unwrap_or(0),
+            peak: self.shared.peak.load(Ordering::Relaxed),
+            dropped: self.shared.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Connects the threads of a single client. Queues are set in the `[bus]` section as
+/// `topic = capacity [block|drop-newest|drop-oldest]`:
+///
+/// ```text
+/// [bus]
+/// model = 4096 block
+/// web = 16 drop-oldest
+/// ```
+#[derive(Clone)]
+pub struct Bus {
+    /// Everything the model reacts to.
+    pub model: Topic<EventModel>,
+    /// Screen updates for telnet sessions. Screens are redrawn after updates are dropped.
+    pub telnet: Topic<EventTelnet>,
+    /// Datagrams for proxies, which may be lost on the way anyway.
+    pub proxy: Topic<EventProxy>,
+    /// States published to web clients. Only the latest one matters.
+    pub web: Topic<EventWeb>,
+}
+
+impl Default for Bus {
+    fn default() -> Bus {
+        let settings = |capacity, overflow| QueueSettings { capacity, overflow };
+        Bus {
+            model: Topic::new("model", settings(4096, Overflow::Block())),
+            telnet: Topic::new("telnet", settings(1024, Overflow::DropNewest())),
+            proxy: Topic::new("proxy", settings(1024, Overflow::DropNewest())),
+            web: Topic::new("web", settings(16, Overflow::DropOldest())),
+        }
+    }
+}
+
+impl Bus {
+    pub fn new(config: &Config) -> Result<Bus> {
+        let mut bus = Bus::default();
+        for (key, value) in config.section("bus") {
+            let settings =
+                QueueSettings::parse(value).with_context(|| format!("invalid queue of {}", key))?;
+            match key {
+                "model" => bus.model = Topic::new("model", settings),
+                "telnet" => bus.telnet = Topic::new("telnet", settings),
+                "proxy" => bus.proxy = Topic::new("proxy", settings),
+                "web" => bus.web = Topic::new("web", settings),
+                _ => return Err(anyhow!("unknown topic: {}", key)),
+            }
+        }
+        Ok(bus)
+    }
+
+    pub fn stats(&self) -> Vec<TopicStats> {
+        vec![
+            self.model.stats(),
+            self.telnet.stats(),
+            self.proxy.stats(),
+            self.web.stats(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn topic(capacity: usize, overflow: Overflow) -> Topic<u32> {
+        Topic::new("test", QueueSettings { capacity, overflow })
+    }
+
+    fn drain(subscription: &Subscription<u32>) -> Vec<u32> {
+        let mut messages = vec![];
+        while let Ok(msg) = subscription.recv_timeout(Duration::from_millis(0)) {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_message() {
+        let topic = topic(8, Overflow::Block());
+        topic.publish(1);
+        let (first, second) = (topic.subscribe(), topic.subscribe());
+        topic.publish(2);
+        topic.publish(3);
+        assert_eq!(drain(&first), vec![2, 3]);
+        assert_eq!(drain(&second), vec![2, 3]);
+        drop(first);
+        topic.publish(4);
+        assert_eq!(topic.stats().subscribers, 1);
+        assert_eq!(drain(&second), vec![4]);
+    }
+
+    #[test]
+    fn full_queues_drop_messages() {
+        let newest = topic(2, Overflow::DropNewest());
+        let oldest = topic(2, Overflow::DropOldest());
+        let (a, b) = (newest.subscribe(), oldest.subscribe());
+        for i in 0..5 {
+            newest.publish(i);
+            oldest.publish(i);
+        }
+        assert_eq!(
+            newest.stats(),
+            TopicStats {
+                name: "test",
+                capacity: 2,
+                subscribers: 1,
+                depth: 2,
+                peak: 2,
+                dropped: 3,
+            }
+        );
+        assert_eq!(drain(&a), vec![0, 1]);
+        assert_eq!(drain(&b), vec![3, 4]);
+        assert_eq!(oldest.stats().dropped, 3);
+        // A blocked publisher waits for the subscriber.
+        let blocking = topic(1, Overflow::Block());
+        let c = blocking.subscribe();
+        blocking.publish(0);
+        let publisher = {
+            let blocking = blocking.clone();
+            std::thread::spawn(move || blocking.publish(1))
+        };
+        assert_eq!(c.recv().unwrap(), 0);
+        publisher.join().unwrap();
+        assert_eq!(c.recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn queues_are_configured() {
+        let config = Config::parse("[bus]\nweb = 4 drop-newest\nmodel = 10\n").unwrap();
+        let bus = Bus::new(&config).unwrap();
+        assert_eq!(bus.web.shared.settings.overflow, Overflow::DropNewest());
+        assert_eq!(bus.model.stats().capacity, 10);
+        assert_eq!(bus.model.shared.settings.overflow, Overflow::Block());
+        // A slow session or socket must not hold up the model.
+        assert_eq!(bus.telnet.shared.settings.overflow, Overflow::DropNewest());
+        assert_eq!(bus.proxy.shared.settings.overflow, Overflow::DropNewest());
+        for invalid in ["web = 0", "web = 4 drop", "radio = 4"].iter() {
+            let config = Config::parse(&format!("[bus]\n{}\n", invalid)).unwrap();
+            assert!(Bus::new(&config).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -1,10 +1,17 @@
 use clap::{App, Arg};
+use std::path::PathBuf;
 
 pub struct CmdArgs {
+    pub bind: Option<String>,
+    pub config: Option<PathBuf>,
+    pub language: Option<String>,
     pub proxy_host: String,
     pub proxy_port: u16,
     pub telnet_port: u16,
     pub timeout: u64,
+    pub timeshift_minutes: u64,
+    pub timeshift_dir: Option<PathBuf>,
+    pub web_port: Option<u16>,
 }
 
 impl CmdArgs {
@@ -48,8 +55,66 @@ impl CmdArgs {
                         _ => Ok(()),
                     }),
             )
+            .arg(
+                Arg::with_name("timeshift_minutes")
+                    .short("B")
+                    .required(false)
+                    .takes_value(true)
+                    .value_name("minutes")
+                    .help("How many minutes of the active station to keep for rewinding.")
+                    .validator(|t| match t.parse::<u64>() {
+                        Err(_) => Err("Must be a non-negative number.".to_string()),
+                        _ => Ok(()),
+                    }),
+            )
+            .arg(
+                Arg::with_name("timeshift_dir")
+                    .short("D")
+                    .required(false)
+                    .takes_value(true)
+                    .value_name("dir")
+                    .help(
+                        "Keep the timeshift buffer in a file in this directory instead of memory.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("config")
+                    .short("c")
+                    .required(false)
+                    .takes_value(true)
+                    .value_name("file")
+                    .help("Read settings, e.g. the keymap, from this file."),
+            )
+            .arg(
+                Arg::with_name("language")
+                    .short("l")
+                    .required(false)
+                    .takes_value(true)
+                    .value_name("lang")
+                    .help("Default language of the user interface: en or pl."),
+            )
+            .arg(
+                Arg::with_name("bind")
+                    .short("b")
+                    .required(false)
+                    .takes_value(true)
+                    .value_name("address")
+                    .help("Accept telnet and web connections only on this address."),
+            )
+            .arg(
+                Arg::with_name("web_port")
+                    .short("w")
+                    .required(false)
+                    .takes_value(true)
+                    .value_name("port")
+                    .help("Serve a web interface on this port.")
+                    .validator(port_validator),
+            )
             .get_matches();
         CmdArgs {
+            bind: matches.value_of("bind").map(String::from),
+            config: matches.value_of("config").map(PathBuf::from),
+            language: matches.value_of("language").map(String::from),
             proxy_host: matches.value_of("proxy_host").unwrap().to_string(),
             proxy_port: matches
                 .value_of("proxy_port")
@@ -65,6 +130,14 @@ impl CmdArgs {
                 Some(t) => t.parse::<u64>().unwrap(),
                 None => 5,
             },
+            timeshift_minutes: match matches.value_of("timeshift_minutes") {
+                Some(t) => t.parse::<u64>().unwrap(),
+                None => 5,
+            },
+            timeshift_dir: matches.value_of("timeshift_dir").map(PathBuf::from),
+            web_port: matches
+                .value_of("web_port")
+                .map(|p| p.parse::<u16>().unwrap()),
         }
     }
 }
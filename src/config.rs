@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Settings read from an INI-like file:
+///
+/// ```text
+/// # comment
+/// [section]
+/// key = value
+/// ```
+///
+/// Keys may repeat. Entries placed before the first section header belong to the "" section.
+#[derive(Default)]
+pub struct Config {
+    entries: Vec<(String, String, String)>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text =
+            read_to_string(path).with_context(|| format!("could not read config {:?}", path))?;
+        Config::parse(&text).with_context(|| format!("invalid config {:?}", path))
+    }
+
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut section = "".to_string();
+        let mut entries = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            match line.find('=') {
+                Some(pos) => entries.push((
+                    section.clone(),
+                    line[..pos].trim().to_string(),
+                    line[pos + 1..].trim().to_string(),
+                )),
+                None => return Err(anyhow!("line {}: expected `key = value`", number + 1)),
+            }
+        }
+        Ok(Config { entries })
+    }
+
+    /// All entries of a section, in the order they appear in the file.
+    pub fn section<'a>(&'a self, name: &str) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        let name = name.to_string();
+        self.entries
+            .iter()
+            .filter(move |(section, _, _)| *section == name)
+            .map(|(_, key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// The last value of a key.
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.section(section)
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_and_entries() {
+        let config =
+            Config::parse("top = 1\n# comment\n[keymap]\nj = down\n k=up \n\n[ui]\n").unwrap();
+        assert_eq!(config.section("").collect::<Vec<_>>(), vec![("top", "1")]);
+        assert_eq!(
+            config.section("keymap").collect::<Vec<_>>(),
+            vec![("j", "down"), ("k", "up")]
+        );
+        assert_eq!(config.section("ui").count(), 0);
+        assert_eq!(config.get("keymap", "k"), Some("up"));
+        assert_eq!(config.get("ui", "language"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(Config::parse("[keymap]\njust text\n").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::Config;
+use crate::i18n::Msg;
+use anyhow::{anyhow, Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many recent payloads a new one is compared with.
+const RECENT_PAYLOADS: usize = 64;
+/// Fewer bytes of frames than this say too little about the audio.
+const MIN_SAMPLE: usize = 256;
+/// Compressed audio looks almost random, at close to 8 bits of entropy per byte. Encoders fill
+/// frames of silence with zeros or repeat the same tiny frame, which is far more predictable.
+const SILENT_ENTROPY: f64 = 5.0;
+
+/// A proxy that keeps in touch but doesn't deliver anything worth playing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeadAir {
+    Silence(),
+    /// No audio arrives, though the proxy still answers.
+    Stalled(),
+    /// The same payloads arrive over and over.
+    Repeating(),
+}
+
+impl DeadAir {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeadAir::Silence() => "silence",
+            DeadAir::Stalled() => "stalled",
+            DeadAir::Repeating() => "repeating",
+        }
+    }
+
+    /// The warning shown to users.
+    pub fn msg(self) -> Msg {
+        match self {
+            DeadAir::Silence() => Msg::DeadAirSilence(),
+            DeadAir::Stalled() => Msg::DeadAirStalled(),
+            DeadAir::Repeating() => Msg::DeadAirRepeating(),
+        }
+    }
+}
+
+/// Read from the `[dead-air]` section, with times in seconds and 0 disabling a check:
+///
+/// ```text
+/// [dead-air]
+/// silence = 10
+/// stall = 5
+/// repeat = 10
+/// failover = true
+/// hook = notify-send "$SKCLIENT_STATION: $SKCLIENT_EVENT"
+/// ```
+///
+/// The hook is a shell command run when dead air starts or ends on a proxy, with the
+/// `SKCLIENT_EVENT` (`silence`, `stalled`, `repeating` or `recovered`), `SKCLIENT_PROXY`,
+/// `SKCLIENT_STATION` and `SKCLIENT_PLAYING` (`1` or `0`) environment variables set.
+#[derive(Clone, Debug)]
+pub struct DeadAirSettings {
+    pub silence: Option<Duration>,
+    pub stall: Option<Duration>,
+    pub repeat: Option<Duration>,
+    /// Whether relays with dead air are treated like silent ones, so that another relay of the
+    /// station is played.
+    pub failover: bool,
+    pub hook: Option<String>,
+}
+
+impl Default for DeadAirSettings {
+    fn default() -> DeadAirSettings {
+        DeadAirSettings {
+            silence: Some(Duration::from_secs(10)),
+            stall: Some(Duration::from_secs(5)),
+            repeat: Some(Duration::from_secs(10)),
+            failover: true,
+            hook: None,
+        }
+    }
+}
+
+impl DeadAirSettings {
+    pub fn new(config: &Config) -> Result<DeadAirSettings> {
+        let mut settings = DeadAirSettings::default();
+        for (key, value) in config.section("dead-air") {
+            let duration = || -> Result<Option<Duration>> {
+                let secs = value
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid value of {}: {}", key, value))?;
+                Ok(Some(Duration::from_secs(secs)).filter(|_| secs > 0))
+            };
+            match key {
+                "silence" => settings.silence = duration()?,
+                "stall" => settings.stall = duration()?,
+                "repeat" => settings.repeat = duration()?,
+                "failover" => {
+                    settings.failover = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(anyhow!("invalid failover: {}", value)),
+                    }
+                }
+                "hook" => settings.hook = Some(value.to_string()),
+                _ => return Err(anyhow!("unknown dead-air setting: {}", key)),
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Runs the hook, if there is one, without waiting for it.
+    pub fn run_hook(&self, event: Option<DeadAir>, addr: SocketAddr, station: &str, playing: bool) {
+        let hook = match &self.hook {
+            Some(hook) => hook,
+            None => return,
+        };
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(hook)
+            .env("SKCLIENT_EVENT", event.map_or("recovered", |x| x.name()))
+            .env("SKCLIENT_PROXY", addr.to_string())
+            .env("SKCLIENT_STATION", station)
+            .env("SKCLIENT_PLAYING", if playing { "1" } else { "0" })
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(err) => log!("could not run dead air hook: {:?}", err),
+        }
+    }
+}
+
+/// Watches the audio of a single proxy.
+pub struct DeadAirDetector {
+    last_audio: Instant,
+    silent_since: Option<Instant>,
+    repeating_since: Option<Instant>,
+    recent: VecDeque<u64>,
+    state: Option<DeadAir>,
+    failover: bool,
+}
+
+impl Default for DeadAirDetector {
+    fn default() -> DeadAirDetector {
+        DeadAirDetector {
+            last_audio: Instant::now(),
+            silent_since: None,
+            repeating_since: None,
+            recent: VecDeque::new(),
+            state: None,
+            failover: false,
+        }
+    }
+}
+
+impl DeadAirDetector {
+    /// Takes the payloads received from the proxy and the audio frames found in them.
+    pub fn feed(&mut self, payloads: &[&[u8]], frames: &[u8], now: Instant) {
+        self.last_audio = now;
+        for payload in payloads.iter().filter(|x| !x.is_empty()) {
+            let mut hasher = DefaultHasher::new();
+            payload.hash(&mut hasher);
+            let hash = hasher.finish();
+            if self.recent.contains(&hash) {
+                self.repeating_since.get_or_insert(now);
+            } else {
+                self.repeating_since = None;
+            }
+            if self.recent.len() == RECENT_PAYLOADS {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(hash);
+        }
+        if frames.len() >= MIN_SAMPLE {
+            if entropy(frames) < SILENT_ENTROPY {
+                self.silent_since.get_or_insert(now);
+            } else {
+                self.silent_since = None;
+            }
+        }
+    }
+
+    /// Updates the state and tells whether it changed. Called periodically.
+    pub fn check(&mut self, now: Instant, settings: &DeadAirSettings) -> bool {
+        let lasted = |since: Option<Instant>, limit: Option<Duration>| match (since, limit) {
+            (Some(since), Some(limit)) => now.duration_since(since) >= limit,
+            _ => false,
+        };
+        let state = if lasted(Some(self.last_audio), settings.stall) {
+            Some(DeadAir::Stalled())
+        } else if lasted(self.silent_since, settings.silence) {
+            Some(DeadAir::Silence())
+        } else if lasted(self.repeating_since, settings.repeat) {
+            Some(DeadAir::Repeating())
+        } else {
+            None
+        };
+        self.failover = settings.failover;
+        let changed = state != self.state;
+        self.state = state;
+        changed
+    }
+
+    pub fn state(&self) -> Option<DeadAir> {
+        self.state
+    }
+
+    /// Whether another relay should be played instead.
+    pub fn is_dead(&self) -> bool {
+        self.failover && self.state.is_some()
+    }
+}
+
+/// Shannon entropy of the bytes, in bits per byte.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|x| **x > 0)
+        .map(|x| {
+            let p = *x as f64 / data.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..1024)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn silence_and_repeats_are_detected() {
+        let settings = DeadAirSettings::default();
+        let mut detector = DeadAirDetector::default();
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        for i in 0..20 {
+            let audio = noise(i);
+            detector.feed(&[&audio], &audio, start + second * i as u32);
+        }
+        assert!(!detector.check(start + second * 20, &settings));
+        // Frames of silence: a header followed by zeros.
+        let mut silence = vec![0xff, 0xfb, 0x90, 0x64];
+        silence.resize(417, 0);
+        for i in 20..31 {
+            detector.feed(&[&silence], &silence, start + second * i);
+        }
+        assert!(detector.check(start + second * 31, &settings));
+        assert_eq!(detector.state(), Some(DeadAir::Silence()));
+        assert!(detector.is_dead());
+        // A short loop of noise.
+        for i in 31..50 {
+            let audio = noise(i as u64 % 3);
+            detector.feed(&[&audio], &audio, start + second * i);
+        }
+        assert!(detector.check(start + second * 50, &settings));
+        assert_eq!(detector.state(), Some(DeadAir::Repeating()));
+        assert!(detector.check(start + second * 60, &settings));
+        assert_eq!(detector.state(), Some(DeadAir::Stalled()));
+        let audio = noise(100);
+        detector.feed(&[&audio], &audio, start + second * 61);
+        assert!(detector.check(start + second * 61, &settings));
+        assert_eq!(detector.state(), None);
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        let config =
+            Config::parse("[dead-air]\nsilence = 0\nstall = 3\nfailover = false\nhook = true\n")
+                .unwrap();
+        let settings = DeadAirSettings::new(&config).unwrap();
+        assert_eq!(settings.silence, None);
+        assert_eq!(settings.stall, Some(Duration::from_secs(3)));
+        assert!(!settings.failover);
+        assert_eq!(settings.hook.as_deref(), Some("true"));
+        for invalid in ["stall = soon", "failover = yes", "volume = 3"].iter() {
+            let config = Config::parse(&format!("[dead-air]\n{}\n", invalid)).unwrap();
+            assert!(DeadAirSettings::new(&config).is_err(), "{}", invalid);
+        }
+    }
+}
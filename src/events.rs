@@ -1,23 +1,52 @@
 use crate::proxy::{IncomingProxyMessage, OutgoingProxyMessage};
+use crate::session::SessionId;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum EventModel {
-    NewTelnetConnection(),
+    Control(ControlCommand),
+    NewTelnetConnection(SessionId),
+    NewWebClient(),
+    TelnetConnectionClosed(SessionId),
     ProxyInput((SocketAddr, IncomingProxyMessage)),
     ProxyServerCrashed(Arc<str>),
     TelnetServerCrashed(Arc<str>),
-    UserInput(Arc<[u8]>),
+    WebServerCrashed(Arc<str>),
+    TelnetEnvironment((SessionId, Vec<(String, String)>)),
+    TelnetTerminalType((SessionId, String)),
+    UserInput((SessionId, Arc<[u8]>)),
     Tick(),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum EventTelnet {
-    Write(Arc<[u8]>),
+    Write((SessionId, Arc<[u8]>)),
+    Close(SessionId),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+pub enum EventWeb {
+    Publish(Arc<str>),
+}
+
+#[derive(Clone, Debug)]
 pub enum EventProxy {
     Write((SocketAddr, OutgoingProxyMessage)),
+    /// The proxy is gone, so what's kept about it can be dropped.
+    Forget(SocketAddr),
+}
+
+#[derive(Clone, Debug)]
+pub enum ControlCommand {
+    Discover(),
+    Play(SocketAddr),
+    Stop(),
+    Pause(),
+    Resume(),
+    Rewind(Duration),
+    SkipToLive(),
+    /// Adds a one-off recording, e.g. `2024-05-01 20:00; 1h; Radio Jazz`.
+    Schedule(String),
 }
@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Id, index, count and the code of the whole message.
+pub const FRAGMENT_HEADER_SIZE: usize = 10;
+/// Keeps fragments below the usual MTU, so that the network doesn't fragment them again.
+pub const FRAGMENT_DATA_SIZE: usize = 1200;
+const MAX_FRAGMENTS: usize = 1024;
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+const MAX_BUFFERED: usize = 4 << 20;
+const MAX_PARTIAL_MESSAGES: usize = 64;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A piece of a message that doesn't fit in one datagram. FRAGMENT messages carry:
+///
+/// ```text
+/// id: u32     the message's number, unique among recent messages of the sender
+/// index: u16
+/// count: u16  number of fragments of the message
+/// code: u16   code of the whole message
+/// data        the index-th piece of the message's content
+/// ```
+#[derive(Debug, Eq, PartialEq)]
+pub struct Fragment {
+    pub id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    pub fn parse(content: &[u8]) -> Result<Fragment> {
+        if content.len() < FRAGMENT_HEADER_SIZE {
+            return Err(anyhow!("fragment too short"));
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([content[i], content[i + 1]]);
+        let fragment = Fragment {
+            id: u32::from_be_bytes([content[0], content[1], content[2], content[3]]),
+            index: u16_at(4),
+            count: u16_at(6),
+            code: u16_at(8),
+            data: content[FRAGMENT_HEADER_SIZE..].to_vec(),
+        };
+        if fragment.index >= fragment.count || fragment.count as usize > MAX_FRAGMENTS {
+            return Err(anyhow!(
+                "invalid fragment {} of {}",
+                fragment.index,
+                fragment.count
+            ));
+        }
+        Ok(fragment)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut content = Vec::with_capacity(FRAGMENT_HEADER_SIZE + self.data.len());
+        content.extend_from_slice(&self.id.to_be_bytes());
+        content.extend_from_slice(&self.index.to_be_bytes());
+        content.extend_from_slice(&self.count.to_be_bytes());
+        content.extend_from_slice(&self.code.to_be_bytes());
+        content.extend_from_slice(&self.data);
+        content
+    }
+}
+
+/// Splits the content of a message into fragments.
+pub fn split(id: u32, code: u16, content: &[u8]) -> Result<Vec<Fragment>> {
+    if content.len() > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("message too long to send: {} bytes", content.len()));
+    }
+    let mut chunks = content.chunks(FRAGMENT_DATA_SIZE).collect::<Vec<_>>();
+    if chunks.is_empty() {
+        chunks.push(content);
+    }
+    let count = u16::try_from(chunks.len()).unwrap();
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, data)| Fragment {
+            id,
+            index: index as u16,
+            count,
+            code,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+struct Partial {
+    code: u16,
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Puts fragmented messages back together. Incomplete messages are dropped after a timeout,
+/// or, oldest first, when they take too much memory.
+#[derive(Default)]
+pub struct Reassembler {
+    partials: HashMap<(SocketAddr, u32), Partial>,
+    buffered: usize,
+}
+
+impl Reassembler {
+    /// Returns the code and content of the message once its last fragment arrives.
+    pub fn push(
+        &mut self,
+        src: SocketAddr,
+        fragment: Fragment,
+        now: Instant,
+    ) -> Result<Option<(u16, Vec<u8>)>> {
+        self.expire(now);
+        let key = (src, fragment.id);
+        if !self.partials.contains_key(&key) {
+            while self.partials.len() >= MAX_PARTIAL_MESSAGES {
+                self.drop_oldest();
+            }
+            let partial = Partial {
+                code: fragment.code,
+                parts: vec![None; fragment.count as usize],
+                missing: fragment.count as usize,
+                size: 0,
+                started: now,
+            };
+            self.partials.insert(key, partial);
+        }
+        let partial = self.partials.get_mut(&key).unwrap();
+        if partial.code != fragment.code || partial.parts.len() != fragment.count as usize {
+            self.remove(&key);
+            return Err(anyhow!("fragments of message {} don't match", fragment.id));
+        }
+        let part = &mut partial.parts[fragment.index as usize];
+        if part.is_some() {
+            return Ok(None);
+        }
+        if partial.size + fragment.data.len() > MAX_MESSAGE_SIZE {
+            self.remove(&key);
+            return Err(anyhow!("fragmented message {} too long", fragment.id));
+        }
+        partial.size += fragment.data.len();
+        partial.missing -= 1;
+        self.buffered += fragment.data.len();
+        *part = Some(fragment.data);
+        if partial.missing == 0 {
+            let partial = self.remove(&key).unwrap();
+            let content = partial.parts.into_iter().flatten().flatten().collect();
+            return Ok(Some((partial.code, content)));
+        }
+        while self.buffered > MAX_BUFFERED {
+            self.drop_oldest();
+        }
+        Ok(None)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .partials
+            .iter()
+            .filter(|(_, x)| now.duration_since(x.started) >= TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired.iter() {
+            log!("dropped incomplete message {} from {}", key.1, key.0);
+            self.remove(key);
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .partials
+            .iter()
+            .min_by_key(|(_, x)| x.started)
+            .map(|(key, _)| *key);
+        if let Some(key) = oldest {
+            log!("no room for incomplete message {} from {}", key.1, key.0);
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &(SocketAddr, u32)) -> Option<Partial> {
+        let partial = self.partials.remove(key)?;
+        self.buffered -= partial.size;
+        Some(partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn src() -> SocketAddr {
+        "10.0.0.1:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_any_order() {
+        let content = (0..5000).map(|x| x as u8).collect::<Vec<_>>();
+        let mut fragments = split(7, 6, &content).unwrap();
+        assert_eq!(fragments.len(), 5);
+        fragments.reverse();
+        let (mut reassembler, now) = (Reassembler::default(), Instant::now());
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            let fragment = Fragment::parse(&fragment.encode()).unwrap();
+            assert_eq!(reassembler.push(src(), fragment, now).unwrap(), None);
+        }
+        assert_eq!(
+            reassembler.push(src(), last, now).unwrap(),
+            Some((6, content))
+        );
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn incomplete_messages_time_out() {
+        let fragments = split(1, 6, &[0; 3000]).unwrap();
+        let (mut reassembler, now) = (Reassembler::default(), Instant::now());
+        let mut fragments = fragments.into_iter();
+        reassembler
+            .push(src(), fragments.next().unwrap(), now)
+            .unwrap();
+        reassembler
+            .push(src(), fragments.next().unwrap(), now)
+            .unwrap();
+        let later = now + TIMEOUT;
+        assert_eq!(
+            reassembler
+                .push(src(), fragments.next().unwrap(), later)
+                .unwrap(),
+            None
+        );
+        assert_eq!(reassembler.partials.len(), 1);
+        assert_eq!(reassembler.buffered, 600);
+    }
+
+    #[test]
+    fn memory_is_limited() {
+        assert!(Fragment::parse(&[0, 0, 0, 1, 0, 2, 0, 2, 0, 6]).is_err());
+        assert!(split(1, 6, &vec![0; MAX_MESSAGE_SIZE + 1]).is_err());
+        let (mut reassembler, now) = (Reassembler::default(), Instant::now());
+        for id in 0..MAX_PARTIAL_MESSAGES as u32 + 10 {
+            let first = split(id, 6, &[0; 2000]).unwrap().remove(0);
+            reassembler.push(src(), first, now).unwrap();
+        }
+        assert_eq!(reassembler.partials.len(), MAX_PARTIAL_MESSAGES);
+        assert_eq!(
+            reassembler.buffered,
+            MAX_PARTIAL_MESSAGES * FRAGMENT_DATA_SIZE
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::fmt;
+
+const MPEG_HEADER_SIZE: usize = 4;
+const ADTS_HEADER_SIZE: usize = 7;
+// If no frame boundary can be found in this much data the stream is passed through unchanged.
+const MAX_SYNC_SEARCH: usize = 64 * 1024;
+
+const MPEG_SAMPLE_RATES: [[u32; 3]; 3] = [
+    [44100, 48000, 32000], // MPEG-1
+    [22050, 24000, 16000], // MPEG-2
+    [11025, 12000, 8000],  // MPEG-2.5
+];
+const MPEG1_BITRATES: [[u32; 14]; 3] = [
+    [
+        32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+const MPEG2_BITRATES: [[u32; 14]; 2] = [
+    [
+        32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+const ADTS_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    Mpeg { version: u8, layer: u8 },
+    Aac { profile: u8 },
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Mpeg { layer, .. } => write!(f, "MP{}", layer),
+            Codec::Aac { profile: 1 } => write!(f, "AAC-LC"),
+            Codec::Aac { .. } => write!(f, "AAC"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StreamInfo {
+    pub codec: Codec,
+    /// In kbit/s. For AAC this is the average over all frames seen so far.
+    pub bitrate: u32,
+    pub sample_rate: u32,
+}
+
+impl fmt::Display for StreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} kbps {}.{} kHz",
+            self.codec,
+            self.bitrate,
+            self.sample_rate / 1000,
+            self.sample_rate % 1000 / 100
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Frame {
+    codec: Codec,
+    bitrate: u32,
+    length: usize,
+    samples: u32,
+    sample_rate: u32,
+}
+
+impl Frame {
+    fn matches(&self, other: &Frame) -> bool {
+        self.codec == other.codec && self.sample_rate == other.sample_rate
+    }
+}
+
+fn parse_mpeg_header(header: &[u8]) -> Option<Frame> {
+    if header.len() < MPEG_HEADER_SIZE || header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    let version = match (header[1] >> 3) & 0b11 {
+        0b11 => 1,
+        0b10 => 2,
+        0b00 => 3, // MPEG-2.5
+        _ => return None,
+    };
+    let layer = match (header[1] >> 1) & 0b11 {
+        0b11 => 1,
+        0b10 => 2,
+        0b01 => 3,
+        _ => return None,
+    };
+    let bitrate_index = (header[2] >> 4) as usize;
+    let sample_rate_index = ((header[2] >> 2) & 0b11) as usize;
+    if bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 || header[3] & 0b11 == 2
+    {
+        return None;
+    }
+    let bitrate = match version {
+        1 => MPEG1_BITRATES[layer - 1][bitrate_index - 1],
+        _ => MPEG2_BITRATES[if layer == 1 { 0 } else { 1 }][bitrate_index - 1],
+    };
+    let sample_rate = MPEG_SAMPLE_RATES[version as usize - 1][sample_rate_index];
+    let padding = ((header[2] >> 1) & 1) as usize;
+    let length = match (layer, version) {
+        (1, _) => ((12 * bitrate * 1000 / sample_rate) as usize + padding) * 4,
+        (2, _) | (3, 1) => (144 * bitrate * 1000 / sample_rate) as usize + padding,
+        _ => (72 * bitrate * 1000 / sample_rate) as usize + padding,
+    };
+    let samples = match (layer, version) {
+        (1, _) => 384,
+        (3, 2) | (3, 3) => 576,
+        _ => 1152,
+    };
+    Some(Frame {
+        codec: Codec::Mpeg {
+            version,
+            layer: layer as u8,
+        },
+        bitrate,
+        length,
+        samples,
+        sample_rate,
+    })
+}
+
+fn parse_adts_header(header: &[u8]) -> Option<Frame> {
+    if header.len() < ADTS_HEADER_SIZE || header[0] != 0xFF || header[1] & 0xF6 != 0xF0 {
+        return None;
+    }
+    let profile = header[2] >> 6;
+    let sample_rate = *ADTS_SAMPLE_RATES.get(((header[2] >> 2) & 0b1111) as usize)?;
+    let length = (((header[3] & 0b11) as usize) << 11)
+        | ((header[4] as usize) << 3)
+        | ((header[5] >> 5) as usize);
+    if length <= ADTS_HEADER_SIZE {
+        return None;
+    }
+    let samples = 1024 * ((header[6] & 0b11) as u32 + 1);
+    Some(Frame {
+        codec: Codec::Aac { profile },
+        bitrate: (length as u64 * 8 * sample_rate as u64 / samples as u64 / 1000) as u32,
+        length,
+        samples,
+        sample_rate,
+    })
+}
+
+fn parse_header(header: &[u8]) -> Option<Frame> {
+    parse_adts_header(header).or_else(|| parse_mpeg_header(header))
+}
+
+enum Sync {
+    Found(usize, Frame),
+    // A header was found, but the data after it has not arrived yet.
+    Pending(usize),
+    NotFound(),
+}
+
+/// Splits a stream of MPEG audio or ADTS AAC into whole frames, so that audio can be cut between
+/// frames when the station changes. Streams in any other format are passed through unchanged.
+#[derive(Default)]
+pub struct FrameParser {
+    aac_bytes: u64,
+    aac_samples: u64,
+    buf: Vec<u8>,
+    corrupted: u64,
+    info: Option<StreamInfo>,
+    passthrough: bool,
+    synced: Option<Frame>,
+    unsynced_bytes: usize,
+}
+
+impl FrameParser {
+    pub fn new() -> FrameParser {
+        FrameParser::default()
+    }
+
+    pub fn info(&self) -> Option<StreamInfo> {
+        self.info
+    }
+
+    /// How many times the parser lost track of frame boundaries.
+    pub fn corrupted(&self) -> u64 {
+        self.corrupted
+    }
+
+    /// Consumes a piece of the stream and returns all frames completed by it.
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.passthrough {
+            return data.to_vec();
+        }
+        if self.info.is_none() {
+            self.unsynced_bytes += data.len();
+        }
+        self.buf.extend_from_slice(data);
+        let mut frames = vec![];
+        let mut pos = 0;
+        loop {
+            match self.synced {
+                Some(prev) => match parse_header(&self.buf[pos..]) {
+                    Some(frame) if frame.matches(&prev) => {
+                        if self.buf.len() - pos < frame.length {
+                            break;
+                        }
+                        frames.extend_from_slice(&self.buf[pos..pos + frame.length]);
+                        pos += frame.length;
+                        self.update_info(&frame);
+                    }
+                    None if self.buf.len() - pos < ADTS_HEADER_SIZE => break,
+                    _ => {
+                        self.corrupted += 1;
+                        self.synced = None;
+                    }
+                },
+                None => match self.find_sync(pos) {
+                    Sync::Found(start, frame) => {
+                        pos = start;
+                        self.synced = Some(frame);
+                    }
+                    Sync::Pending(start) => {
+                        pos = start;
+                        break;
+                    }
+                    Sync::NotFound() => {
+                        // Keep the tail, it may contain the beginning of a header.
+                        pos = self.buf.len().saturating_sub(ADTS_HEADER_SIZE).max(pos);
+                        if self.info.is_none() && self.unsynced_bytes >= MAX_SYNC_SEARCH {
+                            self.passthrough = true;
+                            frames.extend_from_slice(&self.buf);
+                            pos = self.buf.len();
+                        }
+                        break;
+                    }
+                },
+            }
+        }
+        self.buf.drain(..pos);
+        frames
+    }
+
+    /// Looks for a header that is followed by another consistent header.
+    fn find_sync(&self, from: usize) -> Sync {
+        for i in from..self.buf.len() {
+            let frame = match parse_header(&self.buf[i..]) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            match self.buf.get(i + frame.length..) {
+                Some(next) if next.len() >= ADTS_HEADER_SIZE => match parse_header(next) {
+                    Some(next) if frame.matches(&next) => return Sync::Found(i, frame),
+                    _ => continue,
+                },
+                _ => return Sync::Pending(i),
+            }
+        }
+        Sync::NotFound()
+    }
+
+    fn update_info(&mut self, frame: &Frame) {
+        let bitrate = match frame.codec {
+            Codec::Aac { .. } => {
+                self.aac_bytes += frame.length as u64;
+                self.aac_samples += frame.samples as u64;
+                (self.aac_bytes * 8 * frame.sample_rate as u64 / self.aac_samples / 1000) as u32
+            }
+            Codec::Mpeg { .. } => frame.bitrate,
+        };
+        self.info = Some(StreamInfo {
+            codec: frame.codec,
+            bitrate,
+            sample_rate: frame.sample_rate,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG-1 Layer III, 128 kbit/s, 44.1 kHz, no padding: 417 bytes per frame.
+    fn mp3_frame(fill: u8) -> Vec<u8> {
+        let mut frame = vec![fill; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        frame
+    }
+
+    fn adts_frame(fill: u8, length: usize) -> Vec<u8> {
+        let mut frame = vec![fill; length];
+        frame[..7].copy_from_slice(&[
+            0xFF,
+            0xF1,
+            0x50, // AAC-LC, 44.1 kHz
+            0x80 | ((length >> 11) & 0b11) as u8,
+            ((length >> 3) & 0xFF) as u8,
+            (((length & 0b111) << 5) | 0x1F) as u8,
+            0xFC,
+        ]);
+        frame
+    }
+
+    #[test]
+    fn parses_mpeg_headers() {
+        let frame = parse_header(&mp3_frame(0)).unwrap();
+        assert_eq!(frame.length, 417);
+        assert_eq!(frame.bitrate, 128);
+        assert_eq!(frame.sample_rate, 44100);
+        assert_eq!(
+            frame.codec,
+            Codec::Mpeg {
+                version: 1,
+                layer: 3
+            }
+        );
+    }
+
+    #[test]
+    fn parses_adts_headers() {
+        let frame = parse_header(&adts_frame(0, 300)).unwrap();
+        assert_eq!(frame.length, 300);
+        assert_eq!(frame.sample_rate, 44100);
+        assert_eq!(frame.codec, Codec::Aac { profile: 1 });
+    }
+
+    #[test]
+    fn output_is_aligned_to_frames() {
+        let stream = [mp3_frame(1), mp3_frame(2), mp3_frame(3)].concat();
+        let mut parser = FrameParser::new();
+        let mut output = parser.push(&stream[100..500]);
+        assert!(output.is_empty());
+        output.extend(parser.push(&stream[500..1000]));
+        assert_eq!(output, mp3_frame(2));
+        output.extend(parser.push(&stream[1000..]));
+        assert_eq!(output, [mp3_frame(2), mp3_frame(3)].concat());
+        assert_eq!(
+            parser.info().unwrap().to_string(),
+            "MP3 128 kbps 44.1 kHz".to_string()
+        );
+        assert_eq!(parser.corrupted(), 0);
+    }
+
+    #[test]
+    fn corrupted_frames_are_dropped() {
+        let mut garbage = mp3_frame(2);
+        garbage[0] = 0;
+        let stream = [adts_frame(1, 300), garbage, adts_frame(3, 200)].concat();
+        let mut parser = FrameParser::new();
+        let mut output = parser.push(&[adts_frame(0, 250), adts_frame(0, 250)].concat());
+        output.extend(parser.push(&stream));
+        output.extend(parser.push(&adts_frame(4, 250)));
+        assert_eq!(
+            output,
+            [
+                adts_frame(0, 250),
+                adts_frame(0, 250),
+                adts_frame(1, 300),
+                adts_frame(3, 200),
+                adts_frame(4, 250)
+            ]
+            .concat()
+        );
+        assert_eq!(parser.corrupted(), 1);
+    }
+
+    #[test]
+    fn unknown_formats_are_passed_through() {
+        let mut parser = FrameParser::new();
+        let data = vec![7; MAX_SYNC_SEARCH];
+        let output = parser.push(&data);
+        assert_eq!(output.len(), MAX_SYNC_SEARCH);
+        assert_eq!(parser.push(&[1, 2, 3]), vec![1, 2, 3]);
+        assert_eq!(parser.info(), None);
+    }
+}
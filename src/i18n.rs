@@ -0,0 +1,308 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Display;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lang {
+    En(),
+    Pl(),
+}
+
+/// Every piece of text shown to users. Each catalog must translate all of them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Msg {
+    Discover(),
+    Proxy(),
+    Pause(),
+    Resume(),
+    Rewind(),
+    Live(),
+    LiveDelayed(),
+    Quit(),
+    UnknownFormat(),
+    CorruptedFrames(),
+    LostPackets(),
+    DuplicatePackets(),
+    RoundTrip(),
+    Relays(),
+    SortedBy(),
+    SortArrival(),
+    SortName(),
+    SortAddress(),
+    SortLatency(),
+    SortBitrate(),
+    SortFavourites(),
+    SortLastSeen(),
+    DetailAddress(),
+    DetailStation(),
+    DetailInfo(),
+    DetailMeta(),
+    DetailLastContact(),
+    DetailStream(),
+    DetailPackets(),
+    Play(),
+    Stop(),
+    Record(),
+    StopRecording(),
+    Recording(),
+    AddFavourite(),
+    RemoveFavourite(),
+    SetAlias(),
+    Forget(),
+    Back(),
+    AliasPrompt(),
+    RecordingFailure(),
+    ScheduleTitle(),
+    NoJobs(),
+    SchedulePrompt(),
+    ScheduleAdded(),
+    InvalidSchedule(),
+    JobPending(),
+    JobWaiting(),
+    JobRecording(),
+    JobFinished(),
+    JobMissed(),
+    JobFailed(),
+    Searching(),
+    Filter(),
+    TimeshiftFailure(),
+    AudioOutputFailure(),
+    DeadAirSilence(),
+    DeadAirStalled(),
+    DeadAirRepeating(),
+    StateIdle(),
+    StatePlaying(),
+    StatePaused(),
+    StateLost(),
+    ProxyCount(),
+    Output(),
+    PasswordPrompt(),
+    WrongPassword(),
+    ReadOnly(),
+    ServerBusy(),
+    IdleWarning(),
+}
+
+impl Lang {
+    /// Accepts language codes and locale names such as `en`, `pl_PL.UTF-8` or `en-GB`.
+    pub fn parse(name: &str) -> Result<Lang> {
+        let code: String = name
+            .chars()
+            .take_while(|x| x.is_ascii_alphabetic())
+            .collect::<String>()
+            .to_lowercase();
+        match code.as_str() {
+            "en" | "c" | "posix" => Ok(Lang::En()),
+            "pl" => Ok(Lang::Pl()),
+            _ => Err(anyhow!("unsupported language: {}", name)),
+        }
+    }
+
+    pub fn tr(self, msg: Msg) -> &'static str {
+        match self {
+            Lang::En() => english(msg),
+            Lang::Pl() => polish(msg),
+        }
+    }
+
+    /// Translates a message and substitutes its `{}` placeholders with `args`, in order.
+    pub fn format(self, msg: Msg, args: &[&dyn Display]) -> String {
+        let mut parts = self.tr(msg).split("{}");
+        let mut text = parts.next().unwrap_or("").to_string();
+        for (i, part) in parts.enumerate() {
+            if let Some(arg) = args.get(i) {
+                text.push_str(&arg.to_string());
+            }
+            text.push_str(part);
+        }
+        text
+    }
+}
+
+fn english(msg: Msg) -> &'static str {
+    match msg {
+        Msg::Discover() => "Search for proxies",
+        Msg::Proxy() => "Proxy",
+        Msg::Pause() => "Pause",
+        Msg::Resume() => "Resume",
+        Msg::Rewind() => "Rewind {} s",
+        Msg::Live() => "Live",
+        Msg::LiveDelayed() => "Live ({})",
+        Msg::Quit() => "Quit",
+        Msg::UnknownFormat() => "Unknown format",
+        Msg::CorruptedFrames() => "corrupted frames: {}",
+        Msg::LostPackets() => "lost packets: {}",
+        Msg::DuplicatePackets() => "duplicate packets: {}",
+        Msg::RoundTrip() => "RTT min/avg/max: {}/{}/{} ms",
+        Msg::Relays() => "{} relays",
+        Msg::SortedBy() => "Sorted by {}",
+        Msg::SortArrival() => "discovery",
+        Msg::SortName() => "name",
+        Msg::SortAddress() => "address",
+        Msg::SortLatency() => "latency",
+        Msg::SortBitrate() => "bitrate",
+        Msg::SortFavourites() => "favourites",
+        Msg::SortLastSeen() => "last seen",
+        Msg::DetailAddress() => "Address: {}",
+        Msg::DetailStation() => "Station: {}",
+        Msg::DetailInfo() => "Introduction: {}",
+        Msg::DetailMeta() => "Metadata: {}",
+        Msg::DetailLastContact() => "Last contact: {} s ago",
+        Msg::DetailStream() => "Stream: {}",
+        Msg::DetailPackets() => "Packets received: {}, late: {}",
+        Msg::Play() => "Play",
+        Msg::Stop() => "Stop",
+        Msg::Record() => "Record",
+        Msg::StopRecording() => "Stop recording",
+        Msg::Recording() => "REC",
+        Msg::AddFavourite() => "Add to favourites",
+        Msg::RemoveFavourite() => "Remove from favourites",
+        Msg::SetAlias() => "Set alias",
+        Msg::Forget() => "Forget",
+        Msg::Back() => "Back",
+        Msg::AliasPrompt() => "Alias: {}_",
+        Msg::RecordingFailure() => "Error: could not record audio",
+        Msg::ScheduleTitle() => "Scheduled recordings",
+        Msg::NoJobs() => "Nothing is scheduled",
+        Msg::SchedulePrompt() => "New recording (time; duration; station): {}_",
+        Msg::ScheduleAdded() => "The recording was scheduled",
+        Msg::InvalidSchedule() => "Error: expected e.g. 2024-05-01 20:00; 1h30m; Radio Jazz",
+        Msg::JobPending() => "planned",
+        Msg::JobWaiting() => "waiting for the station",
+        Msg::JobRecording() => "recording",
+        Msg::JobFinished() => "finished",
+        Msg::JobMissed() => "missed",
+        Msg::JobFailed() => "failed",
+        Msg::Searching() => "Search: {}_",
+        Msg::Filter() => "Filter: {}",
+        Msg::TimeshiftFailure() => "Error: the timeshift buffer failed",
+        Msg::AudioOutputFailure() => "Error: could not play audio",
+        Msg::DeadAirSilence() => "Warning: the station is silent",
+        Msg::DeadAirStalled() => "Warning: the station stopped sending audio",
+        Msg::DeadAirRepeating() => "Warning: the station keeps repeating the same audio",
+        Msg::StateIdle() => "Not playing",
+        Msg::StatePlaying() => "Playing: {}",
+        Msg::StatePaused() => "Paused: {}",
+        Msg::StateLost() => "Station lost",
+        Msg::ProxyCount() => "Proxies: {}",
+        Msg::Output() => "Output: {}",
+        Msg::PasswordPrompt() => "Password: {}",
+        Msg::WrongPassword() => "Wrong password",
+        Msg::ReadOnly() => "This session is read-only",
+        Msg::ServerBusy() => "The server is busy, please try again later.",
+        Msg::IdleWarning() => "Disconnecting in {} s due to inactivity",
+    }
+}
+
+fn polish(msg: Msg) -> &'static str {
+    match msg {
+        Msg::Discover() => "Szukaj pośrednika",
+        Msg::Proxy() => "Pośrednik",
+        Msg::Pause() => "Pauza",
+        Msg::Resume() => "Wznów",
+        Msg::Rewind() => "Cofnij o {} s",
+        Msg::Live() => "Na żywo",
+        Msg::LiveDelayed() => "Na żywo ({})",
+        Msg::Quit() => "Koniec",
+        Msg::UnknownFormat() => "Nieznany format",
+        Msg::CorruptedFrames() => "uszkodzone ramki: {}",
+        Msg::LostPackets() => "utracone pakiety: {}",
+        Msg::DuplicatePackets() => "zdublowane pakiety: {}",
+        Msg::RoundTrip() => "RTT min/śr./maks.: {}/{}/{} ms",
+        Msg::Relays() => "przekaźniki: {}",
+        Msg::SortedBy() => "Sortowanie: {}",
+        Msg::SortArrival() => "kolejność wykrycia",
+        Msg::SortName() => "nazwa",
+        Msg::SortAddress() => "adres",
+        Msg::SortLatency() => "opóźnienie",
+        Msg::SortBitrate() => "przepływność",
+        Msg::SortFavourites() => "ulubione",
+        Msg::SortLastSeen() => "ostatni kontakt",
+        Msg::DetailAddress() => "Adres: {}",
+        Msg::DetailStation() => "Stacja: {}",
+        Msg::DetailInfo() => "Przedstawienie: {}",
+        Msg::DetailMeta() => "Metadane: {}",
+        Msg::DetailLastContact() => "Ostatni kontakt: {} s temu",
+        Msg::DetailStream() => "Strumień: {}",
+        Msg::DetailPackets() => "Odebrane pakiety: {}, spóźnione: {}",
+        Msg::Play() => "Odtwarzaj",
+        Msg::Stop() => "Zatrzymaj",
+        Msg::Record() => "Nagrywaj",
+        Msg::StopRecording() => "Zatrzymaj nagrywanie",
+        Msg::Recording() => "NAGRYWA",
+        Msg::AddFavourite() => "Dodaj do ulubionych",
+        Msg::RemoveFavourite() => "Usuń z ulubionych",
+        Msg::SetAlias() => "Ustaw alias",
+        Msg::Forget() => "Zapomnij",
+        Msg::Back() => "Wróć",
+        Msg::AliasPrompt() => "Alias: {}_",
+        Msg::RecordingFailure() => "Błąd: nie można nagrywać dźwięku",
+        Msg::ScheduleTitle() => "Zaplanowane nagrania",
+        Msg::NoJobs() => "Nic nie zaplanowano",
+        Msg::SchedulePrompt() => "Nowe nagranie (czas; długość; stacja): {}_",
+        Msg::ScheduleAdded() => "Zaplanowano nagranie",
+        Msg::InvalidSchedule() => "Błąd: oczekiwano np. 2024-05-01 20:00; 1h30m; Radio Jazz",
+        Msg::JobPending() => "zaplanowane",
+        Msg::JobWaiting() => "czeka na stację",
+        Msg::JobRecording() => "nagrywa",
+        Msg::JobFinished() => "zakończone",
+        Msg::JobMissed() => "pominięte",
+        Msg::JobFailed() => "nieudane",
+        Msg::Searching() => "Szukaj: {}_",
+        Msg::Filter() => "Filtr: {}",
+        Msg::TimeshiftFailure() => "Błąd: bufor przesunięcia czasowego zawiódł",
+        Msg::AudioOutputFailure() => "Błąd: nie można odtworzyć dźwięku",
+        Msg::DeadAirSilence() => "Uwaga: stacja milczy",
+        Msg::DeadAirStalled() => "Uwaga: stacja przestała wysyłać dźwięk",
+        Msg::DeadAirRepeating() => "Uwaga: stacja powtarza w kółko ten sam dźwięk",
+        Msg::StateIdle() => "Nic nie gra",
+        Msg::StatePlaying() => "Gra: {}",
+        Msg::StatePaused() => "Wstrzymano: {}",
+        Msg::StateLost() => "Utracono stację",
+        Msg::ProxyCount() => "Pośredniki: {}",
+        Msg::Output() => "Wyjście: {}",
+        Msg::PasswordPrompt() => "Hasło: {}",
+        Msg::WrongPassword() => "Błędne hasło",
+        Msg::ReadOnly() => "Ta sesja jest tylko do odczytu",
+        Msg::ServerBusy() => "Serwer jest zajęty, spróbuj ponownie później.",
+        Msg::IdleWarning() => "Rozłączenie za {} s z powodu braku aktywności",
+    }
+}
+
+/// Picks the language from telnet environment variables, following the POSIX precedence.
+pub fn lang_from_environment(vars: &[(String, String)]) -> Option<Lang> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"].iter().find_map(|name| {
+        vars.iter()
+            .filter(|(var, value)| var == name && !value.is_empty())
+            .find_map(|(_, value)| Lang::parse(value).ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_locale_names() {
+        assert_eq!(Lang::parse("pl_PL.UTF-8").unwrap(), Lang::Pl());
+        assert_eq!(Lang::parse("en-GB").unwrap(), Lang::En());
+        assert_eq!(Lang::parse("EN").unwrap(), Lang::En());
+        assert!(Lang::parse("xx").is_err());
+    }
+
+    #[test]
+    fn format_fills_placeholders() {
+        assert_eq!(Lang::En().format(Msg::Rewind(), &[&10]), "Rewind 10 s");
+        assert_eq!(Lang::Pl().format(Msg::Filter(), &[&"abc"]), "Filtr: abc");
+    }
+
+    #[test]
+    fn environment_precedence_is_respected() {
+        let vars = vec![
+            ("LANG".to_string(), "en_US.UTF-8".to_string()),
+            ("LC_ALL".to_string(), "pl_PL".to_string()),
+        ];
+        assert_eq!(lang_from_environment(&vars), Some(Lang::Pl()));
+        assert_eq!(lang_from_environment(&vars[..1]), Some(Lang::En()));
+        assert_eq!(lang_from_environment(&[]), None);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::Config;
+use crate::keys::Key;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Up(),
+    Down(),
+    Home(),
+    End(),
+    PageUp(),
+    PageDown(),
+    Select(),
+    Discover(),
+    Stop(),
+    Quit(),
+    Search(),
+    Pause(),
+    Rewind(),
+    SkipToLive(),
+    Redraw(),
+    Expand(),
+    Collapse(),
+    Sort(),
+    Details(),
+    NextOutput(),
+    Schedule(),
+}
+
+impl Action {
+    /// Whether the action changes what is played, which read-only users can't do.
+    pub fn requires_control(self) -> bool {
+        matches!(
+            self,
+            Action::Select()
+                | Action::Discover()
+                | Action::Stop()
+                | Action::Quit()
+                | Action::Pause()
+                | Action::Rewind()
+                | Action::SkipToLive()
+        )
+    }
+
+    fn parse(name: &str) -> Result<Action> {
+        Ok(match name {
+            "up" => Action::Up(),
+            "down" => Action::Down(),
+            "home" => Action::Home(),
+            "end" => Action::End(),
+            "page-up" => Action::PageUp(),
+            "page-down" => Action::PageDown(),
+            "select" => Action::Select(),
+            "discover" => Action::Discover(),
+            "stop" => Action::Stop(),
+            "quit" => Action::Quit(),
+            "search" => Action::Search(),
+            "pause" => Action::Pause(),
+            "rewind" => Action::Rewind(),
+            "live" => Action::SkipToLive(),
+            "redraw" => Action::Redraw(),
+            "expand" => Action::Expand(),
+            "collapse" => Action::Collapse(),
+            "sort" => Action::Sort(),
+            "details" => Action::Details(),
+            "next-output" => Action::NextOutput(),
+            "schedule" => Action::Schedule(),
+            _ => return Err(anyhow!("unknown action: {}", name)),
+        })
+    }
+}
+
+const DEFAULT_BINDINGS: &[(Key, Action)] = &[
+    (Key::Up(), Action::Up()),
+    (Key::Char('k'), Action::Up()),
+    (Key::Down(), Action::Down()),
+    (Key::Char('j'), Action::Down()),
+    (Key::Home(), Action::Home()),
+    (Key::Char('g'), Action::Home()),
+    (Key::End(), Action::End()),
+    (Key::Char('G'), Action::End()),
+    (Key::PageUp(), Action::PageUp()),
+    (Key::PageDown(), Action::PageDown()),
+    (Key::Enter(), Action::Select()),
+    (Key::Char('d'), Action::Discover()),
+    (Key::Char('s'), Action::Stop()),
+    (Key::Char('q'), Action::Quit()),
+    (Key::Char('/'), Action::Search()),
+    (Key::Char(' '), Action::Pause()),
+    (Key::Char('r'), Action::Rewind()),
+    (Key::Char('l'), Action::SkipToLive()),
+    (Key::Ctrl('l'), Action::Redraw()),
+    (Key::Right(), Action::Expand()),
+    (Key::Left(), Action::Collapse()),
+    (Key::Char('o'), Action::Sort()),
+    (Key::Char('i'), Action::Details()),
+    (Key::Tab(), Action::NextOutput()),
+    (Key::Char('c'), Action::Schedule()),
+];
+
+/// Maps keys to actions. The defaults can be changed in the `[keymap]` section of the config
+/// file with entries such as `x = quit` or `ctrl-d = discover`; `x = none` removes a binding.
+pub struct Keymap {
+    bindings: HashMap<Key, Action>,
+}
+
+impl Keymap {
+    pub fn new(config: &Config) -> Result<Keymap> {
+        let mut bindings: HashMap<Key, Action> = DEFAULT_BINDINGS.iter().cloned().collect();
+        for (key, action) in config.section("keymap") {
+            let key = Key::parse(key).context("invalid keymap")?;
+            match action {
+                "none" => bindings.remove(&key),
+                _ => bindings.insert(key, Action::parse(action).context("invalid keymap")?),
+            };
+        }
+        Ok(Keymap { bindings })
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Action> {
+        self.bindings.get(key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_overrides_defaults() {
+        let config = Config::parse("[keymap]\nx = quit\nq = none\nctrl-d = discover\n").unwrap();
+        let keymap = Keymap::new(&config).unwrap();
+        assert_eq!(keymap.get(&Key::Char('x')), Some(Action::Quit()));
+        assert_eq!(keymap.get(&Key::Char('q')), None);
+        assert_eq!(keymap.get(&Key::Ctrl('d')), Some(Action::Discover()));
+        assert_eq!(keymap.get(&Key::Char('j')), Some(Action::Down()));
+    }
+
+    #[test]
+    fn unknown_actions_are_rejected() {
+        let config = Config::parse("[keymap]\nx = fly\n").unwrap();
+        assert!(Keymap::new(&config).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::{anyhow, Result};
+use std::str::from_utf8;
+
+const ESC: u8 = 27;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Up(),
+    Down(),
+    Left(),
+    Right(),
+    Home(),
+    End(),
+    PageUp(),
+    PageDown(),
+    Insert(),
+    Delete(),
+    Enter(),
+    Backspace(),
+    Tab(),
+    Escape(),
+    Char(char),
+    Ctrl(char),
+    F(u8),
+}
+
+impl Key {
+    /// Parses key names used in the config file, e.g. `up`, `ctrl-l`, `f5` or `q`.
+    pub fn parse(name: &str) -> Result<Key> {
+        let lower = name.to_lowercase();
+        let key = match lower.as_str() {
+            "up" => Key::Up(),
+            "down" => Key::Down(),
+            "left" => Key::Left(),
+            "right" => Key::Right(),
+            "home" => Key::Home(),
+            "end" => Key::End(),
+            "pageup" => Key::PageUp(),
+            "pagedown" => Key::PageDown(),
+            "insert" => Key::Insert(),
+            "delete" => Key::Delete(),
+            "enter" => Key::Enter(),
+            "backspace" => Key::Backspace(),
+            "tab" => Key::Tab(),
+            "esc" => Key::Escape(),
+            "space" => Key::Char(' '),
+            _ if lower.starts_with("ctrl-") && lower.chars().count() == 6 => {
+                Key::Ctrl(lower.chars().last().unwrap())
+            }
+            _ if lower.starts_with('f') && lower.len() > 1 => match lower[1..].parse::<u8>() {
+                Ok(n @ 1..=12) => Key::F(n),
+                _ => return Err(anyhow!("unknown key: {}", name)),
+            },
+            _ if name.chars().count() == 1 => Key::Char(name.chars().next().unwrap()),
+            _ => return Err(anyhow!("unknown key: {}", name)),
+        };
+        Ok(key)
+    }
+}
+
+enum State {
+    Ground(),
+    Escape(),
+    Csi(Vec<u8>),
+    Ss3(),
+    AfterCr(),
+    Utf8(Vec<u8>),
+}
+
+/// Turns the bytes typed by a telnet user into keys. Understands ANSI/VT100 escape sequences,
+/// both in normal and application cursor mode. Telnet commands must be removed beforehand.
+pub struct KeyDecoder {
+    state: State,
+}
+
+impl KeyDecoder {
+    pub fn new() -> KeyDecoder {
+        KeyDecoder {
+            state: State::Ground(),
+        }
+    }
+
+    pub fn feed(&mut self, input: &[u8]) -> Vec<Key> {
+        let mut keys = vec![];
+        for byte in input {
+            self.feed_byte(*byte, &mut keys);
+        }
+        // Terminals send escape sequences in one piece, so a trailing ESC is the Escape key.
+        if let State::Escape() = self.state {
+            keys.push(Key::Escape());
+            self.state = State::Ground();
+        }
+        keys
+    }
+
+    fn feed_byte(&mut self, byte: u8, keys: &mut Vec<Key>) {
+        let state = std::mem::replace(&mut self.state, State::Ground());
+        self.state = match state {
+            State::Ground() => self.ground(byte, keys),
+            State::AfterCr() if byte == 0 || byte == b'\n' => State::Ground(),
+            State::AfterCr() => self.ground(byte, keys),
+            State::Escape() => match byte {
+                b'[' => State::Csi(vec![]),
+                b'O' => State::Ss3(),
+                ESC => {
+                    keys.push(Key::Escape());
+                    State::Escape()
+                }
+                _ => {
+                    keys.push(Key::Escape());
+                    self.ground(byte, keys)
+                }
+            },
+            State::Csi(mut params) => match byte {
+                0x20..=0x3F => {
+                    params.push(byte);
+                    State::Csi(params)
+                }
+                0x40..=0x7E => {
+                    keys.extend(csi_key(&params, byte));
+                    State::Ground()
+                }
+                _ => State::Ground(),
+            },
+            State::Ss3() => {
+                keys.extend(match byte {
+                    b'A' => Some(Key::Up()),
+                    b'B' => Some(Key::Down()),
+                    b'C' => Some(Key::Right()),
+                    b'D' => Some(Key::Left()),
+                    b'H' => Some(Key::Home()),
+                    b'F' => Some(Key::End()),
+                    b'M' => Some(Key::Enter()),
+                    b'P'..=b'S' => Some(Key::F(byte - b'P' + 1)),
+                    _ => None,
+                });
+                State::Ground()
+            }
+            State::Utf8(mut bytes) => {
+                bytes.push(byte);
+                match from_utf8(&bytes) {
+                    Ok(text) => {
+                        keys.extend(text.chars().next().map(Key::Char));
+                        State::Ground()
+                    }
+                    Err(err) if err.error_len().is_none() && bytes.len() < 4 => State::Utf8(bytes),
+                    Err(_) => State::Ground(),
+                }
+            }
+        };
+    }
+
+    fn ground(&mut self, byte: u8, keys: &mut Vec<Key>) -> State {
+        match byte {
+            ESC => return State::Escape(),
+            b'\r' => {
+                keys.push(Key::Enter());
+                return State::AfterCr();
+            }
+            b'\n' => keys.push(Key::Enter()),
+            b'\t' => keys.push(Key::Tab()),
+            8 | 127 => keys.push(Key::Backspace()),
+            1..=26 => keys.push(Key::Ctrl((b'a' + byte - 1) as char)),
+            32..=126 => keys.push(Key::Char(byte as char)),
+            0xC0..=0xF7 => return State::Utf8(vec![byte]),
+            _ => (),
+        }
+        State::Ground()
+    }
+}
+
+fn csi_key(params: &[u8], final_byte: u8) -> Option<Key> {
+    // Modifiers, as in `ESC [ 1 ; 5 A`, are ignored.
+    let first_param = params
+        .split(|x| *x == b';')
+        .next()
+        .and_then(|x| from_utf8(x).ok())
+        .and_then(|x| x.parse::<u32>().ok());
+    match (final_byte, first_param) {
+        (b'A', _) => Some(Key::Up()),
+        (b'B', _) => Some(Key::Down()),
+        (b'C', _) => Some(Key::Right()),
+        (b'D', _) => Some(Key::Left()),
+        (b'H', _) => Some(Key::Home()),
+        (b'F', _) => Some(Key::End()),
+        (b'~', Some(1)) | (b'~', Some(7)) => Some(Key::Home()),
+        (b'~', Some(2)) => Some(Key::Insert()),
+        (b'~', Some(3)) => Some(Key::Delete()),
+        (b'~', Some(4)) | (b'~', Some(8)) => Some(Key::End()),
+        (b'~', Some(5)) => Some(Key::PageUp()),
+        (b'~', Some(6)) => Some(Key::PageDown()),
+        (b'~', Some(n @ 11..=15)) => Some(Key::F((n - 10) as u8)),
+        (b'~', Some(n @ 17..=21)) => Some(Key::F((n - 11) as u8)),
+        (b'~', Some(n @ 23..=24)) => Some(Key::F((n - 12) as u8)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escape_sequences() {
+        let mut decoder = KeyDecoder::new();
+        let input = b"\x1b[A\x1bOB\x1b[5~\x1b[1;5C\x1b[H\x1b[24~\x1bOP";
+        assert_eq!(
+            decoder.feed(input),
+            vec![
+                Key::Up(),
+                Key::Down(),
+                Key::PageUp(),
+                Key::Right(),
+                Key::Home(),
+                Key::F(12),
+                Key::F(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_enter_variants() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(
+            decoder.feed(b"\r\0\r\n\rx\n"),
+            vec![
+                Key::Enter(),
+                Key::Enter(),
+                Key::Enter(),
+                Key::Char('x'),
+                Key::Enter()
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_characters_and_control_keys() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(
+            decoder.feed("jź\x0c\x7f\x1b".as_bytes()),
+            vec![
+                Key::Char('j'),
+                Key::Char('ź'),
+                Key::Ctrl('l'),
+                Key::Backspace(),
+                Key::Escape()
+            ]
+        );
+    }
+
+    #[test]
+    fn sequences_can_be_split_between_reads() {
+        let mut decoder = KeyDecoder::new();
+        assert_eq!(decoder.feed(b"\x1b[2"), vec![]);
+        assert_eq!(decoder.feed(b"~"), vec![Key::Insert()]);
+    }
+
+    #[test]
+    fn parses_key_names() {
+        assert_eq!(Key::parse("PageUp").unwrap(), Key::PageUp());
+        assert_eq!(Key::parse("ctrl-l").unwrap(), Key::Ctrl('l'));
+        assert_eq!(Key::parse("f5").unwrap(), Key::F(5));
+        assert_eq!(Key::parse("G").unwrap(), Key::Char('G'));
+        assert_eq!(Key::parse("space").unwrap(), Key::Char(' '));
+        assert!(Key::parse("hyper-x").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// Round-trip times to a proxy, measured with PING and PONG messages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Latency {
+    min: Option<Duration>,
+    max: Duration,
+    total: Duration,
+    samples: u32,
+}
+
+impl Latency {
+    pub fn add(&mut self, rtt: Duration) {
+        self.min = Some(self.min.map_or(rtt, |x| x.min(rtt)));
+        self.max = self.max.max(rtt);
+        self.total += rtt;
+        self.samples += 1;
+    }
+
+    /// The minimum, average and maximum round-trip time, if the proxy ever answered a PING.
+    pub fn stats(&self) -> Option<(Duration, Duration, Duration)> {
+        let min = self.min?;
+        Some((min, self.total / self.samples, self.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_need_samples() {
+        assert_eq!(Latency::default().stats(), None);
+    }
+
+    #[test]
+    fn min_avg_and_max_are_kept() {
+        let mut latency = Latency::default();
+        for ms in [30, 10, 20].iter() {
+            latency.add(Duration::from_millis(*ms));
+        }
+        assert_eq!(
+            latency.stats(),
+            Some((
+                Duration::from_millis(10),
+                Duration::from_millis(20),
+                Duration::from_millis(30)
+            ))
+        );
+    }
+}
@@ -1,9 +1,17 @@
 #![macro_use]
-use crate::channels::CHANNEL_LOG_R;
+use crossbeam::crossbeam_channel::{unbounded, Receiver, Sender};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // Logging stays global, so that any code can log without a handle to the client.
+    static ref CHANNEL_LOG: (Sender<String>, Receiver<String>) = unbounded();
+    pub static ref CHANNEL_LOG_S: Sender<String> = CHANNEL_LOG.0.clone();
+    pub static ref CHANNEL_LOG_R: Receiver<String> = CHANNEL_LOG.1.clone();
+}
 
 macro_rules! log {
     ($($arg:tt)*) => {
-        crate::channels::CHANNEL_LOG_S.send(format!($($arg)*)).unwrap()
+        crate::log::CHANNEL_LOG_S.send(format!($($arg)*)).unwrap()
     };
 }
 
@@ -22,7 +30,7 @@ pub mod tests {
     rusty_fork_test! {
         #[test]
         fn log_sends_event() {
-            const MSG: &'static str = "test message";
+            const MSG: &str = "test message";
             log!("{}", MSG);
             match CHANNEL_LOG_R.recv_timeout(Duration::from_secs(1)).unwrap().as_str() {
                 MSG => (),
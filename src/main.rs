@@ -5,15 +5,44 @@ use std::{panic, process};
 #[rustfmt::skip] mod log;
 #[rustfmt::skip] mod util;
 
-mod channels;
+mod access;
+mod bus;
 mod cmd;
+mod config;
+mod dead_air;
 mod events;
+mod fragments;
+mod frames;
+mod i18n;
+mod keymap;
+mod keys;
+mod latency;
 mod model;
+mod outputs;
+mod player;
 mod proxy;
+mod proxy_policy;
+mod recorder;
+#[cfg(feature = "async")]
+mod runtime;
+mod schedule;
+mod screen;
+mod secure;
+mod sequencer;
+mod session;
+mod sort;
+mod stations;
+mod tags;
 mod telnet;
+#[cfg(test)]
+mod test_proxy;
+mod theme;
+mod timeshift;
 mod ui;
+mod web;
 
 use cmd::CmdArgs;
+use config::Config;
 use model::Model;
 
 fn main() -> Result<()> {
@@ -24,12 +53,11 @@ fn main() -> Result<()> {
     }));
 
     let args = CmdArgs::get();
-    let mut model = Model::new(
-        args.proxy_host.as_str(),
-        args.proxy_port,
-        args.telnet_port,
-        args.timeout,
-    )?;
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut model = Model::new(&args, &config)?;
 
     model.start()?;
 
@@ -1,58 +1,201 @@
-use crate::channels::{CHANNEL_MODEL_R, CHANNEL_MODEL_S};
-use crate::events::EventModel;
+use crate::access::{AccessPolicy, Role};
+use crate::bus::{Bus, Subscription};
+use crate::cmd::CmdArgs;
+use crate::config::Config;
+use crate::dead_air::{DeadAir, DeadAirDetector, DeadAirSettings};
+use crate::events::{ControlCommand, EventModel, EventWeb};
+use crate::frames::FrameParser;
+use crate::i18n::{lang_from_environment, Lang, Msg};
+use crate::keymap::{Action, Keymap};
+use crate::keys::Key;
+use crate::latency::Latency;
 use crate::log::begin_logging;
+use crate::outputs::{self, Output, Sink};
 use crate::proxy;
 use crate::proxy::{IncomingProxyMessage, OutgoingProxyMessage};
-use crate::telnet::TelnetServer;
+use crate::proxy_policy::ProxyPolicy;
+use crate::recorder::Recorder;
+#[cfg(feature = "async")]
+use crate::runtime;
+use crate::schedule::Schedule;
+use crate::secure::Security;
+use crate::sequencer::Sequencer;
+use crate::session::{Session, SessionId};
+use crate::sort::SortOrder;
+use crate::stations::{self, Station, StationMap};
+use crate::tags;
+use crate::telnet::{TelnetServer, TelnetSettings};
+use crate::theme::Theme;
+use crate::timeshift::Timeshift;
 use crate::ui;
-use crate::ui::UserInput;
+use crate::ui::{DetailItem, MenuItem, DETAIL_ITEMS};
+use crate::web;
+use crate::web::WebServer;
 use anyhow::{anyhow, Result};
+use chrono::Local;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::cmp::{max, min};
-use std::io::stdout;
-use std::io::Write;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 lazy_static! {
     static ref METADATA_RE: Regex = Regex::new("StreamTitle='(.*)'").unwrap();
 }
 
+// How many rows PageUp and PageDown move the cursor by.
+const PAGE_SIZE: i64 = 10;
+
+// Telnet sessions are closed after this many wrong passwords.
+const MAX_LOGIN_ATTEMPTS: u32 = 3;
+
 enum PostAction {
     Idle(),
     Render(),
 }
 
+enum Flow {
+    Continue(),
+    Quit(),
+}
+
 pub struct ProxyInfo {
     pub addr: SocketAddr,
+    pub dead_air: DeadAirDetector,
     pub info: String,
     pub last_contact: SystemTime,
+    pub latency: Latency,
     pub meta: String,
+    pub sequence: Sequencer,
+    pub stream: FrameParser,
+}
+
+impl ProxyInfo {
+    /// A proxy just heard from.
+    pub fn new(addr: SocketAddr, info: &str) -> ProxyInfo {
+        ProxyInfo {
+            addr,
+            dead_air: DeadAirDetector::default(),
+            info: info.to_string(),
+            last_contact: SystemTime::now(),
+            latency: Latency::default(),
+            meta: "".to_string(),
+            sequence: Sequencer::default(),
+            stream: FrameParser::new(),
+        }
+    }
 }
 
 pub struct Model {
+    access: AccessPolicy,
     active_proxy: Option<SocketAddr>,
-    cursor_line: i64,
-    input_buf: Vec<u8>,
+    /// Set when the user picked a station rather than one of its relays, so that another relay
+    /// can take over.
+    active_station: Option<String>,
+    /// Whether the servers and the model loop run on tokio rather than their own threads.
+    async_runtime: bool,
+    bind: String,
+    bus: Bus,
+    dead_air: DeadAirSettings,
+    /// How many messages each topic of the bus had dropped when it was last reported.
+    dropped: HashMap<&'static str, u64>,
+    events: Subscription<EventModel>,
+    /// Normalized station names and relay addresses.
+    favourites: HashSet<String>,
+    /// Proxies removed by users. They are ignored until the next discovery.
+    forgotten: HashSet<SocketAddr>,
+    keymap: Keymap,
+    lang: Lang,
+    /// Plays `active_proxy` through the timeshift buffer.
+    main_output: Sink,
+    outputs: Vec<Output>,
     proxies: Vec<ProxyInfo>,
     proxy_addr: SocketAddr,
+    proxy_policy: ProxyPolicy,
+    proxy_security: Security,
+    recorder: Recorder,
+    schedule: Schedule,
+    sessions: BTreeMap<SessionId, Session>,
+    sort: SortOrder,
+    station_map: StationMap,
     telnet_port: u16,
+    telnet_settings: TelnetSettings,
+    theme: Theme,
     timeout: u64,
+    timeshift: Timeshift,
+    web_port: Option<u16>,
 }
 
 impl Model {
-    pub fn new(proxy_host: &str, proxy_port: u16, telnet_port: u16, timeout: u64) -> Result<Model> {
-        if let Ok(mut addrs) = (proxy_host, proxy_port).to_socket_addrs() {
+    pub fn new(args: &CmdArgs, config: &Config) -> Result<Model> {
+        let timeshift = Timeshift::new(
+            Duration::from_secs(args.timeshift_minutes * 60),
+            args.timeshift_dir.as_deref(),
+        )?;
+        let lang = match args
+            .language
+            .as_deref()
+            .or_else(|| config.get("ui", "language"))
+        {
+            Some(name) => Lang::parse(name)?,
+            None => Lang::Pl(),
+        };
+        let theme = Theme::new(config)?;
+        let sort = match config.get("ui", "sort") {
+            Some(name) => SortOrder::parse(name)?,
+            None => SortOrder::Arrival(),
+        };
+        let favourites = config
+            .section("ui")
+            .filter(|(key, _)| *key == "favourite")
+            .map(|(_, name)| stations::normalize(name))
+            .collect();
+        let access = AccessPolicy::new(config)?;
+        let bind = args
+            .bind
+            .as_deref()
+            .or_else(|| config.get("access", "bind"))
+            .unwrap_or("0.0.0.0")
+            .to_string();
+        let (main_output, outputs) = outputs::from_config(config, &bind, &access)?;
+        let bus = Bus::new(config)?;
+        // Subscribed right away, so that no event is lost.
+        let events = bus.model.subscribe();
+        if let Ok(mut addrs) = (args.proxy_host.as_str(), args.proxy_port).to_socket_addrs() {
             Ok(Model {
                 active_proxy: None,
-                cursor_line: 0,
-                input_buf: vec![],
+                active_station: None,
+                async_runtime: async_runtime(config)?,
+                bind,
+                bus,
+                dead_air: DeadAirSettings::new(config)?,
+                dropped: HashMap::new(),
+                events,
+                favourites,
+                forgotten: HashSet::new(),
+                keymap: Keymap::new(config)?,
+                lang,
+                main_output,
+                outputs,
                 proxies: vec![],
                 proxy_addr: addrs.next().unwrap(),
-                telnet_port,
-                timeout,
+                proxy_policy: ProxyPolicy::new(config, Duration::from_secs(args.timeout))?,
+                proxy_security: Security::new(config)?,
+                recorder: Recorder::new(config),
+                schedule: Schedule::new(config)?,
+                sort,
+                station_map: StationMap::new(config),
+                access,
+                sessions: BTreeMap::new(),
+                telnet_port: args.telnet_port,
+                telnet_settings: TelnetSettings::new(config)?,
+                theme,
+                timeout: args.timeout,
+                timeshift,
+                web_port: args.web_port,
             })
         } else {
             Err(anyhow!("Could not parse proxy address."))
@@ -60,129 +203,987 @@ impl Model {
     }
 
     pub fn start(&mut self) -> Result<()> {
+        thread::spawn(begin_logging);
+        if self.async_runtime {
+            return self.start_async();
+        }
         let telnet_port = self.telnet_port;
+        let (bind, access) = (self.bind.clone(), self.access.clone());
+        let (settings, lang) = (self.telnet_settings.clone(), self.lang);
 
-        thread::spawn(|| begin_logging());
-        thread::spawn(move || TelnetServer::new("0.0.0.0", telnet_port).start());
-        thread::spawn(|| TelnetServer::start_writer());
-        thread::spawn(|| proxy::start("0.0.0.0:0"));
-        thread::spawn(|| proxy::start_writer());
-        thread::spawn(|| loop {
-            CHANNEL_MODEL_S.send(EventModel::Tick()).unwrap();
+        // The servers subscribe before they start, so that nothing published to them is lost.
+        let (bus, writes) = (self.bus.clone(), self.bus.telnet.subscribe());
+        thread::spawn(move || {
+            TelnetServer::new(&bind, telnet_port, access, settings, lang, bus).start(writes)
+        });
+        let policy = std::mem::take(&mut self.proxy_policy);
+        let security = std::mem::take(&mut self.proxy_security);
+        let (bus, writes) = (self.bus.clone(), self.bus.proxy.subscribe());
+        thread::spawn(|| proxy::start("0.0.0.0:0", policy, security, bus, writes));
+        self.start_web();
+        let events = self.bus.model.clone();
+        thread::spawn(move || loop {
+            events.publish(EventModel::Tick());
             thread::sleep(Duration::from_secs(1));
         });
 
         loop {
-            let post_action = match CHANNEL_MODEL_R.recv()? {
-                EventModel::UserInput(input) => {
-                    for byte in input.iter() {
-                        match ui::interpret_input(&mut self.input_buf, *byte) {
-                            UserInput::Up() => self.cursor_line -= 1,
-                            UserInput::Down() => self.cursor_line += 1,
-                            UserInput::Select() => match self.cursor_line {
-                                0 => {
-                                    proxy::write(&self.proxy_addr, OutgoingProxyMessage::Discover())
-                                }
-                                _ if self.cursor_line == (self.proxies.len() + 1) as i64 => {
-                                    return Ok(());
-                                }
-                                i => {
-                                    let addr = self.proxies[(i - 1) as usize].addr;
-                                    self.active_proxy = if self.active_proxy == Some(addr) {
-                                        None
-                                    } else {
-                                        Some(addr)
-                                    }
-                                }
-                            },
-                            UserInput::Unrecognized() => (),
-                        }
-                    }
-                    PostAction::Render()
+            let event = self.events.recv()?;
+            if let Flow::Quit() = self.handle(event)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs the proxy and telnet servers and the model loop on tokio. Events are handled on this
+    /// thread, so that slow handlers don't hold up the servers.
+    #[cfg(feature = "async")]
+    fn start_async(&mut self) -> Result<()> {
+        let runtime = runtime::Runtime::new()?;
+        let server = TelnetServer::new(
+            &self.bind,
+            self.telnet_port,
+            self.access.clone(),
+            self.telnet_settings.clone(),
+            self.lang,
+            self.bus.clone(),
+        );
+        runtime.spawn(server.serve(
+            runtime.forward(self.bus.telnet.subscribe()),
+            runtime.shutdown(),
+        ));
+        runtime.spawn(proxy::serve(
+            "0.0.0.0:0",
+            std::mem::take(&mut self.proxy_policy),
+            std::mem::take(&mut self.proxy_security),
+            self.bus.clone(),
+            runtime.forward(self.bus.proxy.subscribe()),
+            runtime.shutdown(),
+        ));
+        // The web interface has few clients, so it keeps its threads.
+        self.start_web();
+        let mut events = runtime.forward(self.events.clone());
+        let result = runtime.block_on(async {
+            let mut ticks = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                let event = tokio::select! {
+                    _ = ticks.tick() => EventModel::Tick(),
+                    event = events.recv() => event.ok_or_else(|| anyhow!("the event bus closed"))?,
+                };
+                if let Flow::Quit() = self.handle(event)? {
+                    return Ok(());
                 }
-                EventModel::ProxyInput((addr, msg)) => {
-                    let mut proxy = match self.proxies.iter_mut().find(|x| x.addr == addr) {
-                        Some(info) => {
-                            info.last_contact = SystemTime::now();
-                            info
-                        }
-                        None => {
-                            self.proxies.push(ProxyInfo {
-                                addr,
-                                last_contact: SystemTime::now(),
-                                info: "".to_string(),
-                                meta: "".to_string(),
-                            });
-                            self.proxies.last_mut().unwrap()
-                        }
-                    };
-                    match msg {
-                        IncomingProxyMessage::Audio(audio) => {
-                            if Some(addr) == self.active_proxy {
-                                if let Err(err) = stdout().write_all(&*audio) {
-                                    log!("could not print audio: {:?}", err);
-                                }
-                            }
-                            PostAction::Idle()
-                        }
-                        IncomingProxyMessage::Metadata(meta) => {
-                            match std::str::from_utf8(&*meta) {
-                                Ok("") => (),
-                                Ok(text) => {
-                                    proxy.meta = match METADATA_RE.captures_iter(text).next() {
-                                        Some(cap) => cap[1].to_string(),
-                                        None => text.to_string(),
-                                    }
+            }
+        });
+        runtime.stop();
+        result
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn start_async(&mut self) -> Result<()> {
+        Err(anyhow!(
+            "async networking needs a build with the async feature"
+        ))
+    }
+
+    fn start_web(&mut self) {
+        if let Some(web_port) = self.web_port {
+            let (bind, access) = (self.bind.clone(), self.access.clone());
+            let (bus, updates) = (self.bus.clone(), self.bus.web.subscribe());
+            let max_rewind = self.timeshift.window();
+            thread::spawn(move || {
+                WebServer::new(&bind, web_port, access, bus, max_rewind).start(updates)
+            });
+        }
+    }
+
+    /// Reacts to an event and updates the user interfaces.
+    fn handle(&mut self, event: EventModel) -> Result<Flow> {
+        let known_proxies = self.proxies.len();
+        let post_action = match event {
+            EventModel::UserInput((id, input)) => {
+                // The session is taken out of the map, so that handlers can change the model.
+                let mut session = match self.sessions.remove(&id) {
+                    Some(session) => session,
+                    None => return Ok(Flow::Continue()),
+                };
+                let flow = self.user_input(&mut session, &input);
+                self.sessions.insert(id, session);
+                if let Flow::Quit() = flow? {
+                    return Ok(Flow::Quit());
+                }
+                PostAction::Render()
+            }
+            EventModel::ProxyInput((addr, _)) if self.forgotten.contains(&addr) => {
+                PostAction::Idle()
+            }
+            EventModel::ProxyInput((addr, msg)) => {
+                let proxy = match self.proxies.iter_mut().find(|x| x.addr == addr) {
+                    Some(info) => {
+                        info.last_contact = SystemTime::now();
+                        info
+                    }
+                    None => {
+                        self.proxies.push(ProxyInfo::new(addr, ""));
+                        self.proxies.last_mut().unwrap()
+                    }
+                };
+                match msg {
+                    IncomingProxyMessage::Audio(audio) => self.receive_audio(addr, vec![audio]),
+                    IncomingProxyMessage::SequencedAudio((seq, audio)) => {
+                        let audio = proxy.sequence.push(seq, audio, Instant::now());
+                        self.receive_audio(addr, audio)
+                    }
+                    IncomingProxyMessage::Metadata(meta) => {
+                        match std::str::from_utf8(&meta) {
+                            Ok("") => (),
+                            Ok(text) => {
+                                proxy.meta = match METADATA_RE.captures_iter(text).next() {
+                                    Some(cap) => cap[1].to_string(),
+                                    None => text.to_string(),
                                 }
-                                Err(err) => log!("could not parse metadata: {:?}", err),
                             }
-                            PostAction::Render()
+                            Err(err) => log!("could not parse metadata: {:?}", err),
                         }
-                        IncomingProxyMessage::IAM(info) => {
-                            proxy.info = info.to_string();
-                            PostAction::Render()
+                        PostAction::Render()
+                    }
+                    IncomingProxyMessage::Pong(rtt) => {
+                        proxy.latency.add(rtt);
+                        PostAction::Idle()
+                    }
+                    IncomingProxyMessage::IAM((info, rtt)) => {
+                        proxy.info = info.to_string();
+                        if let Some(rtt) = rtt {
+                            proxy.latency.add(rtt);
                         }
+                        PostAction::Render()
                     }
                 }
-                EventModel::Tick() => {
-                    let now = SystemTime::now();
-                    let prev_length = self.proxies.len();
-                    let timeout = self.timeout;
-                    self.proxies = self
-                        .proxies
-                        .drain(..)
-                        .filter(|x| match now.duration_since(x.last_contact) {
-                            Ok(dur) => dur < Duration::from_secs(timeout),
-                            Err(_) => true,
-                        })
-                        .collect();
-                    for p in &self.proxies {
-                        proxy::write(&p.addr, OutgoingProxyMessage::KeepAlive());
+            }
+            EventModel::Tick() => {
+                let now = SystemTime::now();
+                let timeout = self.timeout;
+                let (proxies, expired): (Vec<_>, Vec<_>) = self.proxies.drain(..).partition(|x| {
+                    match now.duration_since(x.last_contact) {
+                        Ok(dur) => dur < Duration::from_secs(timeout),
+                        Err(_) => true,
                     }
-                    if prev_length == self.proxies.len() {
-                        PostAction::Idle()
-                    } else {
-                        PostAction::Render()
+                });
+                self.proxies = proxies;
+                for p in &expired {
+                    proxy::forget(&self.bus.proxy, &p.addr);
+                }
+                for p in &self.proxies {
+                    proxy::write(&self.bus.proxy, &p.addr, OutgoingProxyMessage::KeepAlive());
+                    // Proxies that don't answer PINGs get them less and less often.
+                    proxy::write(&self.bus.proxy, &p.addr, OutgoingProxyMessage::Ping());
+                }
+                for i in 0..self.proxies.len() {
+                    let audio = self.proxies[i].sequence.flush(Instant::now());
+                    if !audio.is_empty() {
+                        self.receive_audio(self.proxies[i].addr, audio);
                     }
                 }
-                EventModel::NewTelnetConnection() => {
-                    ui::prepare_screen();
+                self.check_dead_air();
+                self.fail_over()?;
+                let stations = self.stations();
+                self.schedule.tick(
+                    Local::now().naive_local(),
+                    &stations,
+                    &self.proxies,
+                    self.stale_after(),
+                );
+                self.main_output.check(Instant::now());
+                for output in self.outputs.iter_mut() {
+                    output.sink.check(Instant::now());
+                }
+                self.check_idle_sessions();
+                self.report_dropped();
+                // The clock and the statistics change all the time.
+                PostAction::Render()
+            }
+            EventModel::Control(cmd) => {
+                self.control(cmd)?;
+                PostAction::Render()
+            }
+            EventModel::NewWebClient() => PostAction::Render(),
+            EventModel::NewTelnetConnection(id) => {
+                let session = Session::new(
+                    id,
+                    self.lang,
+                    self.theme.clone(),
+                    self.access.default_role(),
+                    self.sort,
+                );
+                self.sessions.insert(id, session);
+                ui::prepare_screen(&self.bus.telnet, id);
+                PostAction::Render()
+            }
+            EventModel::TelnetConnectionClosed(id) => {
+                self.sessions.remove(&id);
+                PostAction::Idle()
+            }
+            EventModel::TelnetTerminalType((id, name)) => match self.sessions.get_mut(&id) {
+                Some(session) if Theme::is_dumb_terminal(&name) => {
+                    session.theme = Theme::monochrome();
                     PostAction::Render()
                 }
-                EventModel::ProxyServerCrashed(msg) => {
-                    return Err(anyhow!("proxy server crashed\n{}", msg))
+                _ => PostAction::Idle(),
+            },
+            EventModel::TelnetEnvironment((id, vars)) => {
+                match (self.sessions.get_mut(&id), lang_from_environment(&vars)) {
+                    (Some(session), Some(lang)) => {
+                        session.lang = lang;
+                        PostAction::Render()
+                    }
+                    _ => PostAction::Idle(),
+                }
+            }
+            EventModel::ProxyServerCrashed(msg) => {
+                return Err(anyhow!("proxy server crashed\n{}", msg))
+            }
+            EventModel::TelnetServerCrashed(msg) => {
+                return Err(anyhow!("telnet server crashed\n{}", msg))
+            }
+            EventModel::WebServerCrashed(msg) => {
+                return Err(anyhow!("web server crashed\n{}", msg))
+            }
+        };
+        // Menus only change when something is rendered or a proxy shows up.
+        if matches!(post_action, PostAction::Idle()) && self.proxies.len() == known_proxies {
+            return Ok(Flow::Continue());
+        }
+        let stations = self.stations();
+        for session in self.sessions.values_mut() {
+            if let Some(addr) = session.details {
+                if !self.proxies.iter().any(|x| x.addr == addr) {
+                    session.details = None;
+                    session.alias = None;
+                }
+            }
+            let selected = selected_proxy(&self.outputs, self.active_proxy, session);
+            let keys = ui::menu(&self.proxies, &stations, &selected, session)
+                .iter()
+                .map(|x| ui::item_key(x, &stations))
+                .collect::<Vec<_>>();
+            // Unless the user moved the cursor, it stays on the same item.
+            if let Some((line, key)) = &session.anchor {
+                if *line == session.cursor_line {
+                    if let Some(row) = keys.iter().position(|x| x == key) {
+                        session.cursor_line = row as i64;
+                    }
+                }
+            }
+            session.cursor_line = min(max(0, session.cursor_line), (keys.len() - 1) as i64);
+            session.anchor = Some((
+                session.cursor_line,
+                keys[session.cursor_line as usize].clone(),
+            ));
+        }
+        match post_action {
+            PostAction::Render() => {
+                if self.web_port.is_some() {
+                    let state = web::state(
+                        &self.proxies,
+                        &self.active_proxy,
+                        &self.timeshift,
+                        self.schedule.jobs(),
+                        self.bus.stats(),
+                    );
+                    self.bus.web.publish(EventWeb::Publish(Arc::from(state)));
+                }
+                let stale_after = self.stale_after();
+                for session in self.sessions.values_mut() {
+                    let selected = selected_proxy(&self.outputs, self.active_proxy, session);
+                    if session.schedule.is_some() {
+                        let frame = ui::generate_schedule_ui(
+                            &self.proxies,
+                            &selected,
+                            &self.timeshift,
+                            self.schedule.jobs(),
+                            session,
+                        );
+                        ui::render(&self.bus.telnet, session.id, &session.screen.update(&frame));
+                        continue;
+                    }
+                    let frame = ui::generate_ui(
+                        &self.proxies,
+                        &stations,
+                        &selected,
+                        &self.timeshift,
+                        &self.recorder,
+                        session,
+                        stale_after,
+                    );
+                    ui::render(&self.bus.telnet, session.id, &session.screen.update(&frame));
+                }
+            }
+            PostAction::Idle() => (),
+        };
+        Ok(Flow::Continue())
+    }
+
+    fn user_input(&mut self, session: &mut Session, input: &[u8]) -> Result<Flow> {
+        session.notice = None;
+        session.last_input = Instant::now();
+        session.disconnect_at = None;
+        for key in session.keys.feed(input) {
+            if session.role.is_none() {
+                self.password_input(session, key);
+            } else if session.searching {
+                self.search_input(session, key);
+            } else if session.schedule.is_some() {
+                self.schedule_input(session, key);
+            } else if session.alias.is_some() {
+                self.alias_input(session, key);
+            } else if session.details.is_some() {
+                if let Flow::Quit() = self.details_input(session, key)? {
+                    return Ok(Flow::Quit());
+                }
+            } else if let Some(action) = self.keymap.get(&key) {
+                if let Flow::Quit() = self.action(session, action)? {
+                    return Ok(Flow::Quit());
+                }
+            } else if let Key::Char(c @ '0'..='9') = key {
+                let number = session.jump_digit(c as u8, Instant::now());
+                if let Some(MenuItem::Station(_)) | Some(MenuItem::Proxy(_)) =
+                    self.menu(session).get(number)
+                {
+                    session.cursor_line = number as i64;
+                }
+            }
+        }
+        Ok(Flow::Continue())
+    }
+
+    fn action(&mut self, session: &mut Session, action: Action) -> Result<Flow> {
+        let flow = self.apply(session, action);
+        // More keys may follow in the same input, so the cursor can't wait for `handle`.
+        self.clamp_cursor(session);
+        flow
+    }
+
+    fn clamp_cursor(&self, session: &mut Session) {
+        let last = self.menu(session).len() as i64 - 1;
+        session.cursor_line = max(0, min(session.cursor_line, last));
+    }
+
+    fn apply(&mut self, session: &mut Session, action: Action) -> Result<Flow> {
+        if action.requires_control() && session.role != Some(Role::Control()) {
+            session.notice = Some(Msg::ReadOnly());
+            return Ok(Flow::Continue());
+        }
+        match action {
+            Action::Up() => session.cursor_line -= 1,
+            Action::Down() => session.cursor_line += 1,
+            Action::Home() => session.cursor_line = 0,
+            Action::End() => session.cursor_line = self.menu(session).len() as i64 - 1,
+            Action::PageUp() => session.cursor_line -= PAGE_SIZE,
+            Action::PageDown() => session.cursor_line += PAGE_SIZE,
+            Action::Select() => return self.select(session),
+            Action::Discover() => self.control(ControlCommand::Discover())?,
+            Action::Stop() => self.stop(session)?,
+            Action::Quit() => return Ok(Flow::Quit()),
+            Action::Search() => session.start_search(),
+            Action::Pause() if self.timeshift.is_paused() => {
+                self.control(ControlCommand::Resume())?
+            }
+            Action::Pause() => self.control(ControlCommand::Pause())?,
+            Action::Rewind() => self.control(ControlCommand::Rewind(ui::REWIND_STEP))?,
+            Action::SkipToLive() => self.control(ControlCommand::SkipToLive())?,
+            Action::Redraw() => session.screen.invalidate(),
+            Action::Expand() => self.expand(session, true),
+            Action::Collapse() => self.expand(session, false),
+            Action::Sort() => session.sort = session.sort.next(),
+            Action::Details() => self.open_details(session),
+            Action::NextOutput() => {
+                let current = session
+                    .output
+                    .as_ref()
+                    .and_then(|name| self.outputs.iter().position(|x| &x.name == name));
+                let next = match current {
+                    Some(index) => self.outputs.get(index + 1),
+                    None => self.outputs.first(),
+                };
+                session.output = next.map(|x| x.name.clone());
+            }
+            Action::Schedule() => session.schedule = Some("".to_string()),
+        }
+        Ok(Flow::Continue())
+    }
+
+    fn select(&mut self, session: &mut Session) -> Result<Flow> {
+        let stations = self.stations();
+        let selected = selected_proxy(&self.outputs, self.active_proxy, session);
+        let item = match self.menu(session).get(session.cursor_line as usize) {
+            Some(item) => *item,
+            None => return Ok(Flow::Continue()),
+        };
+        let action = match item {
+            MenuItem::Discover() => Action::Discover(),
+            MenuItem::Station(index) => {
+                let station = &stations[index];
+                if selected.is_some_and(|x| station.relays.contains(&x)) {
+                    Action::Stop()
+                } else {
+                    self.play_station(session, station)?;
+                    return Ok(Flow::Continue());
+                }
+            }
+            MenuItem::Proxy(addr) if selected == Some(addr) => Action::Stop(),
+            MenuItem::Proxy(addr) => {
+                self.play_proxy(session, addr)?;
+                return Ok(Flow::Continue());
+            }
+            MenuItem::Pause() => Action::Pause(),
+            MenuItem::Rewind() => Action::Rewind(),
+            MenuItem::SkipToLive() => Action::SkipToLive(),
+            MenuItem::Quit() => Action::Quit(),
+        };
+        self.action(session, action)
+    }
+
+    fn password_input(&mut self, session: &mut Session, key: Key) {
+        match key {
+            Key::Enter() => {
+                session.role = self.access.role_for(&session.password);
+                session.password.clear();
+                if session.role.is_none() {
+                    session.failed_logins += 1;
+                    session.notice = Some(Msg::WrongPassword());
+                    if session.failed_logins >= MAX_LOGIN_ATTEMPTS {
+                        log!(
+                            "too many wrong passwords, closing telnet session {}",
+                            session.id
+                        );
+                        ui::disconnect(&self.bus.telnet, session.id);
+                    }
+                }
+            }
+            Key::Backspace() => {
+                session.password.pop();
+            }
+            Key::Char(c) => session.password.push(c),
+            _ => (),
+        }
+    }
+
+    fn search_input(&mut self, session: &mut Session, key: Key) {
+        match key {
+            Key::Enter() => session.searching = false,
+            Key::Escape() => {
+                session.searching = false;
+                session.filter.clear();
+            }
+            Key::Backspace() => session.searching = session.filter.pop().is_some(),
+            Key::Up() => session.cursor_line -= 1,
+            Key::Down() => session.cursor_line += 1,
+            Key::Char(c) => session.filter.push(c),
+            _ => (),
+        }
+    }
+
+    /// Shows the detail screen of the relay under the cursor, or of the relay of the station
+    /// under the cursor that is playing or would be played.
+    fn open_details(&mut self, session: &mut Session) {
+        let stations = self.stations();
+        let addr = match self.menu(session).get(session.cursor_line as usize) {
+            Some(MenuItem::Station(index)) => {
+                let station = &stations[*index];
+                match selected_proxy(&self.outputs, self.active_proxy, session) {
+                    Some(addr) if station.relays.contains(&addr) => addr,
+                    _ => stations::healthiest(station, &self.proxies, self.stale_after()),
+                }
+            }
+            Some(MenuItem::Proxy(addr)) => *addr,
+            _ => return,
+        };
+        session.details = Some(addr);
+        session.details_line = 0;
+    }
+
+    fn details_input(&mut self, session: &mut Session, key: Key) -> Result<Flow> {
+        let action = match (key, self.keymap.get(&key)) {
+            (Key::Escape(), _) => Action::Collapse(),
+            (_, Some(action)) => action,
+            _ => return Ok(Flow::Continue()),
+        };
+        match action {
+            Action::Up() | Action::PageUp() => session.details_line -= 1,
+            Action::Down() | Action::PageDown() => session.details_line += 1,
+            Action::Home() => session.details_line = 0,
+            Action::End() => session.details_line = DETAIL_ITEMS.len() as i64 - 1,
+            Action::Select() => self.detail_select(session)?,
+            Action::Collapse() | Action::Details() => session.details = None,
+            Action::Search() | Action::Expand() | Action::Sort() => (),
+            _ => return self.action(session, action),
+        }
+        session.details_line = min(max(0, session.details_line), DETAIL_ITEMS.len() as i64 - 1);
+        Ok(Flow::Continue())
+    }
+
+    fn detail_select(&mut self, session: &mut Session) -> Result<()> {
+        let addr = match session.details {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        let item = DETAIL_ITEMS[session.details_line as usize];
+        if item != DetailItem::Back() && session.role != Some(Role::Control()) {
+            session.notice = Some(Msg::ReadOnly());
+            return Ok(());
+        }
+        match item {
+            DetailItem::Play()
+                if selected_proxy(&self.outputs, self.active_proxy, session) == Some(addr) =>
+            {
+                self.stop(session)?
+            }
+            DetailItem::Play() => self.play_proxy(session, addr)?,
+            DetailItem::Record() => self.toggle_recording(addr),
+            DetailItem::Favourite() => self.toggle_favourite(addr),
+            DetailItem::Alias() => session.alias = Some("".to_string()),
+            DetailItem::Forget() => self.forget(addr)?,
+            DetailItem::Back() => session.details = None,
+        }
+        Ok(())
+    }
+
+    fn alias_input(&mut self, session: &mut Session, key: Key) {
+        match key {
+            Key::Enter() => {
+                if let (Some(addr), Some(alias)) = (session.details, session.alias.take()) {
+                    self.station_map.set_alias(addr, &alias);
                 }
-                EventModel::TelnetServerCrashed(msg) => {
-                    return Err(anyhow!("telnet server crashed\n{}", msg))
+            }
+            Key::Escape() => session.alias = None,
+            Key::Backspace() => {
+                session.alias.as_mut().map(|x| x.pop());
+            }
+            Key::Char(c) => session.alias.iter_mut().for_each(|x| x.push(c)),
+            _ => (),
+        }
+    }
+
+    fn schedule_input(&mut self, session: &mut Session, key: Key) {
+        let spec = match (key, session.schedule.as_mut()) {
+            (_, None) => return,
+            (Key::Enter(), Some(spec)) if !spec.trim().is_empty() => spec,
+            (Key::Enter(), _) | (Key::Escape(), _) => {
+                session.schedule = None;
+                return;
+            }
+            (Key::Backspace(), Some(spec)) => {
+                spec.pop();
+                return;
+            }
+            (Key::Char(c), Some(spec)) => {
+                spec.push(c);
+                return;
+            }
+            _ => return,
+        };
+        if session.role != Some(Role::Control()) {
+            session.notice = Some(Msg::ReadOnly());
+            return;
+        }
+        match self.schedule.add(spec, Local::now().naive_local()) {
+            Ok(()) => {
+                spec.clear();
+                session.notice = Some(Msg::ScheduleAdded());
+            }
+            Err(err) => {
+                log!("{:?}", err);
+                session.notice = Some(Msg::InvalidSchedule());
+            }
+        }
+    }
+
+    fn toggle_recording(&mut self, addr: SocketAddr) {
+        if self.recorder.is_recording(&addr) {
+            self.recorder.stop(&addr);
+            return;
+        }
+        let name = match self.stations().iter().find(|x| x.relays.contains(&addr)) {
+            Some(station) => station.name.clone(),
+            None => addr.to_string(),
+        };
+        let proxy = match self.proxies.iter().find(|x| x.addr == addr) {
+            Some(proxy) => proxy,
+            None => return,
+        };
+        let codec = proxy.stream.info().map(|x| x.codec);
+        let tags = tags::for_proxy(proxy, &name, Local::now().naive_local());
+        if let Err(err) = self.recorder.start(addr, &name, codec, &tags) {
+            log!("{:?}", err);
+            self.notify_all(Msg::RecordingFailure());
+        }
+    }
+
+    /// Favourites are kept by station, so that all relays of a station share them.
+    fn toggle_favourite(&mut self, addr: SocketAddr) {
+        let stations = self.stations();
+        let station = match stations.iter().find(|x| x.relays.contains(&addr)) {
+            Some(station) => station,
+            None => return,
+        };
+        if station.favourite {
+            self.favourites.remove(&station.key);
+            for relay in station.relays.iter() {
+                self.favourites.remove(&relay.to_string());
+            }
+        } else {
+            self.favourites.insert(station.key.clone());
+        }
+    }
+
+    fn forget(&mut self, addr: SocketAddr) -> Result<()> {
+        log!("forgetting proxy {}", addr);
+        if self.active_proxy == Some(addr) {
+            self.control(ControlCommand::Stop())?;
+        }
+        self.recorder.stop(&addr);
+        for output in self.outputs.iter_mut().filter(|x| x.proxy == Some(addr)) {
+            output.proxy = None;
+        }
+        self.proxies.retain(|x| x.addr != addr);
+        self.forgotten.insert(addr);
+        proxy::forget(&self.bus.proxy, &addr);
+        Ok(())
+    }
+
+    fn set_active_proxy(&mut self, addr: Option<SocketAddr>) -> Result<()> {
+        if addr.is_some() && addr != self.active_proxy {
+            self.main_output.switched();
+        }
+        self.active_proxy = addr;
+        self.timeshift.reset()
+    }
+
+    /// Lists or hides the relays of the station under the cursor.
+    fn expand(&mut self, session: &mut Session, open: bool) {
+        let stations = self.stations();
+        let station = match self.menu(session).get(session.cursor_line as usize) {
+            Some(MenuItem::Station(index)) => &stations[*index],
+            Some(MenuItem::Proxy(addr)) => {
+                match stations.iter().find(|x| x.relays.contains(addr)) {
+                    Some(station) => station,
+                    None => return,
                 }
+            }
+            _ => return,
+        };
+        if open {
+            session.expanded.insert(station.key.clone());
+            return;
+        }
+        session.expanded.remove(&station.key);
+        let row = self.menu(session).iter().position(|x| match x {
+            MenuItem::Station(index) => stations[*index].key == station.key,
+            _ => false,
+        });
+        if let Some(row) = row {
+            session.cursor_line = row as i64;
+        }
+    }
+
+    fn stations(&self) -> Vec<Station> {
+        stations::group(&self.proxies, &self.station_map, &self.favourites)
+    }
+
+    fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.timeout) / 2
+    }
+
+    fn menu(&self, session: &Session) -> Vec<MenuItem> {
+        let selected = selected_proxy(&self.outputs, self.active_proxy, session);
+        ui::menu(&self.proxies, &self.stations(), &selected, session)
+    }
+
+    /// The output controlled by a session, unless it's the main one.
+    fn output_mut(&mut self, session: &Session) -> Option<&mut Output> {
+        let name = session.output.as_ref()?;
+        self.outputs.iter_mut().find(|x| &x.name == name)
+    }
+
+    /// Plays the healthiest relay of a station on the session's output and keeps switching to a
+    /// healthy relay when the current one goes silent.
+    fn play_station(&mut self, session: &Session, station: &Station) -> Result<()> {
+        let addr = stations::healthiest(station, &self.proxies, self.stale_after());
+        match self.output_mut(session) {
+            Some(output) => {
+                output.proxy = Some(addr);
+                output.station = Some(station.key.clone());
+            }
+            None => {
+                self.control(ControlCommand::Play(addr))?;
+                self.active_station = Some(station.key.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn play_proxy(&mut self, session: &Session, addr: SocketAddr) -> Result<()> {
+        match self.output_mut(session) {
+            Some(output) => {
+                output.proxy = Some(addr);
+                output.station = None;
+                Ok(())
+            }
+            None => self.control(ControlCommand::Play(addr)),
+        }
+    }
+
+    fn stop(&mut self, session: &Session) -> Result<()> {
+        match self.output_mut(session) {
+            Some(output) => {
+                output.proxy = None;
+                output.station = None;
+                Ok(())
+            }
+            None => self.control(ControlCommand::Stop()),
+        }
+    }
+
+    fn fail_over(&mut self) -> Result<()> {
+        let (stations, stale_after) = (self.stations(), self.stale_after());
+        let proxies = &self.proxies;
+        let silent = |addr: SocketAddr| stations::is_silent(proxies, addr, stale_after);
+        for output in self.outputs.iter_mut() {
+            let station = match stations
+                .iter()
+                .find(|x| Some(&x.key) == output.station.as_ref())
+            {
+                Some(station) => station,
+                None => continue,
             };
-            self.cursor_line = min(max(0, self.cursor_line), (self.proxies.len() + 1) as i64);
-            match post_action {
-                PostAction::Render() => ui::render(
-                    ui::generate_ui(&self.proxies, &self.active_proxy, self.cursor_line).as_str(),
-                ),
-                PostAction::Idle() => (),
+            if output.proxy.is_some_and(|x| !silent(x)) {
+                continue;
+            }
+            let best = stations::healthiest(station, proxies, stale_after);
+            if Some(best) != output.proxy && !silent(best) {
+                log!("switching output {} to relay {}", output.name, best);
+                output.proxy = Some(best);
+            }
+        }
+        let station = match stations
+            .iter()
+            .find(|x| Some(&x.key) == self.active_station.as_ref())
+        {
+            Some(station) => station,
+            None => return Ok(()),
+        };
+        if self.active_proxy.is_some_and(|x| !silent(x)) {
+            return Ok(());
+        }
+        let best = stations::healthiest(station, &self.proxies, stale_after);
+        if Some(best) != self.active_proxy && !silent(best) {
+            log!("switching {} to relay {}", station.name, best);
+            self.set_active_proxy(Some(best))?;
+        }
+        Ok(())
+    }
+
+    /// Logs the topics of the bus that dropped messages since the last check.
+    fn report_dropped(&mut self) {
+        for stats in self.bus.stats() {
+            let reported = self.dropped.entry(stats.name).or_insert(0);
+            if stats.dropped > *reported {
+                log!(
+                    "the {} queue dropped {} messages, its peak depth is {}",
+                    stats.name,
+                    stats.dropped - *reported,
+                    stats.peak
+                );
+                *reported = stats.dropped;
+                // The screens miss some updates, so they have to be drawn anew.
+                if stats.name == "telnet" {
+                    for session in self.sessions.values_mut() {
+                        session.screen.invalidate();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports proxies whose dead air started or ended.
+    fn check_dead_air(&mut self) {
+        let stations = self.stations();
+        let now = Instant::now();
+        let mut notice = None;
+        for proxy in self.proxies.iter_mut() {
+            if !proxy.dead_air.check(now, &self.dead_air) {
+                continue;
+            }
+            let state = proxy.dead_air.state();
+            let station = match stations.iter().find(|x| x.relays.contains(&proxy.addr)) {
+                Some(station) => station.name.as_str(),
+                None => "",
             };
+            log!(
+                "dead air on {} ({}): {}",
+                proxy.addr,
+                station,
+                state.map_or("recovered", |x| x.name())
+            );
+            let playing = Some(proxy.addr) == self.active_proxy
+                || self.outputs.iter().any(|x| x.proxy == Some(proxy.addr));
+            self.dead_air.run_hook(state, proxy.addr, station, playing);
+            if playing {
+                notice = state.map(DeadAir::msg);
+            }
+        }
+        if let Some(notice) = notice {
+            self.notify_all(notice);
+        }
+    }
+
+    /// Warns users who haven't pressed a key for a while and closes their sessions later.
+    fn check_idle_sessions(&mut self) {
+        let timeout = match self.telnet_settings.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let now = Instant::now();
+        for session in self.sessions.values_mut() {
+            let idle = now.duration_since(session.last_input);
+            if idle >= timeout {
+                log!("closing idle telnet session {}", session.id);
+                ui::disconnect(&self.bus.telnet, session.id);
+            } else if idle + self.telnet_settings.idle_warning >= timeout {
+                session.disconnect_at = Some(session.last_input + timeout);
+            }
         }
     }
+
+    fn notify_all(&mut self, notice: Msg) {
+        for session in self.sessions.values_mut() {
+            session.notice = Some(notice);
+        }
+    }
+
+    /// Applies a playback command coming from any of the user interfaces.
+    fn control(&mut self, cmd: ControlCommand) -> Result<()> {
+        match cmd {
+            ControlCommand::Discover() => {
+                self.forgotten.clear();
+                proxy::write(
+                    &self.bus.proxy,
+                    &self.proxy_addr,
+                    OutgoingProxyMessage::Discover(),
+                )
+            }
+            ControlCommand::Play(addr) if self.proxies.iter().any(|x| x.addr == addr) => {
+                self.active_station = None;
+                self.set_active_proxy(Some(addr))?
+            }
+            ControlCommand::Play(addr) => log!("tried to play an unknown proxy {}", addr),
+            ControlCommand::Stop() => {
+                self.active_station = None;
+                self.set_active_proxy(None)?
+            }
+            ControlCommand::Pause() => self.timeshift.pause(),
+            ControlCommand::Resume() => self.timeshift.resume(),
+            ControlCommand::Rewind(by) => self.timeshift.rewind(by, Instant::now()),
+            ControlCommand::SkipToLive() => self.timeshift.skip_to_live(),
+            ControlCommand::Schedule(spec) => {
+                if let Err(err) = self.schedule.add(&spec, Local::now().naive_local()) {
+                    log!("{:?}", err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits audio from a proxy into frames and plays them on the outputs the proxy is selected
+    /// for.
+    fn receive_audio(&mut self, addr: SocketAddr, audio: Vec<Arc<[u8]>>) -> PostAction {
+        let proxy = match self.proxies.iter_mut().find(|x| x.addr == addr) {
+            Some(proxy) => proxy,
+            None => return PostAction::Idle(),
+        };
+        let (info, corrupted) = (proxy.stream.info(), proxy.stream.corrupted());
+        let mut frames = vec![];
+        for chunk in audio.iter() {
+            frames.extend(proxy.stream.push(chunk));
+        }
+        let payloads = audio.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        proxy.dead_air.feed(&payloads, &frames, Instant::now());
+        let post_action = if proxy.stream.corrupted() != corrupted {
+            log!("corrupted audio frame received from {}", addr);
+            PostAction::Render()
+        } else if proxy.stream.info().map(|x| x.codec) != info.map(|x| x.codec) {
+            PostAction::Render()
+        } else {
+            PostAction::Idle()
+        };
+        let recorded = vec![
+            self.recorder.write(&addr, &frames),
+            self.schedule.write(&addr, &frames),
+        ];
+        for err in recorded.into_iter().filter_map(|x| x.err()) {
+            log!("{:?}", err);
+            self.notify_all(Msg::RecordingFailure());
+        }
+        if frames.is_empty() {
+            return post_action;
+        }
+        let audio: Arc<[u8]> = Arc::from(frames);
+        let mut failed = false;
+        for output in self.outputs.iter_mut().filter(|x| x.proxy == Some(addr)) {
+            if let Err(err) = output.sink.write(&audio) {
+                log!("could not play audio on output {}: {:?}", output.name, err);
+                failed = true;
+            }
+        }
+        if failed {
+            self.notify_all(Msg::AudioOutputFailure());
+        }
+        if Some(addr) == self.active_proxy {
+            self.play(audio);
+        }
+        post_action
+    }
+
+    fn play(&mut self, audio: Arc<[u8]>) {
+        match self.timeshift.push(audio, Instant::now()) {
+            Ok(Some(audio)) => {
+                if let Err(err) = self.main_output.write(&audio) {
+                    log!("could not print audio: {:?}", err);
+                    self.notify_all(Msg::AudioOutputFailure());
+                }
+            }
+            Ok(None) => (),
+            Err(err) => {
+                log!("timeshift buffer failure: {:?}", err);
+                self.notify_all(Msg::TimeshiftFailure());
+            }
+        }
+    }
+}
+
+/// Reads the `[runtime]` section, where `async = true` moves the networking to tokio. That
+/// needs a build with the `async` feature.
+fn async_runtime(config: &Config) -> Result<bool> {
+    let mut enabled = false;
+    for (key, value) in config.section("runtime") {
+        match (key, value) {
+            ("async", "true") => enabled = true,
+            ("async", "false") => enabled = false,
+            ("async", _) => return Err(anyhow!("invalid value of async: {}", value)),
+            _ => return Err(anyhow!("unknown runtime setting: {}", key)),
+        }
+    }
+    Ok(enabled)
+}
+
+/// The proxy played by the output a session controls.
+fn selected_proxy(
+    outputs: &[Output],
+    active_proxy: Option<SocketAddr>,
+    session: &Session,
+) -> Option<SocketAddr> {
+    match &session.output {
+        Some(name) => outputs.iter().find(|x| &x.name == name)?.proxy,
+        None => active_proxy,
+    }
 }
@@ -0,0 +1,381 @@
+use crate::access::AccessPolicy;
+use crate::config::Config;
+use crate::player::{self, Player};
+use crate::stations;
+use crate::web::{self, basic_auth_password};
+use anyhow::{anyhow, Context, Result};
+use crossbeam::crossbeam_channel::{bounded, Sender, TrySendError};
+use std::fs::{File, OpenOptions};
+use std::io::{stdout, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait before reopening a pipe without a reader.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How many chunks of audio may wait for a slow pipe reader before they are dropped.
+const PIPE_QUEUE: usize = 64;
+/// How many chunks of audio may wait for a slow HTTP listener before they are dropped.
+const HTTP_QUEUE: usize = 64;
+/// How long a listener may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Listeners = Arc<Mutex<Vec<Sender<Arc<[u8]>>>>>;
+
+/// Where audio goes.
+pub enum Sink {
+    Stdout(),
+    File(File),
+    /// A named pipe. Audio is dropped while nobody reads it, in whole chunks so that the reader
+    /// never gets part of a frame.
+    Pipe {
+        path: PathBuf,
+        /// The queue of the thread writing to the pipe, so that a slow reader can't hold up the
+        /// caller.
+        audio: Option<Sender<Arc<[u8]>>>,
+        retry_at: Instant,
+    },
+    /// Streams audio to HTTP clients connected to a port.
+    Http(Listeners),
+    /// Writes audio to the standard input of a shell command.
+    Exec(Player),
+}
+
+impl Sink {
+    /// Parses `stdout`, `file:<path>`, `pipe:<path>`, `http:<port>` or `exec:<command>`.
+    pub fn parse(spec: &str, bind: &str, access: &AccessPolicy) -> Result<Sink> {
+        let (kind, target) = match spec.find(':') {
+            Some(pos) => (&spec[..pos], spec[pos + 1..].trim()),
+            None => (spec, ""),
+        };
+        Ok(match kind.trim() {
+            "stdout" => Sink::Stdout(),
+            "file" => Sink::File(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(target)
+                    .with_context(|| format!("could not open output file {:?}", target))?,
+            ),
+            "pipe" => Sink::Pipe {
+                path: PathBuf::from(target),
+                audio: None,
+                retry_at: Instant::now(),
+            },
+            "http" => {
+                let port = target
+                    .parse::<u16>()
+                    .with_context(|| format!("invalid output port: {}", target))?;
+                let listener = TcpListener::bind((bind, port))
+                    .with_context(|| format!("could not listen for audio on port {}", port))?;
+                let listeners = Listeners::default();
+                let (shared, access) = (listeners.clone(), access.clone());
+                thread::spawn(move || serve_audio(listener, shared, access));
+                Sink::Http(listeners)
+            }
+            "exec" if !target.is_empty() => Sink::Exec(Player::new(target, false)),
+            _ => return Err(anyhow!("invalid output: {}", spec)),
+        })
+    }
+
+    pub fn write(&mut self, audio: &Arc<[u8]>) -> Result<()> {
+        match self {
+            Sink::Stdout() => stdout().write_all(audio)?,
+            Sink::File(file) => file.write_all(audio)?,
+            Sink::Pipe {
+                path,
+                audio: queue,
+                retry_at,
+            } => {
+                if queue.is_none() && Instant::now() >= *retry_at {
+                    *retry_at = Instant::now() + RETRY_DELAY;
+                    *queue = open_pipe(path).map(|pipe| {
+                        let (sender, receiver) = bounded(PIPE_QUEUE);
+                        thread::spawn(move || player::feed(pipe, receiver));
+                        sender
+                    });
+                }
+                if let Some(Err(TrySendError::Disconnected(_))) =
+                    queue.as_ref().map(|x| x.try_send(audio.clone()))
+                {
+                    *queue = None;
+                }
+            }
+            Sink::Http(listeners) => listeners.lock().unwrap().retain(|x| {
+                !matches!(
+                    x.try_send(audio.clone()),
+                    Err(TrySendError::Disconnected(_))
+                )
+            }),
+            Sink::Exec(player) => player.write(audio, Instant::now())?,
+        }
+        Ok(())
+    }
+
+    /// Notices players that exited. Called periodically.
+    pub fn check(&mut self, now: Instant) {
+        if let Sink::Exec(player) = self {
+            player.check(now);
+        }
+    }
+
+    /// Called when the played station changes.
+    pub fn switched(&mut self) {
+        if let Sink::Exec(player) = self {
+            player.switched();
+        }
+    }
+}
+
+/// Opens a pipe for writing if it has a reader.
+fn open_pipe(path: &Path) -> Option<File> {
+    // Opening a pipe for writing blocks until there is a reader, unless it's opened in
+    // non-blocking mode, which fails instead.
+    let pipe = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .ok()?;
+    // Writes block, so that they never leave part of a chunk in the pipe.
+    let fd = pipe.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return None;
+    }
+    Some(pipe)
+}
+
+fn serve_audio(listener: TcpListener, listeners: Listeners, access: AccessPolicy) {
+    for result in listener.incoming() {
+        match result {
+            Ok(stream) => {
+                let (listeners, access) = (listeners.clone(), access.clone());
+                thread::spawn(move || {
+                    if let Err(err) = handle_listener(stream, &listeners, &access) {
+                        log!("audio listener dropped: {:?}", err);
+                    }
+                });
+            }
+            Err(err) => log!("failed to unpack a new TCP stream: {:?}", err),
+        }
+    }
+}
+
+fn handle_listener(stream: TcpStream, listeners: &Listeners, access: &AccessPolicy) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    if !access.allows(peer.ip()) {
+        log!("rejected an audio listener from {}", peer);
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    let mut request_line = String::new();
+    web::read_line(&mut reader, &mut request_line)?;
+    let headers = web::read_headers(&mut reader)?;
+    let authorized = access.default_role().is_some()
+        || web::find_header(&headers, "authorization")
+            .and_then(basic_auth_password)
+            .is_some_and(|password| access.role_for(&password).is_some());
+    if !authorized {
+        stream.write_all(
+            b"HTTP/1.0 401 Unauthorized\r\n\
+              WWW-Authenticate: Basic realm=\"skclient\"\r\n\r\n",
+        )?;
+        return Ok(());
+    }
+    stream.write_all(
+        b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\nCache-Control: no-cache\r\n\r\n",
+    )?;
+    let (sender, receiver) = bounded::<Arc<[u8]>>(HTTP_QUEUE);
+    listeners.lock().unwrap().push(sender);
+    for audio in receiver.iter() {
+        stream.write_all(&audio)?;
+    }
+    Ok(())
+}
+
+/// An output other than the main one. It plays its own station, live.
+pub struct Output {
+    pub name: String,
+    pub proxy: Option<SocketAddr>,
+    /// Key of the station picked for the output, so that another relay can take over.
+    pub station: Option<String>,
+    pub sink: Sink,
+}
+
+/// Reads the outputs from the `[outputs]` section, where entries are `name = sink`:
+///
+/// ```text
+/// [outputs]
+/// main = stdout
+/// archive = file:/srv/radio/archive.mp3
+/// fifo = pipe:/tmp/radio.fifo
+/// stream = http:8001
+/// kitchen = exec:mpg123 -q -
+///
+/// [output-stations]
+/// kitchen = Radio Nowy Świat
+/// ```
+///
+/// The `main` output, stdout by default, plays the station picked from the list through the
+/// timeshift buffer. The others start with the station set in `[output-stations]`.
+///
+/// The main output can also be a player supervised by the client, set in the `[player]`
+/// section:
+///
+/// ```text
+/// [player]
+/// command = mpv --no-video -
+/// restart-on-switch = true
+/// ```
+pub fn from_config(
+    config: &Config,
+    bind: &str,
+    access: &AccessPolicy,
+) -> Result<(Sink, Vec<Output>)> {
+    let mut main = Sink::Stdout();
+    for (key, _) in config.section("player") {
+        if key != "command" && key != "restart-on-switch" {
+            return Err(anyhow!("unknown player setting: {}", key));
+        }
+    }
+    let command = config.get("player", "command");
+    if let Some(command) = command {
+        let restart_on_switch = match config.get("player", "restart-on-switch") {
+            Some("true") => true,
+            Some("false") | None => false,
+            Some(value) => return Err(anyhow!("invalid restart-on-switch: {}", value)),
+        };
+        main = Sink::Exec(Player::new(command, restart_on_switch));
+    }
+    let mut outputs = vec![];
+    for (name, spec) in config.section("outputs") {
+        let sink =
+            Sink::parse(spec, bind, access).with_context(|| format!("invalid output {}", name))?;
+        if name == "main" && command.is_some() {
+            return Err(anyhow!("the main output and the player can't both be set"));
+        }
+        if name == "main" {
+            main = sink;
+            continue;
+        }
+        if outputs.iter().any(|x: &Output| x.name == name) {
+            return Err(anyhow!("output {} is defined twice", name));
+        }
+        outputs.push(Output {
+            name: name.to_string(),
+            proxy: None,
+            station: config.get("output-stations", name).map(stations::normalize),
+            sink,
+        });
+    }
+    Ok((main, outputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, remove_file};
+
+    fn parse(text: &str) -> Result<(Sink, Vec<Output>)> {
+        let config = Config::parse(text).unwrap();
+        from_config(&config, "127.0.0.1", &AccessPolicy::new(&config).unwrap())
+    }
+
+    #[test]
+    fn outputs_are_configured() {
+        let (main, outputs) = parse(
+            "[outputs]\nmain = exec:true\nfifo = pipe:/tmp/x\n\
+             [output-stations]\nfifo = Radio  ZET\n",
+        )
+        .unwrap();
+        assert!(matches!(main, Sink::Exec(_)));
+        assert_eq!(outputs[0].name, "fifo");
+        assert_eq!(outputs[0].station.as_deref(), Some("radio zet"));
+        assert!(parse("[outputs]\na = speakers\n").is_err());
+        assert!(parse("[outputs]\na = http:x\n").is_err());
+        assert!(parse("[outputs]\na = stdout\na = stdout\n").is_err());
+        assert!(matches!(
+            parse("[player]\ncommand = mpv -\n").unwrap().0,
+            Sink::Exec(_)
+        ));
+        assert!(parse("[player]\ncommand = mpv -\n[outputs]\nmain = stdout\n").is_err());
+        assert!(parse("[player]\ncommand = mpv -\nrestart-on-switch = yes\n").is_err());
+    }
+
+    #[test]
+    fn audio_reaches_files_and_processes() {
+        let dir = std::env::temp_dir();
+        let (file, piped) = (
+            dir.join(format!("skclient-output-{}.bin", std::process::id())),
+            dir.join(format!("skclient-exec-{}.bin", std::process::id())),
+        );
+        let access = AccessPolicy::new(&Config::default()).unwrap();
+        let spec = format!("file:{}", file.display());
+        let mut sink = Sink::parse(&spec, "127.0.0.1", &access).unwrap();
+        sink.write(&Arc::from(&b"abc"[..])).unwrap();
+        assert_eq!(read(&file).unwrap(), b"abc");
+        let spec = format!("exec:cat > {}", piped.display());
+        let mut sink = Sink::parse(&spec, "127.0.0.1", &access).unwrap();
+        sink.write(&Arc::from(&b"xyz"[..])).unwrap();
+        // Closing the input lets the command finish.
+        drop(sink);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while read(&piped).unwrap_or_default() != b"xyz" && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(read(&piped).unwrap(), b"xyz");
+        remove_file(&file).unwrap();
+        remove_file(&piped).unwrap();
+    }
+
+    #[test]
+    fn slow_pipes_drop_whole_chunks() {
+        let path = std::env::temp_dir().join(format!("skclient-pipe-{}", std::process::id()));
+        let _ = remove_file(&path);
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        let access = AccessPolicy::new(&Config::default()).unwrap();
+        let spec = format!("pipe:{}", path.display());
+        let mut sink = Sink::parse(&spec, "127.0.0.1", &access).unwrap();
+        // Chunks that don't divide the pipe's buffer, so that a partial write would show.
+        let chunk = 10_000;
+        let start = Instant::now();
+        for i in 0..PIPE_QUEUE * 4 {
+            sink.write(&Arc::from(vec![i as u8; chunk])).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // Closing the sink lets the writer finish what's queued and close the pipe.
+        drop(sink);
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0; 4096];
+        while Instant::now() < deadline {
+            match std::io::Read::read(&mut reader, &mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        remove_file(&path).unwrap();
+        assert!(!received.is_empty());
+        assert!(received.len() < PIPE_QUEUE * 4 * chunk);
+        assert_eq!(received.len() % chunk, 0);
+        for block in received.chunks(chunk) {
+            assert!(block.iter().all(|x| *x == block[0]));
+        }
+    }
+}
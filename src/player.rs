@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Context, Result};
+use crossbeam::crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait before the first restart of a player that stopped.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A player that ran this long before it stopped is restarted after the shortest delay.
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+/// How long a stopped player may take to play what it has buffered and exit.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many chunks of audio may wait for a slow player before they are dropped.
+const PLAYER_QUEUE: usize = 64;
+
+/// A shell command that audio is piped into, e.g. `mpv -`. It's started on the first write and
+/// restarted whenever it exits, waiting longer after each crash in a row. Audio received while
+/// it's down or can't keep up is dropped.
+pub struct Player {
+    command: String,
+    child: Option<Child>,
+    /// The queue of the thread writing to the player's input, so that a stuck player can't hold
+    /// up the caller.
+    audio: Option<Sender<Arc<[u8]>>>,
+    started: Instant,
+    /// How long to wait after the next crash.
+    backoff: Duration,
+    retry_at: Instant,
+    /// Whether a new player is started when the station changes, so that it doesn't play the
+    /// end of the previous station's buffer or choke on a different format.
+    restart_on_switch: bool,
+}
+
+impl Player {
+    pub fn new(command: &str, restart_on_switch: bool) -> Player {
+        Player {
+            command: command.to_string(),
+            child: None,
+            audio: None,
+            started: Instant::now(),
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            restart_on_switch,
+        }
+    }
+
+    pub fn write(&mut self, audio: &Arc<[u8]>, now: Instant) -> Result<()> {
+        if self.child.is_none() {
+            if now < self.retry_at {
+                return Ok(());
+            }
+            self.start(now)?;
+        }
+        let queue = self.audio.as_ref().unwrap();
+        if let Err(TrySendError::Disconnected(_)) = queue.try_send(audio.clone()) {
+            self.crashed(now);
+            return Err(anyhow!("player {} stopped", self.command));
+        }
+        Ok(())
+    }
+
+    /// Notices a player that exited, even if no audio is written to it.
+    pub fn check(&mut self, now: Instant) {
+        let status = match self.child.as_mut().map(|x| x.try_wait()) {
+            Some(Ok(Some(status))) => status,
+            _ => return,
+        };
+        log!("player {} exited with {}", self.command, status);
+        self.crashed(now);
+    }
+
+    /// Called when the played station changes.
+    pub fn switched(&mut self) {
+        if self.restart_on_switch && self.child.is_some() {
+            log!("restarting player {}", self.command);
+            self.stop();
+            self.retry_at = Instant::now();
+        }
+    }
+
+    fn start(&mut self, now: Instant) -> Result<()> {
+        log!("starting player {}", self.command);
+        // The client's own stdout may carry audio, so the player's output is discarded.
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                let (audio, queue) = bounded(PLAYER_QUEUE);
+                let stdin = child.stdin.take().unwrap();
+                thread::spawn(move || feed(stdin, queue));
+                self.child = Some(child);
+                self.audio = Some(audio);
+                self.started = now;
+                Ok(())
+            }
+            Err(err) => {
+                self.crashed(now);
+                Err(err).with_context(|| format!("could not start player {}", self.command))
+            }
+        }
+    }
+
+    fn crashed(&mut self, now: Instant) {
+        if now.duration_since(self.started) >= STABLE_AFTER {
+            self.backoff = MIN_BACKOFF;
+        }
+        self.retry_at = now + self.backoff;
+        log!(
+            "player {} will be restarted in {} s",
+            self.command,
+            self.backoff.as_secs()
+        );
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.audio = None;
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// Closes the player's input, so that it can finish on its own, and kills it if it doesn't.
+    fn stop(&mut self) {
+        let mut child = match self.child.take() {
+            Some(child) => child,
+            None => return,
+        };
+        // The feeding thread closes the input once it's written what's queued.
+        self.audio = None;
+        thread::spawn(move || {
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while Instant::now() < deadline {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+        });
+    }
+}
+
+/// Writes queued audio until the queue is closed or the writer fails, then closes the writer.
+pub fn feed(mut writer: impl Write, queue: Receiver<Arc<[u8]>>) {
+    for audio in queue.iter() {
+        if writer.write_all(&audio).is_err() {
+            return;
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio(data: &[u8]) -> Arc<[u8]> {
+        Arc::from(data)
+    }
+
+    fn wait_for_exit(player: &mut Player) {
+        player.audio = None;
+        player.child.as_mut().unwrap().wait().unwrap();
+    }
+
+    #[test]
+    fn crashed_players_are_restarted_with_backoff() {
+        let mut player = Player::new("exit 1", false);
+        let now = Instant::now();
+        player.write(&audio(b""), now).unwrap();
+        wait_for_exit(&mut player);
+        player.check(now);
+        assert!(player.child.is_none());
+        assert_eq!(player.retry_at, now + MIN_BACKOFF);
+        // Audio is dropped until it's time to restart the player.
+        player.write(&audio(b"abc"), now).unwrap();
+        assert!(player.child.is_none());
+        let later = now + MIN_BACKOFF;
+        player.write(&audio(b""), later).unwrap();
+        wait_for_exit(&mut player);
+        player.check(later);
+        assert_eq!(player.retry_at, later + MIN_BACKOFF * 2);
+        // The delay starts over once the player has worked for a while.
+        let later = now + MAX_BACKOFF;
+        player.write(&audio(b""), later).unwrap();
+        wait_for_exit(&mut player);
+        player.check(later + STABLE_AFTER);
+        assert_eq!(player.retry_at, later + STABLE_AFTER + MIN_BACKOFF);
+    }
+
+    #[test]
+    fn stuck_players_drop_audio() {
+        let mut player = Player::new("sleep 10", false);
+        let chunk = audio(&[0; 64 * 1024]);
+        let start = Instant::now();
+        for _ in 0..PLAYER_QUEUE * 2 {
+            player.write(&chunk, Instant::now()).unwrap();
+        }
+        assert!(start.elapsed() < STOP_TIMEOUT);
+        let _ = player.child.as_mut().unwrap().kill();
+    }
+
+    #[test]
+    fn players_can_restart_on_station_switch() {
+        let mut player = Player::new("cat", true);
+        player.write(&audio(b"abc"), Instant::now()).unwrap();
+        let first = player.child.as_ref().unwrap().id();
+        player.switched();
+        player.write(&audio(b"abc"), Instant::now()).unwrap();
+        assert_ne!(player.child.as_ref().unwrap().id(), first);
+        let mut player = Player::new("cat", false);
+        player.write(&audio(b"abc"), Instant::now()).unwrap();
+        let first = player.child.as_ref().unwrap().id();
+        player.switched();
+        assert_eq!(player.child.as_ref().unwrap().id(), first);
+    }
+}
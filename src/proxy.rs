@@ -1,151 +1,507 @@
-use crate::channels::{CHANNEL_MODEL_S, CHANNEL_PROXY_R, CHANNEL_PROXY_S};
+use crate::bus::{Bus, Subscription, Topic};
 use crate::events::{EventModel, EventProxy};
+use crate::fragments::{self, Fragment, Reassembler};
+use crate::proxy_policy::{ProxyPolicy, Verdict};
+#[cfg(feature = "async")]
+use crate::runtime;
+use crate::secure::Security;
 use anyhow::{anyhow, Context, Result};
 use lazy_static::lazy_static;
-use std::convert::TryFrom;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::{TryFrom, TryInto};
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use tokio::sync::{mpsc, watch};
 
-const HEADER_SIZE: usize = 4;
+pub const HEADER_SIZE: usize = 4;
+/// Longer messages are fragmented. This leaves room for the authenticated protocol's overhead
+/// below the UDP payload limit.
+const MAX_UNFRAGMENTED_SIZE: usize = 65000;
 
-mod message_codes {
+pub mod message_codes {
     pub const DISCOVER: u16 = 1;
     pub const IAM: u16 = 2;
     pub const KEEPALIVE: u16 = 3;
     pub const AUDIO: u16 = 4;
     pub const METADATA: u16 = 6;
+    /// An IAM that also negotiates the authenticated protocol, see `secure.rs`.
+    pub const SECURE_IAM: u16 = 10;
+    /// A whole datagram protected by the authenticated protocol.
+    pub const SECURED: u16 = 11;
+    /// A piece of a message too long for one datagram, see `fragments.rs`.
+    pub const FRAGMENT: u16 = 12;
+    /// Audio preceded by a u32 sequence number, which lets the client reorder datagrams and
+    /// detect lost ones.
+    pub const SEQUENCED_AUDIO: u16 = 13;
+    /// Carries 8 bytes, which the proxy sends back in a PONG.
+    pub const PING: u16 = 14;
+    pub const PONG: u16 = 15;
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum IncomingProxyMessage {
     Audio(Arc<[u8]>),
-    IAM(Arc<str>),
+    /// The proxy's info, and the round-trip time of the DISCOVER it answers, which is known for
+    /// the first answer only. Named after the message code.
+    #[allow(clippy::upper_case_acronyms)]
+    IAM((Arc<str>, Option<Duration>)),
     Metadata(Arc<[u8]>),
+    SequencedAudio((u32, Arc<[u8]>)),
+    /// The round-trip time of a PING.
+    Pong(Duration),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum OutgoingProxyMessage {
     Discover(),
     KeepAlive(),
+    Ping(),
+}
+
+/// What a datagram carries once it's unwrapped.
+enum Datagram {
+    Message(IncomingProxyMessage),
+    Fragment(Fragment),
+    /// The token of the PING being answered.
+    Pong(u64),
+}
+
+/// How many PINGs to a proxy may wait for an answer. Proxies that don't know PINGs never
+/// answer them.
+const MAX_PENDING_PINGS: usize = 8;
+
+/// The PINGs sent to a proxy since it last answered one.
+#[derive(Default)]
+struct Pings {
+    /// Tokens of the PINGs waiting for an answer, oldest first.
+    pending: VecDeque<u64>,
+    /// How many PINGs were skipped since the last one sent.
+    skipped: u32,
+}
+
+/// Matches IAMs and PONGs with the DISCOVERs and PINGs they answer, to measure round trips.
+#[derive(Default)]
+struct RoundTrips {
+    /// When the last DISCOVER was sent, and the proxies that have answered it since.
+    discovery: Option<(Instant, HashSet<SocketAddr>)>,
+    pings: HashMap<SocketAddr, Pings>,
+}
+
+impl RoundTrips {
+    fn discovery_sent(&mut self, now: Instant) {
+        self.discovery = Some((now, HashSet::new()));
+    }
+
+    fn iam(&mut self, src: SocketAddr, now: Instant) -> Option<Duration> {
+        let (sent, answered) = self.discovery.as_mut()?;
+        match answered.insert(src) {
+            true => Some(now.duration_since(*sent)),
+            false => None,
+        }
+    }
+
+    /// Whether to send a PING with `token` to `dst`. Every unanswered PING doubles the interval
+    /// between them, so that proxies that don't know PINGs get few of them.
+    fn ping(&mut self, dst: SocketAddr, token: u64) -> bool {
+        let pings = self.pings.entry(dst).or_default();
+        if pings.skipped + 1 < 1 << pings.pending.len() {
+            pings.skipped += 1;
+            return false;
+        }
+        pings.skipped = 0;
+        if pings.pending.len() >= MAX_PENDING_PINGS {
+            pings.pending.pop_front();
+        }
+        pings.pending.push_back(token);
+        true
+    }
+
+    /// Whether `token` is that of a PING sent to `src`. The PINGs sent before it are given up on.
+    fn pong(&mut self, src: SocketAddr, token: u64) -> bool {
+        let pending = match self.pings.get_mut(&src) {
+            Some(pings) => &mut pings.pending,
+            None => return false,
+        };
+        match pending.iter().position(|x| *x == token) {
+            Some(index) => {
+                pending.drain(..=index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forget(&mut self, addr: SocketAddr) {
+        self.pings.remove(&addr);
+        if let Some((_, answered)) = self.discovery.as_mut() {
+            answered.remove(&addr);
+        }
+    }
 }
 
 lazy_static! {
-    static ref SOCKET: Arc<Mutex<Option<UdpSocket>>> = Arc::from(Mutex::new(None));
+    // PINGs carry the number of microseconds since this moment.
+    static ref EPOCH: Instant = Instant::now();
 }
 
-fn parse_msg(msg: &[u8]) -> Result<IncomingProxyMessage> {
+/// The client's side of the protocol, shared by whatever reads and writes its socket.
+pub struct Endpoint {
+    policy: Mutex<ProxyPolicy>,
+    security: Mutex<Security>,
+    reassembler: Mutex<Reassembler>,
+    round_trips: Mutex<RoundTrips>,
+    /// Numbers the fragmented messages sent by the client.
+    fragmented_id: AtomicU32,
+}
+
+/// Splits a message into its code and content.
+pub fn split_msg(msg: &[u8]) -> Result<(u16, &[u8])> {
     if msg.len() < HEADER_SIZE {
         return Err(anyhow!("message too short"));
     }
-    let code: u16;
-    let length: u16;
-    unsafe {
-        let msg_u16 = msg.as_ptr() as *mut u16;
-        code = u16::from_be(*msg_u16);
-        length = u16::from_be(*(msg_u16.add(1)));
-    }
-    let content = &msg[HEADER_SIZE..(HEADER_SIZE + length as usize)];
+    let code = u16::from_be_bytes([msg[0], msg[1]]);
+    let length = u16::from_be_bytes([msg[2], msg[3]]);
+    let content = msg
+        .get(HEADER_SIZE..(HEADER_SIZE + length as usize))
+        .ok_or_else(|| anyhow!("message shorter than its declared length"))?;
+    Ok((code, content))
+}
+
+fn parse_msg(msg: &[u8]) -> Result<IncomingProxyMessage> {
+    let (code, content) = split_msg(msg)?;
+    parse_content(code, content)
+}
+
+fn parse_content(code: u16, content: &[u8]) -> Result<IncomingProxyMessage> {
     match code {
-        message_codes::IAM => Ok(IncomingProxyMessage::IAM(Arc::from(from_utf8(content)?))),
+        message_codes::IAM => Ok(IncomingProxyMessage::IAM((
+            Arc::from(from_utf8(content)?),
+            None,
+        ))),
         message_codes::AUDIO => Ok(IncomingProxyMessage::Audio(Arc::from(content))),
         message_codes::METADATA => Ok(IncomingProxyMessage::Metadata(Arc::from(content))),
+        message_codes::SEQUENCED_AUDIO if content.len() >= 4 => {
+            let seq = u32::from_be_bytes([content[0], content[1], content[2], content[3]]);
+            Ok(IncomingProxyMessage::SequencedAudio((
+                seq,
+                Arc::from(&content[4..]),
+            )))
+        }
         _ => Err(anyhow!("invalid message code: {}", code)),
     }
 }
 
-pub fn start<A: ToSocketAddrs>(addr: A) {
+fn parse_datagram(msg: &[u8]) -> Result<Datagram> {
+    match split_msg(msg)? {
+        (message_codes::FRAGMENT, content) => Ok(Datagram::Fragment(Fragment::parse(content)?)),
+        (message_codes::PONG, content) if content.len() == 8 => Ok(Datagram::Pong(
+            u64::from_be_bytes(content.try_into().unwrap()),
+        )),
+        _ => Ok(Datagram::Message(parse_msg(msg)?)),
+    }
+}
+
+/// Unwraps secured messages and checks that plain ones are allowed.
+fn receive(security: &Mutex<Security>, src: SocketAddr, msg: &[u8]) -> Result<Datagram> {
+    let (code, content) = split_msg(msg)?;
+    let mut security = security.lock().unwrap();
+    match code {
+        message_codes::SECURE_IAM => Ok(Datagram::Message(IncomingProxyMessage::IAM((
+            Arc::from(security.accept_iam(src, content)?),
+            None,
+        )))),
+        message_codes::SECURED => parse_datagram(&security.open(src, content)?),
+        _ => {
+            security.check_plain(src)?;
+            parse_datagram(msg)
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn new(policy: ProxyPolicy, security: Security) -> Endpoint {
+        Endpoint {
+            policy: Mutex::new(policy),
+            security: Mutex::new(security),
+            reassembler: Mutex::new(Reassembler::default()),
+            round_trips: Mutex::new(RoundTrips::default()),
+            fragmented_id: AtomicU32::new(0),
+        }
+    }
+
+    /// Drops the secure channel of a proxy that's gone, so that it negotiates a new one if it
+    /// comes back.
+    pub fn forget(&self, addr: SocketAddr) {
+        self.security.lock().unwrap().forget(addr);
+        self.round_trips.lock().unwrap().forget(addr);
+    }
+
+    /// Adds the round-trip time to the first IAM answering a DISCOVER.
+    fn timed(&self, src: SocketAddr, msg: IncomingProxyMessage) -> IncomingProxyMessage {
+        match msg {
+            IncomingProxyMessage::IAM((info, None)) => {
+                let rtt = self.round_trips.lock().unwrap().iam(src, Instant::now());
+                IncomingProxyMessage::IAM((info, rtt))
+            }
+            msg => msg,
+        }
+    }
+
+    /// Unwraps a received datagram. Returns a message once it's accepted and complete.
+    pub fn incoming(&self, src: SocketAddr, buf: &[u8]) -> Option<IncomingProxyMessage> {
+        let datagram = receive(&self.security, src, buf);
+        let msg = match &datagram {
+            Ok(Datagram::Message(msg)) => Some(msg),
+            _ => None,
+        };
+        // Fragments are only accepted from trusted proxies, so that unknown senders can't
+        // fill the reassembly buffers.
+        match self.policy.lock().unwrap().check(src, msg, Instant::now()) {
+            Verdict::Accept() => (),
+            Verdict::Reject(reason) => {
+                log!("rejected a datagram from {}: {}", src, reason);
+                return None;
+            }
+            Verdict::Drop() => return None,
+        }
+        let fragment = match datagram {
+            Ok(Datagram::Message(msg)) => return Some(self.timed(src, msg)),
+            Ok(Datagram::Fragment(fragment)) => fragment,
+            // PINGs carry the time they were sent, so a PONG is only trusted if it answers one.
+            Ok(Datagram::Pong(token)) => {
+                if !self.round_trips.lock().unwrap().pong(src, token) {
+                    log!("rejected a PONG from {} to a PING that was never sent", src);
+                    return None;
+                }
+                let sent = *EPOCH + Duration::from_micros(token);
+                return Some(IncomingProxyMessage::Pong(
+                    Instant::now().saturating_duration_since(sent),
+                ));
+            }
+            Err(err) => {
+                log!("failed to parse UDP message: {:?}", err);
+                return None;
+            }
+        };
+        let mut reassembler = self.reassembler.lock().unwrap();
+        match reassembler.push(src, fragment, Instant::now()) {
+            Ok(Some((code, content))) => match parse_content(code, &content) {
+                Ok(msg) => Some(self.timed(src, msg)),
+                Err(err) => {
+                    log!("failed to parse a reassembled message: {:?}", err);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(err) => {
+                log!("failed to reassemble a message: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Prepares the datagrams carrying a message to a proxy.
+    pub fn outgoing(&self, addr: SocketAddr, msg: OutgoingProxyMessage) -> Result<Vec<Vec<u8>>> {
+        let (code, content) = match msg {
+            OutgoingProxyMessage::Discover() => {
+                self.policy.lock().unwrap().discovery_sent(Instant::now());
+                self.round_trips
+                    .lock()
+                    .unwrap()
+                    .discovery_sent(Instant::now());
+                (
+                    message_codes::DISCOVER,
+                    self.security.lock().unwrap().offer()?,
+                )
+            }
+            OutgoingProxyMessage::KeepAlive() => (message_codes::KEEPALIVE, vec![]),
+            OutgoingProxyMessage::Ping() => {
+                let micros = EPOCH.elapsed().as_micros() as u64;
+                if !self.round_trips.lock().unwrap().ping(addr, micros) {
+                    return Ok(vec![]);
+                }
+                (message_codes::PING, micros.to_be_bytes().to_vec())
+            }
+        };
+        let mut id = self.fragmented_id.load(Ordering::Relaxed);
+        let bufs = prepare_msgs(code, &content, &mut id)?;
+        self.fragmented_id.store(id, Ordering::Relaxed);
+        bufs.into_iter()
+            .map(|buf| match code {
+                // DISCOVER stays plain, because it starts the negotiation.
+                message_codes::DISCOVER => Ok(buf),
+                _ => seal(&self.security, addr, buf).context("failed to seal message"),
+            })
+            .collect()
+    }
+}
+
+/// Binds the client's socket and passes what proxies send to the model. Messages published on
+/// the proxy topic, to which `writes` is subscribed, are sent by another thread.
+pub fn start<A: ToSocketAddrs>(
+    addr: A,
+    policy: ProxyPolicy,
+    security: Security,
+    bus: Bus,
+    writes: Subscription<EventProxy>,
+) {
     match || -> Result<()> {
-        let socket = UdpSocket::bind(&addr)?;
+        let socket = Arc::new(UdpSocket::bind(&addr)?);
         socket.set_broadcast(true).context("set broadcast failed")?;
+        let endpoint = Arc::new(Endpoint::new(policy, security));
         {
-            *SOCKET.lock().unwrap() = Some(socket.try_clone()?);
+            let (socket, endpoint) = (socket.clone(), endpoint.clone());
+            thread::spawn(move || start_writer(&socket, &endpoint, writes));
         }
         let mut buf: [u8; 65535] = [0; 65535];
 
         loop {
             let (size, src) =
                 continue_on_err!(socket.recv_from(&mut buf), "failed to receive UDP message");
-
-            let msg = continue_on_err!(parse_msg(&buf[..size]), "failed to parse UDP message");
-
-            CHANNEL_MODEL_S
-                .send(EventModel::ProxyInput((src, msg)))
-                .unwrap();
+            if let Some(msg) = endpoint.incoming(src, &buf[..size]) {
+                bus.model.publish(EventModel::ProxyInput((src, msg)));
+            }
         }
     }() {
         Ok(()) => (),
-        Err(e) => CHANNEL_MODEL_S
-            .send(EventModel::ProxyServerCrashed(Arc::from(e.to_string())))
-            .unwrap(),
+        Err(e) => bus
+            .model
+            .publish(EventModel::ProxyServerCrashed(Arc::from(e.to_string()))),
+    }
+}
+
+/// Like `start`, but on tokio, with a single task reading and writing the socket. Returns once
+/// `shutdown` changes.
+#[cfg(feature = "async")]
+pub async fn serve<A: tokio::net::ToSocketAddrs>(
+    addr: A,
+    policy: ProxyPolicy,
+    security: Security,
+    bus: Bus,
+    writes: mpsc::Receiver<EventProxy>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let endpoint = Endpoint::new(policy, security);
+    if let Err(e) = serve_socket(addr, &endpoint, &bus, writes, shutdown).await {
+        let crashed = EventModel::ProxyServerCrashed(Arc::from(e.to_string()));
+        runtime::publish(&bus.model, crashed);
     }
 }
 
-fn prepare_msg(code: u16, content: &[u8]) -> Result<Vec<u8>> {
+#[cfg(feature = "async")]
+async fn serve_socket<A: tokio::net::ToSocketAddrs>(
+    addr: A,
+    endpoint: &Endpoint,
+    bus: &Bus,
+    mut writes: mpsc::Receiver<EventProxy>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let socket = tokio::net::UdpSocket::bind(addr).await?;
+    socket.set_broadcast(true).context("set broadcast failed")?;
+    let mut buf = vec![0; 65535];
+    loop {
+        // Each of these futures is cancellation safe. Sends happen in the handler, so that they
+        // can't be cut short.
+        tokio::select! {
+            _ = shutdown.changed() => return Ok(()),
+            received = socket.recv_from(&mut buf) => {
+                let (size, src) = continue_on_err!(received, "failed to receive UDP message");
+                if let Some(msg) = endpoint.incoming(src, &buf[..size]) {
+                    runtime::publish(&bus.model, EventModel::ProxyInput((src, msg)));
+                }
+            }
+            write = writes.recv() => {
+                let (addr, msg) = match write {
+                    Some(EventProxy::Write(write)) => write,
+                    Some(EventProxy::Forget(addr)) => {
+                        endpoint.forget(addr);
+                        continue;
+                    }
+                    None => return Ok(()),
+                };
+                let bufs =
+                    continue_on_err!(endpoint.outgoing(addr, msg), "failed to prepare message");
+                for buf in bufs {
+                    continue_on_err!(socket.send_to(&buf, addr).await, "failed to send message");
+                }
+            }
+        }
+    }
+}
+
+pub fn prepare_msg(code: u16, content: &[u8]) -> Result<Vec<u8>> {
     let mut msg = vec![0_u8; HEADER_SIZE + content.len()];
     let length = u16::try_from(content.len()).context("content length must fit in u16")?;
-    unsafe {
-        let msg_u16 = msg.as_mut_ptr() as *mut u16;
-        *msg_u16 = code.to_be();
-        *(msg_u16.add(1)) = length.to_be();
-    }
+    msg[..2].copy_from_slice(&code.to_be_bytes());
+    msg[2..HEADER_SIZE].copy_from_slice(&length.to_be_bytes());
     for (i, val) in content.iter().enumerate() {
         msg[HEADER_SIZE + i] = *val;
     }
     Ok(msg)
 }
 
-pub fn start_writer() {
-    match || -> Result<()> {
-        loop {
-            match CHANNEL_PROXY_R.recv().unwrap() {
-                EventProxy::Write((addr, msg)) => match SOCKET.lock().unwrap().as_mut() {
-                    Some(socket) => {
-                        let (code, content) = match msg {
-                            OutgoingProxyMessage::Discover() => (message_codes::DISCOVER, &[]),
-                            OutgoingProxyMessage::KeepAlive() => (message_codes::KEEPALIVE, &[]),
-                        };
-                        let buf = continue_on_err!(
-                            prepare_msg(code, content),
-                            "failed to prepare message"
-                        );
-                        continue_on_err!(socket.send_to(&buf[..], addr), "failed to send message");
-                    }
-                    None => log!("tried to write when socket was None"),
-                },
+/// Prepares the datagrams carrying a message, which is fragmented if it doesn't fit in one.
+/// `id` numbers the fragmented messages of the sender.
+pub fn prepare_msgs(code: u16, content: &[u8], id: &mut u32) -> Result<Vec<Vec<u8>>> {
+    if HEADER_SIZE + content.len() <= MAX_UNFRAGMENTED_SIZE {
+        return Ok(vec![prepare_msg(code, content)?]);
+    }
+    *id = id.wrapping_add(1);
+    fragments::split(*id, code, content)?
+        .iter()
+        .map(|x| prepare_msg(message_codes::FRAGMENT, &x.encode()))
+        .collect()
+}
+
+fn start_writer(socket: &UdpSocket, endpoint: &Endpoint, writes: Subscription<EventProxy>) {
+    while let Ok(event) = writes.recv() {
+        let (addr, msg) = match event {
+            EventProxy::Write(write) => write,
+            EventProxy::Forget(addr) => {
+                endpoint.forget(addr);
+                continue;
             }
+        };
+        let bufs = continue_on_err!(endpoint.outgoing(addr, msg), "failed to prepare message");
+        for buf in bufs {
+            continue_on_err!(socket.send_to(&buf[..], addr), "failed to send message");
         }
-    }() {
-        Ok(()) => (),
-        Err(e) => CHANNEL_MODEL_S
-            .send(EventModel::ProxyServerCrashed(Arc::from(e.to_string())))
-            .unwrap(),
     }
 }
 
-pub fn write(addr: &SocketAddr, msg: OutgoingProxyMessage) {
-    CHANNEL_PROXY_S
-        .send(EventProxy::Write((*addr, msg)))
-        .unwrap();
+/// Wraps a message for a proxy that negotiated the authenticated protocol.
+fn seal(security: &Mutex<Security>, addr: SocketAddr, msg: Vec<u8>) -> Result<Vec<u8>> {
+    match security.lock().unwrap().seal(addr, &msg) {
+        Some(content) => prepare_msg(message_codes::SECURED, &content),
+        None => Ok(msg),
+    }
+}
+
+pub fn write(proxies: &Topic<EventProxy>, addr: &SocketAddr, msg: OutgoingProxyMessage) {
+    proxies.publish(EventProxy::Write((*addr, msg)));
+}
+
+pub fn forget(proxies: &Topic<EventProxy>, addr: &SocketAddr) {
+    proxies.publish(EventProxy::Forget(*addr));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::channels::*;
-    use crate::events::EventModel;
-    use crate::log::begin_logging;
-    use rusty_fork::rusty_fork_test;
-    use std::net::ToSocketAddrs;
-    use std::thread;
-    use std::time::Duration;
-
-    static SERVER_HOST: &'static str = "localhost";
+    use crate::config::Config;
+    use crate::test_proxy::TestProxy;
+
+    static SERVER_HOST: &str = "localhost";
     static SERVER_PORT: u16 = 15789;
 
     #[test]
     fn header_size_is_big_enough() {
-        // unsafe code depends on this
+        // The header is the code and the length, both u16.
         if HEADER_SIZE != 4 {
             panic!("header size must be 4");
         }
@@ -156,15 +512,9 @@ mod tests {
         let code: u16 = 67;
         let content = [2; 82];
         let msg = prepare_msg(code, &content).unwrap();
-        unsafe {
-            let msg_u16 = msg.as_ptr() as *const u16;
-            assert!(u16::from_be(*msg_u16) == code);
-            assert!(u16::from_be(*(msg_u16.add(1))) == content.len() as u16);
-            let msg_u8 = msg.as_ptr() as *const u8;
-            for (offset, val) in content.iter().enumerate() {
-                assert!(*(msg_u8.add(HEADER_SIZE + offset)) == *val);
-            }
-        }
+        assert_eq!(u16::from_be_bytes([msg[0], msg[1]]), code);
+        assert_eq!(u16::from_be_bytes([msg[2], msg[3]]), content.len() as u16);
+        assert_eq!(&msg[HEADER_SIZE..], &content[..]);
     }
 
     #[test]
@@ -173,7 +523,7 @@ mod tests {
             (
                 message_codes::IAM,
                 "hello".as_bytes(),
-                IncomingProxyMessage::IAM(Arc::from("hello")),
+                IncomingProxyMessage::IAM((Arc::from("hello"), None)),
             ),
             (
                 message_codes::AUDIO,
@@ -185,6 +535,11 @@ mod tests {
                 &[2; 4],
                 IncomingProxyMessage::Metadata(Arc::from([2; 4])),
             ),
+            (
+                message_codes::SEQUENCED_AUDIO,
+                &[0, 0, 1, 2, 5],
+                IncomingProxyMessage::SequencedAudio((258, Arc::from([5]))),
+            ),
         ];
         for (code, content, expected_msg) in cases.iter() {
             let msg = parse_msg(&prepare_msg(*code, content).unwrap()[..]).unwrap();
@@ -201,72 +556,216 @@ mod tests {
         }
     }
 
-    rusty_fork_test! {
-        #[test]
-        fn server_processes_message() {
-            thread::spawn(|| start((SERVER_HOST, SERVER_PORT)));
-            let socket = UdpSocket::bind((SERVER_HOST, SERVER_PORT + 1)).unwrap();
-            let msg_content = [];
-            let msg = prepare_msg(message_codes::AUDIO, &msg_content).unwrap();
-
-            let mut loops = 0;
-            while let Err(_) = socket.send_to(&msg[..], (SERVER_HOST, SERVER_PORT)) {
-                thread::sleep(Duration::from_millis(5));
-                loops += 1;
-                if loops > 500 {
-                    panic!("cant send the message!");
-                }
-            }
+    /// Starts the client's side on a port and returns the bus it talks to the model through.
+    fn start_client(
+        port: u16,
+        policy: ProxyPolicy,
+        security: Security,
+    ) -> (Bus, Subscription<EventModel>) {
+        let bus = Bus::default();
+        let (events, writes) = (bus.model.subscribe(), bus.proxy.subscribe());
+        let shared = bus.clone();
+        thread::spawn(move || start((SERVER_HOST, port), policy, security, shared, writes));
+        (bus, events)
+    }
 
-            match CHANNEL_MODEL_R.recv_timeout(Duration::from_secs(5)) {
-                Ok(EventModel::ProxyInput((_, IncomingProxyMessage::Audio(content)))) =>
-                    assert_eq!(*content, msg_content),
-                result => panic!("expected to receive an audio message but got {:?}", result),
+    fn expect_input(events: &Subscription<EventModel>, expected: IncomingProxyMessage) {
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(EventModel::ProxyInput((_, msg))) => assert_eq!(msg, expected),
+            result => panic!("expected {:?} but got {:?}", expected, result),
+        }
+    }
+
+    /// Expects an IAM from a proxy named `radio` and returns its round-trip time.
+    fn expect_iam(events: &Subscription<EventModel>) -> Option<Duration> {
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(EventModel::ProxyInput((_, IncomingProxyMessage::IAM((info, rtt))))) => {
+                assert_eq!(&*info, "radio");
+                rtt
             }
+            result => panic!("expected an IAM but got {:?}", result),
         }
+    }
 
-        #[test]
-        fn writer_sends_message() {
-            thread::spawn(begin_logging);
-            thread::spawn(|| start((SERVER_HOST, SERVER_PORT + 2)));
-            thread::spawn(|| start_writer());
-
-            let mut loops = 0;
-            loop {
-                loops += 1;
-                if loops > 1000 {
-                    panic!("timeout");
-                }
-                let socket_present;
-                {
-                    socket_present = SOCKET.lock().unwrap().is_some();
-                }
-                if socket_present {
-                    break;
-                } else {
-                    thread::sleep(Duration::from_millis(5));
+    #[test]
+    fn server_processes_message() {
+        let config = Config::parse("[proxy]\nallow = 127.0.0.1\nallow = ::1\n").unwrap();
+        let policy = ProxyPolicy::new(&config, Duration::from_secs(5)).unwrap();
+        let (_bus, events) = start_client(SERVER_PORT, policy, Security::default());
+        let socket = UdpSocket::bind((SERVER_HOST, SERVER_PORT + 1)).unwrap();
+        let msg_content = [];
+        let msg = prepare_msg(message_codes::AUDIO, &msg_content).unwrap();
+
+        // The message is sent until the client is there to receive it.
+        for _ in 0..500 {
+            let _ = socket.send_to(&msg[..], (SERVER_HOST, SERVER_PORT));
+            match events.recv_timeout(Duration::from_millis(10)) {
+                Ok(EventModel::ProxyInput((_, IncomingProxyMessage::Audio(content)))) => {
+                    assert_eq!(*content, msg_content);
+                    return;
                 }
+                Ok(event) => panic!("expected to receive an audio message but got {:?}", event),
+                Err(_) => (),
+            }
+        }
+        panic!("cant send the message!");
+    }
+
+    #[test]
+    fn writer_sends_message() {
+        let (bus, _events) =
+            start_client(SERVER_PORT + 2, ProxyPolicy::default(), Security::default());
+        let socket_addr = (SERVER_HOST, SERVER_PORT + 3)
+            .to_socket_addrs()
+            .unwrap()
+            .next()
+            .unwrap();
+        let socket = UdpSocket::bind(socket_addr).unwrap();
+
+        let msg = prepare_msg(message_codes::DISCOVER, &[]).unwrap();
+        write(&bus.proxy, &socket_addr, OutgoingProxyMessage::Discover());
+
+        let mut buf = [0; 65535];
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        match socket.recv(&mut buf) {
+            Ok(msg_size) => {
+                assert_eq!(msg_size, msg.len());
+                assert_eq!(buf[..msg_size], msg[..]);
             }
+            result => panic!("expected to receive a message but got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn secured_proxy_is_negotiated() {
+        let config = Config::parse("[security]\nkey = secret\n").unwrap();
+        let security = Security::new(&config).unwrap();
+        let (bus, events) = start_client(SERVER_PORT + 4, ProxyPolicy::default(), security);
+        let mut proxy =
+            TestProxy::bind((SERVER_HOST, SERVER_PORT + 5), "radio", Some("secret")).unwrap();
+
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::Discover());
+        let (client, code) = proxy.serve_one().unwrap();
+        assert_eq!(code, message_codes::DISCOVER);
+        expect_iam(&events);
+
+        // Once secured, the proxy's plain datagrams could be forged and are dropped.
+        proxy
+            .send_plain(client, message_codes::AUDIO, &[1])
+            .unwrap();
+        proxy.send(client, message_codes::AUDIO, &[2]).unwrap();
+        expect_input(&events, IncomingProxyMessage::Audio(Arc::from([2])));
+
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::KeepAlive());
+        assert_eq!(proxy.serve_one().unwrap().1, message_codes::KEEPALIVE);
+    }
+
+    #[test]
+    fn plain_proxy_is_accepted_when_allowed() {
+        let config = Config::parse("[security]\nkey = secret\nallow-plain = true\n").unwrap();
+        let security = Security::new(&config).unwrap();
+        let (bus, events) = start_client(SERVER_PORT + 6, ProxyPolicy::default(), security);
+        let mut proxy = TestProxy::bind((SERVER_HOST, SERVER_PORT + 7), "radio", None).unwrap();
+
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::Discover());
+        let (client, _) = proxy.serve_one().unwrap();
+        expect_iam(&events);
+        proxy.send(client, message_codes::AUDIO, &[2]).unwrap();
+        expect_input(&events, IncomingProxyMessage::Audio(Arc::from([2])));
+    }
+
+    #[test]
+    fn long_messages_are_fragmented() {
+        let config = Config::parse("[security]\nkey = secret\n").unwrap();
+        let security = Security::new(&config).unwrap();
+        let (bus, events) = start_client(SERVER_PORT + 8, ProxyPolicy::default(), security);
+        let mut proxy =
+            TestProxy::bind((SERVER_HOST, SERVER_PORT + 9), "radio", Some("secret")).unwrap();
+
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::Discover());
+        let (client, _) = proxy.serve_one().unwrap();
+        expect_iam(&events);
+        let meta = (0..100_000).map(|x| x as u8).collect::<Vec<_>>();
+        proxy.send(client, message_codes::METADATA, &meta).unwrap();
+        expect_input(&events, IncomingProxyMessage::Metadata(Arc::from(meta)));
+    }
+
+    #[test]
+    fn unanswered_pings_back_off() {
+        let mut round_trips = RoundTrips::default();
+        let (legacy, proxy) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        let sent = (0..600).filter(|x| round_trips.ping(legacy, *x)).count();
+        assert!(sent < 20);
+        for token in 0..600 {
+            assert!(round_trips.ping(proxy, token));
+            assert!(round_trips.pong(proxy, token));
+            assert!(!round_trips.pong(proxy, token));
+        }
+        // A proxy that answers again is pinged on every tick.
+        let token = (600..).find(|x| round_trips.ping(legacy, *x)).unwrap();
+        assert!(round_trips.pong(legacy, token));
+        assert!(round_trips.ping(legacy, 2000));
+    }
 
-            let socket_addr = (SERVER_HOST, SERVER_PORT + 3)
-                .to_socket_addrs()
-                .unwrap()
-                .next()
-                .unwrap();
-            let socket = UdpSocket::bind(socket_addr).unwrap();
-
-            let msg = prepare_msg(message_codes::DISCOVER, &[]).unwrap();
-            write(&socket_addr, OutgoingProxyMessage::Discover());
-
-            let mut buf = [0; 65535];
-            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
-            match socket.recv(&mut buf) {
-                Ok(msg_size) => {
-                    assert_eq!(msg_size, msg.len());
-                    assert_eq!(buf[..msg_size], msg[..]);
-                },
-                result => panic!("expected to receive a message but got {:?}", result),
+    #[test]
+    fn pings_measure_round_trip_time() {
+        let (bus, events) = start_client(
+            SERVER_PORT + 10,
+            ProxyPolicy::default(),
+            Security::default(),
+        );
+        let mut proxy = TestProxy::bind((SERVER_HOST, SERVER_PORT + 11), "radio", None).unwrap();
+
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::Discover());
+        proxy.serve_one().unwrap();
+        // Proxies that don't know PINGs are measured by their answer to the DISCOVER.
+        assert!(expect_iam(&events).unwrap() < Duration::from_secs(5));
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::Ping());
+        let (client, code) = proxy.serve_one().unwrap();
+        assert_eq!(code, message_codes::PING);
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(EventModel::ProxyInput((_, IncomingProxyMessage::Pong(rtt)))) => {
+                assert!(rtt < Duration::from_secs(5))
             }
+            result => panic!("expected a PONG but got {:?}", result),
         }
+        // A PONG must answer a PING that was actually sent.
+        proxy
+            .send(client, message_codes::PONG, &0_u64.to_be_bytes())
+            .unwrap();
+        proxy.send(client, message_codes::AUDIO, &[3]).unwrap();
+        expect_input(&events, IncomingProxyMessage::Audio(Arc::from([3])));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_client_talks_to_proxies() {
+        use crate::runtime::Runtime;
+
+        let runtime = Runtime::new().unwrap();
+        let bus = Bus::default();
+        let events = bus.model.subscribe();
+        runtime.spawn(serve(
+            (SERVER_HOST, SERVER_PORT + 12),
+            ProxyPolicy::default(),
+            Security::default(),
+            bus.clone(),
+            runtime.forward(bus.proxy.subscribe()),
+            runtime.shutdown(),
+        ));
+        let mut proxy = TestProxy::bind((SERVER_HOST, SERVER_PORT + 13), "radio", None).unwrap();
+
+        write(&bus.proxy, &proxy.addr(), OutgoingProxyMessage::Discover());
+        let (client, code) = proxy.serve_one().unwrap();
+        assert_eq!(code, message_codes::DISCOVER);
+        expect_iam(&events);
+        proxy.send(client, message_codes::AUDIO, &[2]).unwrap();
+        expect_input(&events, IncomingProxyMessage::Audio(Arc::from([2])));
+        runtime.stop();
     }
 }
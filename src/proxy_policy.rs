@@ -0,0 +1,228 @@
+use crate::access::Cidr;
+use crate::config::Config;
+use crate::proxy::IncomingProxyMessage;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Bounds the memory used for rate limiting when datagrams come from many (possibly spoofed)
+// addresses.
+const MAX_UNKNOWN_SENDERS: usize = 1024;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Accept(),
+    /// Rejected traffic should be logged with the given reason.
+    Reject(&'static str),
+    /// The sender is sending too much to be even logged.
+    Drop(),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Decides which datagrams reach the model. Proxies are trusted if they answered our DISCOVER
+/// or are on the allowlist, configured in the `[proxy]` section:
+///
+/// ```text
+/// [proxy]
+/// allow = 192.168.1.10
+/// max-proxies = 32
+/// discovery-window = 5   # seconds after a DISCOVER in which IAM answers are accepted
+/// unknown-rate = 5       # datagrams per second processed from each unknown sender
+/// ```
+pub struct ProxyPolicy {
+    allow: Vec<Cidr>,
+    max_proxies: usize,
+    discovery_window: Duration,
+    unknown_rate: f64,
+    expiry: Duration,
+    last_discovery: Option<Instant>,
+    trusted: HashMap<SocketAddr, Instant>,
+    unknown: HashMap<SocketAddr, Bucket>,
+}
+
+impl Default for ProxyPolicy {
+    fn default() -> ProxyPolicy {
+        ProxyPolicy {
+            allow: vec![],
+            max_proxies: 32,
+            discovery_window: Duration::from_secs(5),
+            unknown_rate: 5.0,
+            expiry: Duration::from_secs(5),
+            last_discovery: None,
+            trusted: HashMap::new(),
+            unknown: HashMap::new(),
+        }
+    }
+}
+
+impl ProxyPolicy {
+    /// Trusted proxies that stay silent for `expiry` have to be discovered again.
+    pub fn new(config: &Config, expiry: Duration) -> Result<ProxyPolicy> {
+        let mut policy = ProxyPolicy {
+            expiry,
+            ..ProxyPolicy::default()
+        };
+        for (key, value) in config.section("proxy") {
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid value of {}: {}", key, value))
+            };
+            match key {
+                "allow" => policy.allow.push(Cidr::parse(value)?),
+                "max-proxies" => policy.max_proxies = number()? as usize,
+                "discovery-window" => policy.discovery_window = Duration::from_secs(number()?),
+                "unknown-rate" => policy.unknown_rate = number()? as f64,
+                _ => return Err(anyhow!("unknown proxy setting: {}", key)),
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn discovery_sent(&mut self, now: Instant) {
+        self.last_discovery = Some(now);
+    }
+
+    /// Checks a datagram; `msg` is None if it couldn't be parsed.
+    pub fn check(
+        &mut self,
+        src: SocketAddr,
+        msg: Option<&IncomingProxyMessage>,
+        now: Instant,
+    ) -> Verdict {
+        let expiry = self.expiry;
+        self.trusted
+            .retain(|_, last_seen| now.duration_since(*last_seen) < expiry);
+        if let Some(last_seen) = self.trusted.get_mut(&src) {
+            *last_seen = now;
+            return Verdict::Accept();
+        }
+        if !self.take_token(src, now) {
+            return Verdict::Drop();
+        }
+        let allowed = self.allow.iter().any(|x| x.contains(src.ip()));
+        let answers_discovery = matches!(msg, Some(IncomingProxyMessage::IAM(_)))
+            && self
+                .last_discovery
+                .is_some_and(|at| now.duration_since(at) <= self.discovery_window);
+        if !allowed && !answers_discovery {
+            return Verdict::Reject("unsolicited traffic");
+        }
+        if self.trusted.len() >= self.max_proxies {
+            return Verdict::Reject("too many proxies");
+        }
+        self.unknown.remove(&src);
+        self.trusted.insert(src, now);
+        Verdict::Accept()
+    }
+
+    fn take_token(&mut self, src: SocketAddr, now: Instant) -> bool {
+        if !self.unknown.contains_key(&src) && self.unknown.len() >= MAX_UNKNOWN_SENDERS {
+            let oldest = self
+                .unknown
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(addr, _)| *addr);
+            if let Some(addr) = oldest {
+                self.unknown.remove(&addr);
+            }
+        }
+        let rate = self.unknown_rate;
+        let bucket = self.unknown.entry(src).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn iam() -> IncomingProxyMessage {
+        IncomingProxyMessage::IAM((Arc::from("radio"), None))
+    }
+
+    fn audio() -> IncomingProxyMessage {
+        IncomingProxyMessage::Audio(Arc::from(&[0_u8][..]))
+    }
+
+    #[test]
+    fn only_answers_to_discovery_are_trusted() {
+        let mut policy = ProxyPolicy::default();
+        let (proxy, now) = ("10.0.0.1:4000".parse().unwrap(), Instant::now());
+        assert_eq!(
+            policy.check(proxy, Some(&iam()), now),
+            Verdict::Reject("unsolicited traffic")
+        );
+        policy.discovery_sent(now);
+        assert_eq!(
+            policy.check(proxy, Some(&audio()), now),
+            Verdict::Reject("unsolicited traffic")
+        );
+        assert_eq!(policy.check(proxy, Some(&iam()), now), Verdict::Accept());
+        let later = now + Duration::from_secs(3);
+        assert_eq!(
+            policy.check(proxy, Some(&audio()), later),
+            Verdict::Accept()
+        );
+        let other = "10.0.0.2:4000".parse().unwrap();
+        assert_eq!(
+            policy.check(other, Some(&iam()), later + Duration::from_secs(3)),
+            Verdict::Reject("unsolicited traffic")
+        );
+    }
+
+    #[test]
+    fn allowlist_and_limits_are_applied() {
+        let config = Config::parse("[proxy]\nallow = 10.0.0.0/8\nmax-proxies = 1\n").unwrap();
+        let mut policy = ProxyPolicy::new(&config, Duration::from_secs(5)).unwrap();
+        let now = Instant::now();
+        let first = "10.0.0.1:4000".parse().unwrap();
+        assert_eq!(policy.check(first, Some(&audio()), now), Verdict::Accept());
+        let second = "10.0.0.2:4000".parse().unwrap();
+        assert_eq!(
+            policy.check(second, Some(&audio()), now),
+            Verdict::Reject("too many proxies")
+        );
+        // Once the first proxy goes silent, its slot is freed.
+        let later = now + Duration::from_secs(6);
+        assert_eq!(
+            policy.check(second, Some(&audio()), later),
+            Verdict::Accept()
+        );
+    }
+
+    #[test]
+    fn unknown_senders_are_rate_limited() {
+        let mut policy = ProxyPolicy::default();
+        let (sender, now) = ("10.0.0.1:4000".parse().unwrap(), Instant::now());
+        for _ in 0..5 {
+            assert_eq!(
+                policy.check(sender, None, now),
+                Verdict::Reject("unsolicited traffic")
+            );
+        }
+        assert_eq!(policy.check(sender, None, now), Verdict::Drop());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            policy.check(sender, None, later),
+            Verdict::Reject("unsolicited traffic")
+        );
+    }
+}
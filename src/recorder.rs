@@ -0,0 +1,168 @@
+use crate::config::Config;
+use crate::frames::Codec;
+use crate::tags::Tags;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+struct Recording {
+    file: File,
+    path: PathBuf,
+}
+
+/// Saves the audio frames received from proxies to files, independently of what is played.
+/// Files are created in `dir` from the `[recording]` section, or in the working directory.
+pub struct Recorder {
+    dir: PathBuf,
+    recordings: HashMap<SocketAddr, Recording>,
+}
+
+impl Recorder {
+    pub fn new(config: &Config) -> Recorder {
+        Recorder {
+            dir: recording_dir(config),
+            recordings: HashMap::new(),
+        }
+    }
+
+    pub fn is_recording(&self, addr: &SocketAddr) -> bool {
+        self.recordings.contains_key(addr)
+    }
+
+    /// Starts recording a proxy to a new file named after the station and the current time.
+    pub fn start(
+        &mut self,
+        addr: SocketAddr,
+        name: &str,
+        codec: Option<Codec>,
+        tags: &Tags,
+    ) -> Result<()> {
+        let (file, path) = create(&self.dir, name, codec, tags)?;
+        log!("recording {} to {:?}", addr, path);
+        self.recordings.insert(addr, Recording { file, path });
+        Ok(())
+    }
+
+    pub fn stop(&mut self, addr: &SocketAddr) {
+        if let Some(recording) = self.recordings.remove(addr) {
+            log!("stopped recording {} to {:?}", addr, recording.path);
+        }
+    }
+
+    /// Appends frames to the recording of a proxy, if there is one. The recording is stopped
+    /// when the file can't be written.
+    pub fn write(&mut self, addr: &SocketAddr, frames: &[u8]) -> Result<()> {
+        let recording = match self.recordings.get_mut(addr) {
+            Some(recording) => recording,
+            None => return Ok(()),
+        };
+        let path = recording.path.clone();
+        if let Err(err) = recording.file.write_all(frames) {
+            self.recordings.remove(addr);
+            return Err(err).with_context(|| format!("could not write recording {:?}", path));
+        }
+        Ok(())
+    }
+}
+
+pub fn recording_dir(config: &Config) -> PathBuf {
+    PathBuf::from(config.get("recording", "dir").unwrap_or("."))
+}
+
+/// Creates a file for a new recording, named after the station and the time it starts. Files
+/// of a known format begin with an ID3 tag.
+pub fn create(
+    dir: &Path,
+    name: &str,
+    codec: Option<Codec>,
+    tags: &Tags,
+) -> Result<(File, PathBuf)> {
+    let path = dir.join(file_name(
+        name,
+        &tags.recorded.format("%Y%m%d-%H%M%S").to_string(),
+        codec,
+    ));
+    let mut file =
+        File::create(&path).with_context(|| format!("could not create recording {:?}", path))?;
+    if codec.is_some() {
+        file.write_all(&tags.id3v2())
+            .with_context(|| format!("could not write recording {:?}", path))?;
+    }
+    Ok((file, path))
+}
+
+fn file_name(name: &str, time: &str, codec: Option<Codec>) -> String {
+    let name = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let extension = match codec {
+        Some(Codec::Mpeg { layer: 3, .. }) => "mp3",
+        Some(Codec::Mpeg { layer: 2, .. }) => "mp2",
+        Some(Codec::Mpeg { .. }) => "mpa",
+        Some(Codec::Aac { .. }) => "aac",
+        None => "bin",
+    };
+    format!("{}-{}.{}", name, time, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use std::fs::{read, remove_file};
+
+    #[test]
+    fn file_names_are_safe() {
+        let mp3 = Some(Codec::Mpeg {
+            version: 1,
+            layer: 3,
+        });
+        assert_eq!(
+            file_name(" Radio Nowy Świat/128k ", "20240101-120000", mp3),
+            "Radio_Nowy_Świat_128k-20240101-120000.mp3"
+        );
+        assert_eq!(file_name("../x", "t", None), "___x-t.bin");
+    }
+
+    #[test]
+    fn frames_of_recorded_proxies_are_saved() {
+        let dir = std::env::temp_dir();
+        let config = Config::parse(&format!("[recording]\ndir = {}\n", dir.display())).unwrap();
+        let mut recorder = Recorder::new(&config);
+        let (addr, other) = ("10.0.0.1:1".parse().unwrap(), "10.0.0.1:2".parse().unwrap());
+        let name = format!("skclient-test-{}", std::process::id());
+        let tags = Tags::new("Radio", "", Local::now().naive_local());
+        recorder.start(addr, &name, None, &tags).unwrap();
+        recorder.write(&addr, b"abc").unwrap();
+        recorder.write(&other, b"xyz").unwrap();
+        let path = recorder.recordings[&addr].path.clone();
+        recorder.stop(&addr);
+        assert!(!recorder.is_recording(&addr));
+        assert_eq!(read(&path).unwrap(), b"abc");
+        remove_file(&path).unwrap();
+        // Files of a known format are tagged.
+        let mp3 = Some(Codec::Mpeg {
+            version: 1,
+            layer: 3,
+        });
+        recorder.start(addr, &name, mp3, &tags).unwrap();
+        recorder.write(&addr, b"abc").unwrap();
+        let path = recorder.recordings[&addr].path.clone();
+        recorder.stop(&addr);
+        let mut expected = tags.id3v2();
+        expected.extend(b"abc");
+        assert_eq!(read(&path).unwrap(), expected);
+        remove_file(&path).unwrap();
+    }
+}
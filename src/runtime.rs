@@ -0,0 +1,130 @@
+use crate::bus::{Subscription, Topic};
+use anyhow::Result;
+use crossbeam::crossbeam_channel::RecvTimeoutError;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// How long a forwarding thread waits for a message before it checks whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Messages forwarded to a task that hasn't taken them yet.
+const FORWARD_CAPACITY: usize = 64;
+/// How long tasks get to finish once the client stops.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Publishes to a topic from a task. Publishing to a `block` queue waits for its subscribers, so
+/// the worker's other tasks are handed over to another thread meanwhile.
+pub fn publish<T: Clone>(topic: &Topic<T>, msg: T) {
+    tokio::task::block_in_place(|| topic.publish(msg))
+}
+
+/// Tokio, set up to run the client's servers. Tasks are told to stop through a watch channel,
+/// which they wait on next to their sockets, so that they finish between two operations rather
+/// than in the middle of one.
+pub struct Runtime {
+    runtime: tokio::runtime::Runtime,
+    shutdown: watch::Sender<bool>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Runtime {
+    pub fn new() -> Result<Runtime> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let (shutdown, _) = watch::channel(false);
+        Ok(Runtime {
+            runtime,
+            shutdown,
+            tasks: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        self.tasks.lock().unwrap().push(self.runtime.spawn(future));
+    }
+
+    /// Runs a future on the current thread, while the spawned tasks run on the workers.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Changes when the client stops.
+    pub fn shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Passes the messages of a subscription to a task. Receiving from the bus blocks, so a
+    /// thread of the blocking pool waits for them.
+    pub fn forward<T: Send + 'static>(&self, subscription: Subscription<T>) -> mpsc::Receiver<T> {
+        let (sender, receiver) = mpsc::channel(FORWARD_CAPACITY);
+        let shutdown = self.shutdown();
+        self.runtime.spawn_blocking(move || {
+            while !*shutdown.borrow() {
+                match subscription.recv_timeout(POLL_INTERVAL) {
+                    Ok(msg) => {
+                        if sender.blocking_send(msg).is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Tells the tasks to stop and waits a moment for them. Those that don't finish in time are
+    /// dropped.
+    pub fn stop(self) {
+        let _ = self.shutdown.send(true);
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        self.runtime.block_on(async {
+            let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+                for task in tasks {
+                    let _ = task.await;
+                }
+            })
+            .await;
+        });
+        self.runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Overflow, QueueSettings, Topic};
+    use std::time::Instant;
+
+    #[test]
+    fn forwarding_stops_with_the_runtime() {
+        let runtime = Runtime::new().unwrap();
+        let topic = Topic::new(
+            "test",
+            QueueSettings {
+                capacity: 8,
+                overflow: Overflow::Block(),
+            },
+        );
+        let mut messages = runtime.forward(topic.subscribe());
+        topic.publish(1);
+        topic.publish(2);
+        let received = runtime.block_on(async {
+            vec![
+                messages.recv().await.unwrap(),
+                messages.recv().await.unwrap(),
+            ]
+        });
+        assert_eq!(received, vec![1, 2]);
+        // The forwarding thread doesn't hold up the shutdown, and lets go of its subscription.
+        let start = Instant::now();
+        runtime.stop();
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT * 2);
+        topic.publish(3);
+        assert_eq!(topic.stats().subscribers, 0);
+    }
+}
@@ -0,0 +1,530 @@
+use crate::config::Config;
+use crate::model::ProxyInfo;
+use crate::recorder;
+use crate::stations::{self, Station};
+use crate::tags;
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, Duration as Span, NaiveDate, NaiveDateTime, NaiveTime};
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How many finished jobs are listed.
+const MAX_FINISHED: usize = 20;
+/// How far ahead the next time of a cron expression is looked for.
+const MAX_YEARS: i64 = 5;
+/// The longest recording, in seconds.
+const MAX_DURATION: u64 = 7 * 24 * 3600;
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Times given as `minute hour day-of-month month day-of-week`, as in crontab. Fields are `*`,
+/// numbers, ranges and lists, optionally with steps, e.g. `*/15`, `1-5` or `mon,wed,fri`.
+/// As in cron, when both days are restricted, either of them may match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let value = |x: &str| -> Result<u32> {
+        match names.iter().position(|name| x.eq_ignore_ascii_case(name)) {
+            Some(index) => Ok(index as u32),
+            None => x
+                .parse::<u32>()
+                .map_err(|_| anyhow!("invalid value: {}", x)),
+        }
+    };
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>()?),
+            None => (part, 1),
+        };
+        let (low, high) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((low, high)) => (value(low)?, value(high)?),
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if step == 0 || low < min || high > max || low > high {
+            return Err(anyhow!("invalid field: {}", text));
+        }
+        for x in (low..=high).step_by(step) {
+            mask |= 1 << x;
+        }
+    }
+    Ok(mask)
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> Result<Cron> {
+        let fields = text.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(anyhow!("expected 5 fields: {}", text));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7, &WEEKDAYS)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &[])?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.months & 1 << date.month() != 0
+    }
+
+    /// The first matching minute after `time`.
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut date = time.date();
+        while date <= time.date() + Span::days(366 * MAX_YEARS) {
+            if self.matches_date(date) {
+                for hour in (0..24).filter(|x| self.hours & 1 << x != 0) {
+                    for minute in (0..60).filter(|x| self.minutes & 1 << x != 0) {
+                        let candidate = date.and_hms_opt(hour, minute, 0)?;
+                        if candidate > time {
+                            return Some(candidate);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// Parses durations such as `90m`, `2h` or `1h30m`.
+fn parse_duration(text: &str) -> Result<Span> {
+    let (mut total, mut number) = (0, String::new());
+    for c in text.trim().chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'h' | 'm' | 's' if !number.is_empty() => {
+                let unit = match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                total = number
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|x| x.checked_mul(unit))
+                    .and_then(|x| x.checked_add(total))
+                    .filter(|x| *x <= MAX_DURATION)
+                    .ok_or_else(|| anyhow!("duration longer than a week: {}", text))?;
+                number.clear();
+            }
+            _ => return Err(anyhow!("invalid duration: {}", text)),
+        }
+    }
+    if !number.is_empty() || total == 0 {
+        return Err(anyhow!("invalid duration: {}", text));
+    }
+    Ok(Span::seconds(total as i64))
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JobState {
+    Pending(),
+    /// The recording should be on, but the station isn't available.
+    Waiting(),
+    Recording(),
+    Finished(),
+    /// The station wasn't available during the whole time window.
+    Missed(),
+    Failed(),
+}
+
+/// A single recording of a station in a time window.
+pub struct Job {
+    /// The name of the rule the job comes from, empty for one-off jobs.
+    pub name: String,
+    pub station: String,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub state: JobState,
+    rule: Option<usize>,
+    /// The relay being recorded. It changes when the relay goes silent.
+    relay: Option<SocketAddr>,
+    file: Option<File>,
+    path: Option<PathBuf>,
+}
+
+struct Rule {
+    name: String,
+    cron: Cron,
+    duration: Span,
+    station: String,
+}
+
+/// Records stations at planned times, in files created like those of manual recordings.
+/// Weekly shows are set in the `[schedule]` section as `name = cron; duration; station`:
+///
+/// ```text
+/// [schedule]
+/// jazz = 0 20 * * fri; 1h30m; Radio Jazz
+/// ```
+///
+/// One-off recordings are added at runtime as `YYYY-MM-DD HH:MM; duration; station`, or with
+/// just `HH:MM` for the next such time.
+pub struct Schedule {
+    dir: PathBuf,
+    jobs: Vec<Job>,
+    rules: Vec<Rule>,
+}
+
+fn split_spec(spec: &str) -> Result<(&str, Span, &str)> {
+    let parts = spec.split(';').map(|x| x.trim()).collect::<Vec<_>>();
+    match parts[..] {
+        [time, duration, station] if !station.is_empty() => {
+            Ok((time, parse_duration(duration)?, station))
+        }
+        _ => Err(anyhow!("expected time; duration; station: {}", spec)),
+    }
+}
+
+impl Schedule {
+    pub fn new(config: &Config) -> Result<Schedule> {
+        let mut rules = vec![];
+        for (name, spec) in config.section("schedule") {
+            let (cron, duration, station) =
+                split_spec(spec).with_context(|| format!("invalid schedule {}", name))?;
+            rules.push(Rule {
+                name: name.to_string(),
+                cron: Cron::parse(cron).with_context(|| format!("invalid schedule {}", name))?,
+                duration,
+                station: station.to_string(),
+            });
+        }
+        Ok(Schedule {
+            dir: recorder::recording_dir(config),
+            jobs: vec![],
+            rules,
+        })
+    }
+
+    /// Jobs by start time, including recently finished ones.
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Adds a one-off recording.
+    pub fn add(&mut self, spec: &str, now: NaiveDateTime) -> Result<()> {
+        let (time, duration, station) = split_spec(spec)?;
+        let start = match NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+            Ok(start) => start,
+            Err(_) => {
+                let time = NaiveTime::parse_from_str(time, "%H:%M")
+                    .map_err(|_| anyhow!("invalid time: {}", time))?;
+                let today = now.date().and_time(time);
+                if today > now {
+                    today
+                } else {
+                    today + Span::days(1)
+                }
+            }
+        };
+        let end = start
+            .checked_add_signed(duration)
+            .ok_or_else(|| anyhow!("invalid time: {}", time))?;
+        if end <= now {
+            return Err(anyhow!("the recording would already be over: {}", spec));
+        }
+        log!("scheduled a recording of {} at {}", station, start);
+        self.push(Job::new("", station, start, end, None));
+        Ok(())
+    }
+
+    fn push(&mut self, job: Job) {
+        let index = self
+            .jobs
+            .iter()
+            .position(|x| x.start > job.start)
+            .unwrap_or(self.jobs.len());
+        self.jobs.insert(index, job);
+    }
+
+    /// Starts, moves and stops recordings. Called periodically.
+    pub fn tick(
+        &mut self,
+        now: NaiveDateTime,
+        stations: &[Station],
+        proxies: &[ProxyInfo],
+        stale_after: Duration,
+    ) {
+        let mut planned = vec![];
+        for (index, rule) in self.rules.iter().enumerate() {
+            if self
+                .jobs
+                .iter()
+                .any(|x| x.rule == Some(index) && x.end > now)
+            {
+                continue;
+            }
+            let duration = rule.duration;
+            // A show that's already on is recorded from now on.
+            let after = self
+                .jobs
+                .iter()
+                .filter(|x| x.rule == Some(index))
+                .map(|x| x.start)
+                .max()
+                .unwrap_or(now - duration);
+            if let Some(start) = rule.cron.next_after(after) {
+                if let Some(end) = start.checked_add_signed(duration) {
+                    planned.push(Job::new(&rule.name, &rule.station, start, end, Some(index)));
+                }
+            }
+        }
+        for job in planned {
+            self.push(job);
+        }
+        for job in self.jobs.iter_mut() {
+            job.update(now, stations, proxies, &self.dir, stale_after);
+        }
+        let finished = self.jobs.iter().filter(|x| x.is_over()).count();
+        let mut excess = finished.saturating_sub(MAX_FINISHED);
+        self.jobs.retain(|x| {
+            let remove = excess > 0 && x.is_over();
+            excess -= remove as usize;
+            !remove
+        });
+    }
+
+    /// Appends audio frames from a relay to the jobs recording it.
+    pub fn write(&mut self, addr: &SocketAddr, frames: &[u8]) -> Result<()> {
+        for job in self.jobs.iter_mut() {
+            if job.state != JobState::Recording() || job.relay != Some(*addr) {
+                continue;
+            }
+            if let Some(file) = job.file.as_mut() {
+                if let Err(err) = file.write_all(frames) {
+                    job.state = JobState::Failed();
+                    job.file = None;
+                    return Err(err).with_context(|| format!("could not write {:?}", job.path));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Job {
+    fn new(
+        name: &str,
+        station: &str,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+        rule: Option<usize>,
+    ) -> Job {
+        Job {
+            name: name.to_string(),
+            station: station.to_string(),
+            start,
+            end,
+            state: JobState::Pending(),
+            rule,
+            relay: None,
+            file: None,
+            path: None,
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        matches!(
+            self.state,
+            JobState::Finished() | JobState::Missed() | JobState::Failed()
+        )
+    }
+
+    fn update(
+        &mut self,
+        now: NaiveDateTime,
+        stations: &[Station],
+        proxies: &[ProxyInfo],
+        dir: &std::path::Path,
+        stale_after: Duration,
+    ) {
+        if self.is_over() || now < self.start {
+            return;
+        }
+        if now >= self.end {
+            self.state = match self.path {
+                Some(_) => JobState::Finished(),
+                None => JobState::Missed(),
+            };
+            self.file = None;
+            log!("recording of {} ended: {:?}", self.station, self.path);
+            return;
+        }
+        let silent = |addr: SocketAddr| stations::is_silent(proxies, addr, stale_after);
+        if self.relay.is_some_and(|x| !silent(x)) {
+            return;
+        }
+        let key = stations::normalize(&self.station);
+        let relay = stations
+            .iter()
+            .find(|x| x.key == key)
+            .map(|x| stations::healthiest(x, proxies, stale_after))
+            .filter(|x| !silent(*x));
+        let relay = match relay {
+            Some(relay) => relay,
+            None => {
+                if self.state != JobState::Waiting() {
+                    log!("waiting for {} to record it", self.station);
+                    self.state = JobState::Waiting();
+                }
+                return;
+            }
+        };
+        if self.file.is_none() {
+            let name = if self.name.is_empty() {
+                &self.station
+            } else {
+                &self.name
+            };
+            let proxy = proxies.iter().find(|x| x.addr == relay).unwrap();
+            let codec = proxy.stream.info().map(|x| x.codec);
+            let tags = tags::for_proxy(proxy, &self.station, now);
+            match recorder::create(dir, name, codec, &tags) {
+                Ok((file, path)) => {
+                    log!("recording {} to {:?}", self.station, path);
+                    self.file = Some(file);
+                    self.path = Some(path);
+                }
+                Err(err) => {
+                    log!("{:?}", err);
+                    self.state = JobState::Failed();
+                    return;
+                }
+            }
+        }
+        self.relay = Some(relay);
+        self.state = JobState::Recording();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stations::StationMap;
+    use std::collections::HashSet;
+    use std::fs::{read, remove_file};
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn cron_expressions_find_next_times() {
+        // 2024-01-03 is a Wednesday.
+        let now = time("2024-01-03 12:00");
+        let next = |cron: &str| Cron::parse(cron).unwrap().next_after(now).unwrap();
+        assert_eq!(next("30 20 * * fri"), time("2024-01-05 20:30"));
+        assert_eq!(next("*/15 * * * *"), time("2024-01-03 12:15"));
+        assert_eq!(next("0 0 1 * 0"), time("2024-01-07 00:00"));
+        assert_eq!(next("0 0 1 * *"), time("2024-02-01 00:00"));
+        assert_eq!(next("0 12 29 2 *"), time("2024-02-29 12:00"));
+        assert_eq!(next("0 9-17/4 * * mon-fri"), time("2024-01-03 13:00"));
+        assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(now), None);
+        for invalid in ["61 * * * *", "* * *", "* * * * fly", "*/0 * * * *"].iter() {
+            assert!(Cron::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn one_off_recordings_are_added() {
+        let mut schedule = Schedule::new(&Config::default()).unwrap();
+        let now = time("2024-01-03 12:00");
+        schedule
+            .add("2024-01-04 20:00; 1h30m; Radio Jazz", now)
+            .unwrap();
+        schedule.add("08:00; 30m; Jedynka", now).unwrap();
+        schedule.add("11:50; 1h; Dwójka", now).unwrap();
+        let jobs = schedule
+            .jobs()
+            .iter()
+            .map(|x| (x.station.as_str(), x.start, x.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            jobs,
+            vec![
+                (
+                    "Jedynka",
+                    time("2024-01-04 08:00"),
+                    time("2024-01-04 08:30")
+                ),
+                ("Dwójka", time("2024-01-04 11:50"), time("2024-01-04 12:50")),
+                (
+                    "Radio Jazz",
+                    time("2024-01-04 20:00"),
+                    time("2024-01-04 21:30")
+                ),
+            ]
+        );
+        assert!(schedule.add("2024-01-01 10:00; 1h; Past", now).is_err());
+        assert!(schedule.add("10:00; soon; X", now).is_err());
+        assert!(schedule.add("10:00; 169h; X", now).is_err());
+        assert!(schedule
+            .add("10:00; 18446744073709551615h; X", now)
+            .is_err());
+        assert!(schedule
+            .add("10:00; 99999999999999999999s; X", now)
+            .is_err());
+        assert!(schedule.add("+262142-12-31 23:00; 2h; X", now).is_err());
+        assert!(schedule.add("10:00; 1h", now).is_err());
+    }
+
+    #[test]
+    fn scheduled_stations_are_recorded() {
+        let dir = std::env::temp_dir();
+        let config = Config::parse(&format!(
+            "[recording]\ndir = {}\n[schedule]\nskclient-test-{} = 0 20 * * *; 1h; Radio Jazz\n",
+            dir.display(),
+            std::process::id()
+        ))
+        .unwrap();
+        let mut schedule = Schedule::new(&config).unwrap();
+        let proxies = vec![ProxyInfo::new("10.0.0.1:1".parse().unwrap(), "radio jazz")];
+        let addr = proxies[0].addr;
+        let stations = stations::group(&proxies, &StationMap::default(), &HashSet::new());
+        let stale_after = Duration::from_secs(60);
+        // The show is already on when the client starts, but the station isn't there yet.
+        schedule.tick(time("2024-01-03 20:10"), &[], &[], stale_after);
+        assert_eq!(schedule.jobs()[0].start, time("2024-01-03 20:00"));
+        assert_eq!(schedule.jobs()[0].state, JobState::Waiting());
+        schedule.tick(time("2024-01-03 20:11"), &stations, &proxies, stale_after);
+        assert_eq!(schedule.jobs()[0].state, JobState::Recording());
+        schedule.write(&addr, b"abc").unwrap();
+        schedule.tick(time("2024-01-03 21:00"), &stations, &proxies, stale_after);
+        let jobs = schedule.jobs();
+        assert_eq!(jobs[0].state, JobState::Finished());
+        assert_eq!(jobs[1].start, time("2024-01-04 20:00"));
+        let path = jobs[0].path.clone().unwrap();
+        assert_eq!(read(&path).unwrap(), b"abc");
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use std::cmp::{max, min};
+
+/// A character on the screen together with the SGR parameters it's displayed with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Cell {
+    ch: char,
+    style: String,
+}
+
+/// What a telnet user currently sees. Each new frame is compared with the previous one, so that
+/// only the changed cells are sent over the network.
+pub struct Screen {
+    rows: Vec<Vec<Cell>>,
+    valid: bool,
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen {
+            rows: vec![],
+            valid: false,
+        }
+    }
+
+    /// Makes the next update redraw the whole screen, e.g. after the terminal got garbled.
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    /// Takes a frame made of `\r\n`-terminated rows with SGR sequences and returns what has to be
+    /// written to the terminal to display it. The result is empty if nothing changed.
+    pub fn update(&mut self, text: &str) -> String {
+        let rows = parse(text);
+        let mut output = String::new();
+        if self.valid {
+            for i in 0..max(self.rows.len(), rows.len()) {
+                let old = self.rows.get(i).map(Vec::as_slice).unwrap_or(&[]);
+                let new = rows.get(i).map(Vec::as_slice).unwrap_or(&[]);
+                output.push_str(&diff_row(i, old, new));
+            }
+            if output.is_empty() {
+                return output;
+            }
+        } else {
+            output.push_str(CLEAR_SCREEN);
+            for (i, row) in rows.iter().enumerate() {
+                output.push_str(&diff_row(i, &[], row));
+            }
+            self.valid = true;
+        }
+        // Leave the cursor below the last row, where a full redraw would leave it.
+        output.push_str(&move_to(rows.len(), 0));
+        self.rows = rows;
+        output
+    }
+}
+
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+fn move_to(row: usize, column: usize) -> String {
+    format!("\x1b[{};{}H", row + 1, column + 1)
+}
+
+fn set_style(style: &str) -> String {
+    if style.is_empty() {
+        "\x1b[0m".to_string()
+    } else {
+        format!("\x1b[0;{}m", style)
+    }
+}
+
+fn diff_row(row: usize, old: &[Cell], new: &[Cell]) -> String {
+    let first = match old.iter().zip(new).position(|(a, b)| a != b) {
+        Some(pos) => pos,
+        None if old.len() == new.len() => return "".to_string(),
+        None => min(old.len(), new.len()),
+    };
+    // With equal lengths, the unchanged tail doesn't need to be written either.
+    let end = if old.len() == new.len() {
+        new.len()
+            - old
+                .iter()
+                .rev()
+                .zip(new.iter().rev())
+                .position(|(a, b)| a != b)
+                .unwrap()
+    } else {
+        new.len()
+    };
+    let mut output = move_to(row, first);
+    let mut style = "";
+    for cell in &new[first..end] {
+        if cell.style != style {
+            style = &cell.style;
+            output.push_str(&set_style(style));
+        }
+        output.push(cell.ch);
+    }
+    if !style.is_empty() {
+        output.push_str(&set_style(""));
+    }
+    if new.len() < old.len() {
+        output.push_str("\x1b[K");
+    }
+    output
+}
+
+/// Splits a frame into rows of cells, interpreting `ESC [ ... m` sequences.
+fn parse(text: &str) -> Vec<Vec<Cell>> {
+    let mut rows = vec![];
+    for line in text.split_terminator("\r\n") {
+        let mut row = vec![];
+        let mut style = String::new();
+        let mut chars = line.chars();
+        while let Some(ch) = chars.next() {
+            if ch != '\x1b' {
+                row.push(Cell {
+                    ch,
+                    style: style.clone(),
+                });
+                continue;
+            }
+            let params: String = chars.by_ref().skip(1).take_while(|x| *x != 'm').collect();
+            style = match params.as_str() {
+                "" | "0" => String::new(),
+                _ if params.starts_with("0;") => params[2..].to_string(),
+                _ if style.is_empty() => params,
+                _ => format!("{};{}", style, params),
+            };
+        }
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_update_redraws_everything() {
+        let mut screen = Screen::new();
+        assert_eq!(
+            screen.update("ab\r\n\x1b[0;7mc\x1b[0m\r\n"),
+            "\x1b[H\x1b[2J\x1b[1;1Hab\x1b[2;1H\x1b[0;7mc\x1b[0m\x1b[3;1H"
+        );
+        assert_eq!(screen.update("ab\r\n\x1b[0;7mc\x1b[0m\r\n"), "");
+        screen.invalidate();
+        assert!(screen.update("ab\r\n").starts_with(CLEAR_SCREEN));
+    }
+
+    #[test]
+    fn only_changed_cells_are_sent() {
+        let mut screen = Screen::new();
+        screen.update("Song 1 of 9\r\nmenu\r\n");
+        assert_eq!(
+            screen.update("Song 2 of 9\r\nmenu\r\n"),
+            "\x1b[1;6H2\x1b[3;1H"
+        );
+        assert_eq!(
+            screen.update("Song 2\r\n\x1b[0;7mmenu\x1b[0m\r\n"),
+            "\x1b[1;7H\x1b[K\x1b[2;1H\x1b[0;7mmenu\x1b[0m\x1b[3;1H"
+        );
+    }
+
+    #[test]
+    fn removed_rows_are_cleared() {
+        let mut screen = Screen::new();
+        screen.update("a\r\nb\r\n");
+        assert_eq!(screen.update("a\r\n"), "\x1b[2;1H\x1b[K\x1b[2;1H");
+    }
+}
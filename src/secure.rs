@@ -0,0 +1,483 @@
+use crate::config::Config;
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Starts the content of a DISCOVER that offers the extension. Proxies that don't know it
+/// ignore the content and answer with a plain IAM.
+pub const OFFER_MAGIC: &[u8] = b"SKSEC1";
+pub const NONCE_SIZE: usize = 16;
+pub const IAM_TAG_SIZE: usize = 32;
+const SEQ_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+const REPLAY_WINDOW: u64 = 64;
+
+pub type SessionNonce = [u8; NONCE_SIZE];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Datagrams are signed with a truncated HMAC-SHA256, but sent in the clear.
+    Hmac(),
+    /// Datagrams are encrypted and authenticated with ChaCha20-Poly1305.
+    Aead(),
+}
+
+impl Mode {
+    pub fn code(self) -> u8 {
+        match self {
+            Mode::Hmac() => 1,
+            Mode::Aead() => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Result<Mode> {
+        match code {
+            1 => Ok(Mode::Hmac()),
+            2 => Ok(Mode::Aead()),
+            _ => Err(anyhow!("unknown security mode: {}", code)),
+        }
+    }
+}
+
+pub fn random_nonce() -> Result<SessionNonce> {
+    let mut nonce = [0; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("could not generate a nonce: {}", e))?;
+    Ok(nonce)
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// The MAC a proxy puts in its secured IAM to prove that it knows the key. It also binds the
+/// chosen mode, so that it can't be downgraded on the way.
+pub fn iam_mac(
+    key: &[u8],
+    client_nonce: &SessionNonce,
+    mode: Mode,
+    proxy_nonce: &SessionNonce,
+    info: &[u8],
+) -> HmacSha256 {
+    mac(
+        key,
+        &[b"iam", client_nonce, &[mode.code()], proxy_nonce, info],
+    )
+}
+
+/// Protects datagrams exchanged with one peer. Each direction has its own key, derived from the
+/// pre-shared key and the nonces of both sides, and its own sequence numbers.
+pub struct Channel {
+    mode: Mode,
+    /// The nonces the channel was set up with.
+    client_nonce: SessionNonce,
+    proxy_nonce: SessionNonce,
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_seq: u64,
+    recv_highest: u64,
+    recv_seen: u64,
+}
+
+impl Channel {
+    /// The client's end of a channel.
+    pub fn new(
+        key: &[u8],
+        mode: Mode,
+        client_nonce: &SessionNonce,
+        proxy_nonce: &SessionNonce,
+    ) -> Channel {
+        let master: [u8; 32] = mac(
+            key,
+            &[b"session", client_nonce, proxy_nonce, &[mode.code()]],
+        )
+        .finalize()
+        .into_bytes()
+        .into();
+        Channel {
+            mode,
+            client_nonce: *client_nonce,
+            proxy_nonce: *proxy_nonce,
+            send_key: mac(&master, &[b"client to proxy"])
+                .finalize()
+                .into_bytes()
+                .into(),
+            recv_key: mac(&master, &[b"proxy to client"])
+                .finalize()
+                .into_bytes()
+                .into(),
+            send_seq: 0,
+            recv_highest: 0,
+            recv_seen: 0,
+        }
+    }
+
+    /// The proxy's end of a channel.
+    #[cfg(test)]
+    pub fn for_proxy(
+        key: &[u8],
+        mode: Mode,
+        client_nonce: &SessionNonce,
+        proxy_nonce: &SessionNonce,
+    ) -> Channel {
+        let mut channel = Channel::new(key, mode, client_nonce, proxy_nonce);
+        std::mem::swap(&mut channel.send_key, &mut channel.recv_key);
+        channel
+    }
+
+    /// Wraps a whole datagram into the content of a SECURED message: a sequence number, the
+    /// datagram (encrypted in the AEAD mode) and a tag.
+    pub fn seal(&mut self, datagram: &[u8]) -> Vec<u8> {
+        self.send_seq += 1;
+        let seq = self.send_seq.to_be_bytes();
+        let mut content = seq.to_vec();
+        match self.mode {
+            Mode::Hmac() => {
+                content.extend_from_slice(datagram);
+                let tag = mac(&self.send_key, &[&seq, datagram])
+                    .finalize()
+                    .into_bytes();
+                content.extend_from_slice(&tag[..TAG_SIZE]);
+            }
+            Mode::Aead() => {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.send_key));
+                let payload = Payload {
+                    msg: datagram,
+                    aad: &seq,
+                };
+                let sealed = cipher
+                    .encrypt(Nonce::from_slice(&aead_nonce(&seq)), payload)
+                    .expect("datagrams are small enough to encrypt");
+                content.extend_from_slice(&sealed);
+            }
+        }
+        content
+    }
+
+    /// Verifies the content of a SECURED message and returns the datagram inside.
+    pub fn open(&mut self, content: &[u8]) -> Result<Vec<u8>> {
+        if content.len() < SEQ_SIZE + TAG_SIZE {
+            return Err(anyhow!("secured message too short"));
+        }
+        let (seq, body) = content.split_at(SEQ_SIZE);
+        let number = u64::from_be_bytes(seq.try_into().unwrap());
+        self.check_replay(number)?;
+        let datagram = match self.mode {
+            Mode::Hmac() => {
+                let (datagram, tag) = body.split_at(body.len() - TAG_SIZE);
+                mac(&self.recv_key, &[seq, datagram])
+                    .verify_truncated_left(tag)
+                    .map_err(|_| anyhow!("secured message failed authentication"))?;
+                datagram.to_vec()
+            }
+            Mode::Aead() => {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.recv_key));
+                let payload = Payload {
+                    msg: body,
+                    aad: seq,
+                };
+                cipher
+                    .decrypt(Nonce::from_slice(&aead_nonce(seq)), payload)
+                    .map_err(|_| anyhow!("secured message failed authentication"))?
+            }
+        };
+        self.mark_received(number);
+        Ok(datagram)
+    }
+
+    fn check_replay(&self, seq: u64) -> Result<()> {
+        let replayed = seq == 0
+            || (seq <= self.recv_highest
+                && (self.recv_highest - seq >= REPLAY_WINDOW
+                    || self.recv_seen & (1 << (self.recv_highest - seq)) != 0));
+        if replayed {
+            Err(anyhow!(
+                "secured message {} was replayed or is too old",
+                seq
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn mark_received(&mut self, seq: u64) {
+        if seq > self.recv_highest {
+            let shift = seq - self.recv_highest;
+            self.recv_seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.recv_seen << shift
+            };
+            self.recv_seen |= 1;
+            self.recv_highest = seq;
+        } else {
+            self.recv_seen |= 1 << (self.recv_highest - seq);
+        }
+    }
+}
+
+fn aead_nonce(seq: &[u8]) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[12 - SEQ_SIZE..].copy_from_slice(seq);
+    nonce
+}
+
+/// The client's side of the optional authenticated protocol, configured in the `[security]`
+/// section:
+///
+/// ```text
+/// [security]
+/// key = a long pre-shared secret
+/// mode = any          # aead, hmac or any
+/// allow-plain = false # accept proxies that don't support the extension
+/// ```
+///
+/// Without a key the client speaks the plain protocol. With a key it offers the extension in
+/// every DISCOVER, and proxies that accept it must secure all the datagrams they send.
+#[derive(Default)]
+pub struct Security {
+    key: Option<Vec<u8>>,
+    modes: Vec<Mode>,
+    allow_plain: bool,
+    nonce: Option<SessionNonce>,
+    channels: HashMap<SocketAddr, Channel>,
+}
+
+impl Security {
+    pub fn new(config: &Config) -> Result<Security> {
+        let mut security = Security {
+            modes: vec![Mode::Aead(), Mode::Hmac()],
+            ..Security::default()
+        };
+        for (key, value) in config.section("security") {
+            match key {
+                "key" if value.is_empty() => return Err(anyhow!("the security key is empty")),
+                "key" => security.key = Some(value.as_bytes().to_vec()),
+                "mode" => {
+                    security.modes = match value {
+                        "aead" => vec![Mode::Aead()],
+                        "hmac" => vec![Mode::Hmac()],
+                        "any" => vec![Mode::Aead(), Mode::Hmac()],
+                        _ => return Err(anyhow!("unknown security mode: {}", value)),
+                    }
+                }
+                "allow-plain" => {
+                    security.allow_plain = value
+                        .parse()
+                        .with_context(|| format!("invalid value of allow-plain: {}", value))?
+                }
+                _ => return Err(anyhow!("unknown security setting: {}", key)),
+            }
+        }
+        Ok(security)
+    }
+
+    /// The content of the next DISCOVER: empty in plain mode, otherwise the magic, a bitmask of
+    /// accepted modes and a fresh nonce.
+    pub fn offer(&mut self) -> Result<Vec<u8>> {
+        if self.key.is_none() {
+            return Ok(vec![]);
+        }
+        let nonce = random_nonce()?;
+        self.nonce = Some(nonce);
+        let mut content = OFFER_MAGIC.to_vec();
+        content.push(self.modes.iter().fold(0, |acc, x| acc | 1 << x.code()));
+        content.extend_from_slice(&nonce);
+        Ok(content)
+    }
+
+    /// Verifies a secured IAM (mode, proxy nonce, MAC and the proxy's info) and sets up a
+    /// channel with its sender.
+    pub fn accept_iam(&mut self, src: SocketAddr, content: &[u8]) -> Result<String> {
+        let (key, client_nonce) = match (&self.key, &self.nonce) {
+            (Some(key), Some(nonce)) => (key, nonce),
+            _ => return Err(anyhow!("unexpected secured IAM")),
+        };
+        if content.len() < 1 + NONCE_SIZE + IAM_TAG_SIZE {
+            return Err(anyhow!("secured IAM too short"));
+        }
+        let mode = Mode::from_code(content[0])?;
+        if !self.modes.contains(&mode) {
+            return Err(anyhow!("proxy chose a mode that wasn't offered"));
+        }
+        let proxy_nonce: SessionNonce = content[1..1 + NONCE_SIZE].try_into().unwrap();
+        let (tag, info) = content[1 + NONCE_SIZE..].split_at(IAM_TAG_SIZE);
+        iam_mac(key, client_nonce, mode, &proxy_nonce, info)
+            .verify_slice(tag)
+            .map_err(|_| anyhow!("secured IAM failed authentication"))?;
+        let info = std::str::from_utf8(info)?.to_string();
+        // Setting the channel up again would reset its sequence numbers, so that datagrams
+        // already received could be replayed. A proxy answers each DISCOVER once, so an IAM
+        // repeating the same answer leaves the channel as is, and a different one is refused.
+        if let Some(channel) = self.channels.get(&src) {
+            if channel.client_nonce == *client_nonce {
+                if channel.proxy_nonce != proxy_nonce {
+                    return Err(anyhow!("second IAM answering the same DISCOVER"));
+                }
+                return Ok(info);
+            }
+        }
+        let channel = Channel::new(key, mode, client_nonce, &proxy_nonce);
+        self.channels.insert(src, channel);
+        Ok(info)
+    }
+
+    /// Drops the channel of a proxy that's gone.
+    pub fn forget(&mut self, src: SocketAddr) {
+        self.channels.remove(&src);
+    }
+
+    pub fn open(&mut self, src: SocketAddr, content: &[u8]) -> Result<Vec<u8>> {
+        match self.channels.get_mut(&src) {
+            Some(channel) => channel.open(content),
+            None => Err(anyhow!("secured message from a proxy without a channel")),
+        }
+    }
+
+    /// Plain datagrams are refused from proxies that negotiated the extension, so that it can't
+    /// be stripped by an attacker, and from all proxies unless plain mode is allowed.
+    pub fn check_plain(&self, src: SocketAddr) -> Result<()> {
+        if self.key.is_none() {
+            Ok(())
+        } else if self.channels.contains_key(&src) {
+            Err(anyhow!("plain message from a secured proxy"))
+        } else if !self.allow_plain {
+            Err(anyhow!("plain messages are not allowed"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The content of a SECURED message wrapping `datagram`, if `dst` has a channel.
+    pub fn seal(&mut self, dst: SocketAddr, datagram: &[u8]) -> Option<Vec<u8>> {
+        self.channels.get_mut(&dst).map(|x| x.seal(datagram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(mode: Mode) -> (Channel, Channel) {
+        let (client_nonce, proxy_nonce) = (random_nonce().unwrap(), random_nonce().unwrap());
+        (
+            Channel::new(b"key", mode, &client_nonce, &proxy_nonce),
+            Channel::for_proxy(b"key", mode, &client_nonce, &proxy_nonce),
+        )
+    }
+
+    #[test]
+    fn channels_protect_datagrams() {
+        for mode in [Mode::Hmac(), Mode::Aead()].iter() {
+            let (mut client, mut proxy) = channels(*mode);
+            let sealed = proxy.seal(b"audio");
+            assert_eq!(
+                sealed.windows(5).any(|x| x == b"audio"),
+                *mode == Mode::Hmac()
+            );
+            let mut tampered = sealed.clone();
+            tampered[SEQ_SIZE] ^= 1;
+            assert!(client.open(&tampered).is_err());
+            assert_eq!(client.open(&sealed).unwrap(), b"audio");
+            assert_eq!(
+                proxy.open(&client.seal(b"keepalive")).unwrap(),
+                b"keepalive"
+            );
+            // A datagram the proxy sent can't be reflected back to it.
+            assert!(proxy.open(&sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn replays_are_rejected() {
+        let (mut client, mut proxy) = channels(Mode::Aead());
+        let datagrams = (0..70).map(|_| proxy.seal(b"x")).collect::<Vec<_>>();
+        assert!(client.open(&datagrams[5]).is_ok());
+        assert!(client.open(&datagrams[5]).is_err());
+        // Reordered datagrams are fine, as long as they are not too old.
+        assert!(client.open(&datagrams[2]).is_ok());
+        assert!(client.open(&datagrams[69]).is_ok());
+        assert!(client.open(&datagrams[3]).is_err());
+        assert!(client.open(&datagrams[68]).is_ok());
+    }
+
+    /// The content of an IAM answering a DISCOVER with `client_nonce`.
+    fn iam(
+        key: &[u8],
+        mode: Mode,
+        client_nonce: &SessionNonce,
+        proxy_nonce: &SessionNonce,
+    ) -> Vec<u8> {
+        let mut content = vec![mode.code()];
+        content.extend_from_slice(proxy_nonce);
+        let tag = iam_mac(key, client_nonce, mode, proxy_nonce, b"radio");
+        content.extend_from_slice(&tag.finalize().into_bytes());
+        content.extend_from_slice(b"radio");
+        content
+    }
+
+    #[test]
+    fn iam_must_prove_knowledge_of_the_key() {
+        let config = Config::parse("[security]\nkey = secret\nmode = hmac\n").unwrap();
+        let mut security = Security::new(&config).unwrap();
+        let offer = security.offer().unwrap();
+        assert_eq!(offer[OFFER_MAGIC.len()], 1 << Mode::Hmac().code());
+        let client_nonce: SessionNonce = offer[OFFER_MAGIC.len() + 1..].try_into().unwrap();
+        let proxy_nonce = random_nonce().unwrap();
+        let iam = |key: &[u8], mode: Mode| iam(key, mode, &client_nonce, &proxy_nonce);
+        let src = "10.0.0.1:4000".parse().unwrap();
+        assert!(security
+            .accept_iam(src, &iam(b"wrong", Mode::Hmac()))
+            .is_err());
+        assert!(security
+            .accept_iam(src, &iam(b"secret", Mode::Aead()))
+            .is_err());
+        assert!(security.check_plain(src).is_err());
+        assert_eq!(
+            security
+                .accept_iam(src, &iam(b"secret", Mode::Hmac()))
+                .unwrap(),
+            "radio"
+        );
+        assert!(security.seal(src, b"keepalive").is_some());
+        assert!(Security::default().check_plain(src).is_ok());
+    }
+
+    #[test]
+    fn repeated_iams_keep_the_channel() {
+        let config = Config::parse("[security]\nkey = secret\nmode = hmac\n").unwrap();
+        let mut security = Security::new(&config).unwrap();
+        let offer = security.offer().unwrap();
+        let client_nonce: SessionNonce = offer[OFFER_MAGIC.len() + 1..].try_into().unwrap();
+        let proxy_nonce = random_nonce().unwrap();
+        let first = iam(b"secret", Mode::Hmac(), &client_nonce, &proxy_nonce);
+        let src = "10.0.0.1:4000".parse().unwrap();
+        security.accept_iam(src, &first).unwrap();
+        let mut proxy = Channel::for_proxy(b"secret", Mode::Hmac(), &client_nonce, &proxy_nonce);
+        let sealed = proxy.seal(b"audio");
+        assert!(security.open(src, &sealed).is_ok());
+        // A replayed IAM doesn't reopen the channel to replayed datagrams.
+        assert_eq!(security.accept_iam(src, &first).unwrap(), "radio");
+        assert!(security.open(src, &sealed).is_err());
+        let other = iam(
+            b"secret",
+            Mode::Hmac(),
+            &client_nonce,
+            &random_nonce().unwrap(),
+        );
+        assert!(security.accept_iam(src, &other).is_err());
+        assert!(security.open(src, &proxy.seal(b"audio")).is_ok());
+        security.forget(src);
+        assert!(security.seal(src, b"keepalive").is_none());
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many datagrams may be held back while waiting for a missing one.
+const REORDER_WINDOW: usize = 16;
+/// How long a missing datagram is waited for.
+const MAX_DELAY: Duration = Duration::from_millis(500);
+/// How many delivered datagrams are remembered to tell duplicates from late datagrams.
+const HISTORY: i64 = 64;
+/// A bigger jump in sequence numbers means the proxy has restarted its numbering.
+const MAX_JUMP: i64 = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SequenceStats {
+    pub received: u64,
+    /// Datagrams that never arrived, or arrived too late to be played.
+    pub lost: u64,
+    pub duplicates: u64,
+    /// Datagrams that arrived after they had been given up on. They are also counted as lost.
+    pub late: u64,
+}
+
+/// Puts sequenced audio datagrams back in order. A missing datagram is waited for until
+/// `REORDER_WINDOW` newer ones arrive or `MAX_DELAY` passes.
+#[derive(Default)]
+pub struct Sequencer {
+    // Sequence numbers are extended to i64, so that they can wrap around.
+    next: Option<i64>,
+    pending: BTreeMap<i64, (Arc<[u8]>, Instant)>,
+    // Bit n is set if datagram next - 1 - n was delivered.
+    delivered: u64,
+    stats: SequenceStats,
+}
+
+impl Sequencer {
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+
+    /// Returns the audio that can be played, in order.
+    pub fn push(&mut self, seq: u32, audio: Arc<[u8]>, now: Instant) -> Vec<Arc<[u8]>> {
+        self.stats.received += 1;
+        let next = *self.next.get_or_insert(seq as i64);
+        let seq = next + seq.wrapping_sub(next as u32) as i32 as i64;
+        if (seq - next).abs() > MAX_JUMP {
+            self.pending.clear();
+            self.delivered = 0;
+            self.next = Some(seq);
+        } else if seq < next {
+            let age = next - 1 - seq;
+            if age < HISTORY && self.delivered & (1 << age) != 0 {
+                self.stats.duplicates += 1;
+            } else {
+                self.stats.late += 1;
+            }
+            return vec![];
+        } else if self.pending.contains_key(&seq) {
+            self.stats.duplicates += 1;
+            return vec![];
+        }
+        self.pending.insert(seq, (audio, now));
+        let mut output = self.release();
+        while self.pending.len() > REORDER_WINDOW {
+            self.skip_gap();
+            output.extend(self.release());
+        }
+        output
+    }
+
+    /// Gives up on missing datagrams that have been waited for too long.
+    pub fn flush(&mut self, now: Instant) -> Vec<Arc<[u8]>> {
+        let mut output = vec![];
+        while self
+            .pending
+            .values()
+            .any(|(_, arrived)| now.duration_since(*arrived) >= MAX_DELAY)
+        {
+            self.skip_gap();
+            output.extend(self.release());
+        }
+        output
+    }
+
+    fn release(&mut self) -> Vec<Arc<[u8]>> {
+        let mut output = vec![];
+        while let Some(next) = self.next {
+            match self.pending.remove(&next) {
+                Some((audio, _)) => {
+                    output.push(audio);
+                    self.delivered = self.delivered << 1 | 1;
+                    self.next = Some(next + 1);
+                }
+                None => break,
+            }
+        }
+        output
+    }
+
+    fn skip_gap(&mut self) {
+        if let (Some(next), Some(&first)) = (self.next, self.pending.keys().next()) {
+            let missing = first - next;
+            self.stats.lost += missing as u64;
+            self.delivered = if missing >= HISTORY {
+                0
+            } else {
+                self.delivered << missing
+            };
+            self.next = Some(first);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio(byte: u8) -> Arc<[u8]> {
+        Arc::from(&[byte][..])
+    }
+
+    #[test]
+    fn late_datagrams_are_reordered() {
+        let (mut sequencer, now) = (Sequencer::default(), Instant::now());
+        assert_eq!(sequencer.push(10, audio(10), now), vec![audio(10)]);
+        assert!(sequencer.push(12, audio(12), now).is_empty());
+        assert_eq!(
+            sequencer.push(11, audio(11), now),
+            vec![audio(11), audio(12)]
+        );
+        assert!(sequencer.push(11, audio(11), now).is_empty());
+        assert_eq!(
+            sequencer.stats(),
+            SequenceStats {
+                received: 4,
+                duplicates: 1,
+                ..SequenceStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn gaps_are_skipped_after_a_while() {
+        let (mut sequencer, now) = (Sequencer::default(), Instant::now());
+        sequencer.push(u32::MAX, audio(0), now);
+        assert!(sequencer.push(1, audio(1), now).is_empty());
+        assert!(sequencer.flush(now + MAX_DELAY / 2).is_empty());
+        assert_eq!(sequencer.flush(now + MAX_DELAY), vec![audio(1)]);
+        assert!(sequencer.push(0, audio(0), now).is_empty());
+        let stats = sequencer.stats();
+        assert_eq!((stats.lost, stats.late), (1, 1));
+    }
+
+    #[test]
+    fn reorder_window_is_limited() {
+        let (mut sequencer, now) = (Sequencer::default(), Instant::now());
+        sequencer.push(0, audio(0), now);
+        for seq in 2..2 + REORDER_WINDOW as u32 {
+            assert!(sequencer.push(seq, audio(seq as u8), now).is_empty());
+        }
+        let released = sequencer.push(100, audio(100), now);
+        assert_eq!(released.len(), REORDER_WINDOW);
+        assert_eq!(sequencer.stats().lost, 1);
+        // A restarted proxy doesn't count as lost audio.
+        assert_eq!(sequencer.push(1 << 20, audio(7), now), vec![audio(7)]);
+        assert_eq!(sequencer.stats().lost, 1);
+    }
+}
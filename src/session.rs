@@ -0,0 +1,173 @@
+use crate::access::Role;
+use crate::i18n::{Lang, Msg};
+use crate::keys::KeyDecoder;
+use crate::model::ProxyInfo;
+use crate::screen::Screen;
+use crate::sort::SortOrder;
+use crate::theme::Theme;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Identifies a telnet connection.
+pub type SessionId = u64;
+
+// Digits typed within this time of each other form a single row number.
+const JUMP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// State of the user interface of a single telnet session.
+pub struct Session {
+    /// The alias being typed on the detail screen.
+    pub alias: Option<String>,
+    /// The row the cursor was on after the last update and the key of the item shown there, so
+    /// that the cursor can follow the item when the list is reordered.
+    pub anchor: Option<(i64, String)>,
+    pub cursor_line: i64,
+    /// The proxy shown on the detail screen, if it's open.
+    pub details: Option<SocketAddr>,
+    pub details_line: i64,
+    /// When the session will be closed for inactivity, once it's time to warn the user.
+    pub disconnect_at: Option<Instant>,
+    /// Keys of the stations whose relays are listed.
+    pub expanded: HashSet<String>,
+    pub failed_logins: u32,
+    pub filter: String,
+    pub id: SessionId,
+    pub keys: KeyDecoder,
+    pub lang: Lang,
+    pub last_input: Instant,
+    /// An error or status message shown until the next key press.
+    pub notice: Option<Msg>,
+    /// The name of the output the session controls, or None for the main output.
+    pub output: Option<String>,
+    /// The password being typed at the login prompt.
+    pub password: String,
+    /// None until the user logs in.
+    pub role: Option<Role>,
+    /// The recording being typed on the schedule screen, if it's open.
+    pub schedule: Option<String>,
+    pub screen: Screen,
+    pub searching: bool,
+    pub sort: SortOrder,
+    pub theme: Theme,
+    jump: Option<(usize, Instant)>,
+}
+
+impl Session {
+    pub fn new(
+        id: SessionId,
+        lang: Lang,
+        theme: Theme,
+        role: Option<Role>,
+        sort: SortOrder,
+    ) -> Session {
+        Session {
+            alias: None,
+            anchor: None,
+            cursor_line: 0,
+            details: None,
+            details_line: 0,
+            disconnect_at: None,
+            expanded: HashSet::new(),
+            failed_logins: 0,
+            filter: "".to_string(),
+            id,
+            keys: KeyDecoder::new(),
+            lang,
+            last_input: Instant::now(),
+            notice: None,
+            output: None,
+            password: "".to_string(),
+            role,
+            schedule: None,
+            screen: Screen::new(),
+            searching: false,
+            sort,
+            theme,
+            jump: None,
+        }
+    }
+
+    pub fn start_search(&mut self) {
+        self.filter.clear();
+        self.searching = true;
+    }
+
+    pub fn matches(&self, proxy: &ProxyInfo) -> bool {
+        self.filter.is_empty()
+            || !find_matches(&proxy.info, &self.filter).is_empty()
+            || !find_matches(&proxy.meta, &self.filter).is_empty()
+    }
+
+    /// Appends a typed digit to the row number being entered and returns that number.
+    pub fn jump_digit(&mut self, digit: u8, now: Instant) -> usize {
+        let digit = (digit - b'0') as usize;
+        let number = match self.jump {
+            Some((prev, at)) if now.duration_since(at) < JUMP_TIMEOUT => {
+                prev.saturating_mul(10).saturating_add(digit)
+            }
+            _ => digit,
+        };
+        self.jump = Some((number, now));
+        number
+    }
+}
+
+fn fold_case(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Returns the byte ranges of case-insensitive occurrences of `query` in `text`.
+pub fn find_matches(text: &str, query: &str) -> Vec<(usize, usize)> {
+    let query: Vec<char> = query.chars().map(fold_case).collect();
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut matches = vec![];
+    let mut i = 0;
+    while !query.is_empty() && i + query.len() <= chars.len() {
+        let candidate = &chars[i..i + query.len()];
+        if candidate
+            .iter()
+            .map(|(_, c)| fold_case(*c))
+            .eq(query.iter().cloned())
+        {
+            let end = chars
+                .get(i + query.len())
+                .map_or(text.len(), |(pos, _)| *pos);
+            matches.push((chars[i].0, end));
+            i += query.len();
+        } else {
+            i += 1;
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_matches_ignores_case() {
+        assert_eq!(
+            find_matches("Radio ZET radio", "RADIO"),
+            vec![(0, 5), (10, 15)]
+        );
+        assert_eq!(find_matches("Żółw", "óŁ"), vec![(2, 6)]);
+        assert_eq!(find_matches("abc", ""), vec![]);
+    }
+
+    #[test]
+    fn jump_digits_form_numbers() {
+        let mut session = Session::new(
+            1,
+            Lang::En(),
+            Theme::monochrome(),
+            None,
+            SortOrder::Arrival(),
+        );
+        let now = Instant::now();
+        assert_eq!(session.jump_digit(b'1', now), 1);
+        assert_eq!(session.jump_digit(b'2', now), 12);
+        assert_eq!(session.jump_digit(b'3', now + JUMP_TIMEOUT), 3);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::i18n::Msg;
+use crate::model::ProxyInfo;
+use crate::stations::Station;
+use anyhow::{anyhow, Result};
+use std::cmp::Reverse;
+use std::time::{Duration, SystemTime};
+
+/// How the station list is ordered. Each telnet session can cycle through the orders; the
+/// initial one is set with `sort` in the `[ui]` section of the config file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SortOrder {
+    /// The order in which stations were discovered.
+    Arrival(),
+    Name(),
+    Address(),
+    Latency(),
+    /// Highest first.
+    Bitrate(),
+    /// Favourites first, then by name.
+    Favourites(),
+    /// Most recently heard first.
+    LastSeen(),
+}
+
+const ALL: [SortOrder; 7] = [
+    SortOrder::Arrival(),
+    SortOrder::Name(),
+    SortOrder::Address(),
+    SortOrder::Latency(),
+    SortOrder::Bitrate(),
+    SortOrder::Favourites(),
+    SortOrder::LastSeen(),
+];
+
+impl SortOrder {
+    pub fn parse(name: &str) -> Result<SortOrder> {
+        Ok(match name {
+            "arrival" => SortOrder::Arrival(),
+            "name" => SortOrder::Name(),
+            "address" => SortOrder::Address(),
+            "latency" => SortOrder::Latency(),
+            "bitrate" => SortOrder::Bitrate(),
+            "favourites" => SortOrder::Favourites(),
+            "last-seen" => SortOrder::LastSeen(),
+            _ => return Err(anyhow!("unknown sort order: {}", name)),
+        })
+    }
+
+    pub fn next(self) -> SortOrder {
+        let i = ALL.iter().position(|x| *x == self).unwrap();
+        ALL[(i + 1) % ALL.len()]
+    }
+
+    pub fn msg(self) -> Msg {
+        match self {
+            SortOrder::Arrival() => Msg::SortArrival(),
+            SortOrder::Name() => Msg::SortName(),
+            SortOrder::Address() => Msg::SortAddress(),
+            SortOrder::Latency() => Msg::SortLatency(),
+            SortOrder::Bitrate() => Msg::SortBitrate(),
+            SortOrder::Favourites() => Msg::SortFavourites(),
+            SortOrder::LastSeen() => Msg::SortLastSeen(),
+        }
+    }
+}
+
+/// Returns the indices of `stations` in the given order. Ties keep the order of discovery.
+pub fn order(stations: &[Station], proxies: &[ProxyInfo], sort: SortOrder) -> Vec<usize> {
+    let relays = |station: &Station| {
+        proxies
+            .iter()
+            .filter(|x| station.relays.contains(&x.addr))
+            .collect::<Vec<_>>()
+    };
+    let mut indices = (0..stations.len()).collect::<Vec<_>>();
+    let name = |i: &usize| stations[*i].key.clone();
+    match sort {
+        SortOrder::Arrival() => (),
+        SortOrder::Name() => indices.sort_by_key(name),
+        SortOrder::Address() => indices.sort_by_key(|i| stations[*i].relays.iter().min().copied()),
+        SortOrder::Latency() => indices.sort_by_key(|i| {
+            relays(&stations[*i])
+                .iter()
+                .filter_map(|x| x.latency.stats().map(|(_, avg, _)| avg))
+                .min()
+                .unwrap_or(Duration::MAX)
+        }),
+        SortOrder::Bitrate() => indices.sort_by_key(|i| {
+            Reverse(
+                relays(&stations[*i])
+                    .iter()
+                    .filter_map(|x| x.stream.info().map(|info| info.bitrate))
+                    .max(),
+            )
+        }),
+        SortOrder::Favourites() => indices.sort_by_key(|i| (!stations[*i].favourite, name(i))),
+        SortOrder::LastSeen() => indices.sort_by_key(|i| {
+            Reverse(
+                relays(&stations[*i])
+                    .iter()
+                    .map(|x| x.last_contact)
+                    .max()
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            )
+        }),
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn proxy(port: u16, rtt: Option<u64>) -> ProxyInfo {
+        let mut proxy = ProxyInfo::new(SocketAddr::from(([10, 0, 0, 1], port)), "");
+        if let Some(ms) = rtt {
+            proxy.latency.add(Duration::from_millis(ms));
+        }
+        proxy.last_contact = SystemTime::now() - Duration::from_secs(port as u64);
+        proxy
+    }
+
+    fn station(name: &str, port: u16, favourite: bool) -> Station {
+        Station {
+            key: name.to_lowercase(),
+            name: name.to_string(),
+            relays: vec![SocketAddr::from(([10, 0, 0, 1], port))],
+            favourite,
+        }
+    }
+
+    #[test]
+    fn stations_are_sorted() {
+        let stations = vec![
+            station("Trójka", 3, false),
+            station("Dwójka", 2, true),
+            station("Jedynka", 1, false),
+        ];
+        let proxies = vec![proxy(1, None), proxy(2, Some(30)), proxy(3, Some(10))];
+        let cases = [
+            (SortOrder::Arrival(), vec![0, 1, 2]),
+            (SortOrder::Name(), vec![1, 2, 0]),
+            (SortOrder::Address(), vec![2, 1, 0]),
+            (SortOrder::Latency(), vec![0, 1, 2]),
+            (SortOrder::Bitrate(), vec![0, 1, 2]),
+            (SortOrder::Favourites(), vec![1, 2, 0]),
+            (SortOrder::LastSeen(), vec![2, 1, 0]),
+        ];
+        for (sort, expected) in cases.iter() {
+            assert_eq!(order(&stations, &proxies, *sort), *expected, "{:?}", sort);
+        }
+    }
+
+    #[test]
+    fn orders_cycle() {
+        let mut sort = SortOrder::Arrival();
+        for _ in 0..ALL.len() {
+            sort = sort.next();
+        }
+        assert_eq!(sort, SortOrder::Arrival());
+        assert_eq!(
+            SortOrder::parse("last-seen").unwrap(),
+            SortOrder::LastSeen()
+        );
+        assert!(SortOrder::parse("random").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::config::Config;
+use crate::model::ProxyInfo;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+/// Proxies relaying the same station.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Station {
+    /// Identifies the station across updates of the proxy list.
+    pub key: String,
+    pub name: String,
+    /// In the order in which they were discovered.
+    pub relays: Vec<SocketAddr>,
+    pub favourite: bool,
+}
+
+/// Names stations whose relays introduce themselves differently. Configured in the `[stations]`
+/// section, where keys are relay addresses or IAM texts:
+///
+/// ```text
+/// [stations]
+/// 10.0.0.5:16000 = Radio Nowy Świat
+/// RNS 128k = Radio Nowy Świat
+/// ```
+#[derive(Default)]
+pub struct StationMap {
+    /// Set by users at runtime. They take precedence over the configuration.
+    aliases: HashMap<SocketAddr, String>,
+    names: HashMap<String, String>,
+}
+
+impl StationMap {
+    pub fn new(config: &Config) -> StationMap {
+        StationMap {
+            aliases: HashMap::new(),
+            names: config
+                .section("stations")
+                .map(|(key, name)| (normalize(key), name.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Names the station relayed by a proxy, overriding the configuration. An empty name
+    /// removes the alias.
+    pub fn set_alias(&mut self, addr: SocketAddr, name: &str) {
+        if name.trim().is_empty() {
+            self.aliases.remove(&addr);
+        } else {
+            self.aliases.insert(addr, name.trim().to_string());
+        }
+    }
+
+    /// The configured name of the station relayed by a proxy.
+    fn name(&self, proxy: &ProxyInfo) -> Option<&str> {
+        self.aliases
+            .get(&proxy.addr)
+            .or_else(|| self.names.get(&proxy.addr.to_string()))
+            .or_else(|| self.names.get(&normalize(&proxy.info)))
+            .map(|x| x.as_str())
+    }
+}
+
+/// Station names and IAM texts that differ only in case and spacing are the same.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Groups proxies by the station they relay: by the configured name, by their IAM text and, for
+/// proxies that haven't introduced themselves, by what they are playing. `favourites` holds
+/// normalized station names and relay addresses.
+pub fn group(
+    proxies: &[ProxyInfo],
+    map: &StationMap,
+    favourites: &HashSet<String>,
+) -> Vec<Station> {
+    let mut stations: Vec<Station> = vec![];
+    for proxy in proxies {
+        let (key, name) = match map.name(proxy) {
+            Some(name) => (normalize(name), name.to_string()),
+            None if !proxy.info.trim().is_empty() => (normalize(&proxy.info), proxy.info.clone()),
+            None => {
+                let same_meta = proxies.iter().find(|x| {
+                    !x.info.trim().is_empty() && !proxy.meta.is_empty() && x.meta == proxy.meta
+                });
+                match same_meta {
+                    Some(other) => match map.name(other) {
+                        Some(name) => (normalize(name), name.to_string()),
+                        None => (normalize(&other.info), other.info.clone()),
+                    },
+                    None => (proxy.addr.to_string(), proxy.addr.to_string()),
+                }
+            }
+        };
+        match stations.iter_mut().find(|x| x.key == key) {
+            Some(station) => station.relays.push(proxy.addr),
+            None => stations.push(Station {
+                key,
+                name,
+                relays: vec![proxy.addr],
+                favourite: false,
+            }),
+        }
+    }
+    for station in stations.iter_mut() {
+        station.favourite = favourites.contains(&station.key)
+            || station
+                .relays
+                .iter()
+                .any(|x| favourites.contains(&x.to_string()));
+    }
+    stations
+}
+
+/// Whether a proxy has been quiet for too long, is gone or sends dead air.
+pub fn is_silent(proxies: &[ProxyInfo], addr: SocketAddr, stale_after: Duration) -> bool {
+    match proxies.iter().find(|x| x.addr == addr) {
+        Some(proxy) => is_stale(proxy, SystemTime::now(), stale_after),
+        None => true,
+    }
+}
+
+fn is_stale(proxy: &ProxyInfo, now: SystemTime, stale_after: Duration) -> bool {
+    proxy.dead_air.is_dead()
+        || now
+            .duration_since(proxy.last_contact)
+            .is_ok_and(|silence| silence >= stale_after)
+}
+
+/// Picks the relay to play: one that is not silent, loses the fewest packets, has the fewest
+/// corrupted frames and the shortest round-trip time, in that order.
+pub fn healthiest(station: &Station, proxies: &[ProxyInfo], stale_after: Duration) -> SocketAddr {
+    let now = SystemTime::now();
+    station
+        .relays
+        .iter()
+        .filter_map(|addr| proxies.iter().find(|x| x.addr == *addr))
+        .min_by_key(|proxy| {
+            let stale = is_stale(proxy, now, stale_after);
+            let stats = proxy.sequence.stats();
+            let loss = stats.lost * 1000 / (stats.received + stats.lost).max(1);
+            let rtt = proxy
+                .latency
+                .stats()
+                .map_or(Duration::MAX, |(_, avg, _)| avg);
+            (stale, loss, proxy.stream.corrupted(), rtt)
+        })
+        .map_or(station.relays[0], |x| x.addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn proxy(port: u16, info: &str, meta: &str) -> ProxyInfo {
+        let mut proxy = ProxyInfo::new(SocketAddr::from(([10, 0, 0, 1], port)), info);
+        proxy.meta = meta.to_string();
+        proxy
+    }
+
+    #[test]
+    fn relays_of_a_station_are_grouped() {
+        let proxies = vec![
+            proxy(1, "Radio  ZET", "Song"),
+            proxy(2, "Dwójka", ""),
+            proxy(3, "radio zet", ""),
+            proxy(4, "", "Song"),
+            proxy(5, "", ""),
+        ];
+        let stations = group(&proxies, &StationMap::default(), &HashSet::new());
+        let relays = stations
+            .iter()
+            .map(|x| (x.name.as_str(), x.relays.iter().map(|x| x.port()).collect()))
+            .collect::<Vec<(&str, Vec<u16>)>>();
+        assert_eq!(
+            relays,
+            vec![
+                ("Radio  ZET", vec![1, 3, 4]),
+                ("Dwójka", vec![2]),
+                ("10.0.0.1:5", vec![5]),
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_names_join_stations() {
+        let config = Config::parse("[stations]\n10.0.0.1:2 = Jedynka\nPR1 = Jedynka\n").unwrap();
+        let proxies = vec![proxy(1, "pr1", ""), proxy(2, "Polskie Radio", "")];
+        let favourites = ["jedynka".to_string()].iter().cloned().collect();
+        let mut map = StationMap::new(&config);
+        let stations = group(&proxies, &map, &favourites);
+        assert_eq!(stations.len(), 1);
+        assert_eq!(stations[0].name, "Jedynka");
+        assert!(stations[0].favourite);
+        map.set_alias(proxies[1].addr, "PR 1 ");
+        assert_eq!(group(&proxies, &map, &favourites)[1].name, "PR 1");
+        map.set_alias(proxies[1].addr, "");
+        assert_eq!(group(&proxies, &map, &favourites).len(), 1);
+    }
+
+    #[test]
+    fn healthiest_relay_is_picked() {
+        let mut proxies = vec![proxy(1, "a", ""), proxy(2, "a", ""), proxy(3, "a", "")];
+        let stale_after = Duration::from_secs(2);
+        proxies[0].last_contact = SystemTime::now() - Duration::from_secs(3);
+        proxies[1].latency.add(Duration::from_millis(30));
+        proxies[2].latency.add(Duration::from_millis(10));
+        let station = group(&proxies, &StationMap::default(), &HashSet::new()).remove(0);
+        assert_eq!(healthiest(&station, &proxies, stale_after).port(), 3);
+        // Packet loss matters more than latency.
+        let now = Instant::now();
+        proxies[2].sequence.push(0, Arc::from(&[][..]), now);
+        proxies[2].sequence.push(2, Arc::from(&[][..]), now);
+        proxies[2].sequence.flush(now + Duration::from_secs(1));
+        assert_eq!(healthiest(&station, &proxies, stale_after).port(), 2);
+    }
+}
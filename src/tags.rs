@@ -0,0 +1,109 @@
+use crate::model::ProxyInfo;
+use chrono::NaiveDateTime;
+
+/// What is known about a recording when it starts.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tags {
+    /// The name the station introduced itself with.
+    pub station: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub recorded: NaiveDateTime,
+}
+
+impl Tags {
+    /// Takes the artist and the title from a `StreamTitle` such as `Artist - Title`. Titles
+    /// without a separator are taken whole.
+    pub fn new(station: &str, meta: &str, recorded: NaiveDateTime) -> Tags {
+        let meta = meta.trim();
+        let (artist, title) = match meta.split_once(" - ") {
+            Some((artist, title)) => (Some(artist.trim()), Some(title.trim())),
+            None if meta.is_empty() => (None, None),
+            None => (None, Some(meta)),
+        };
+        Tags {
+            station: station.trim().to_string(),
+            artist: artist.map(|x| x.to_string()),
+            title: title.map(|x| x.to_string()),
+            recorded,
+        }
+    }
+
+    /// An ID3v2.4 tag, which players read at the start of MP3 files and ADTS streams alike.
+    pub fn id3v2(&self) -> Vec<u8> {
+        let recorded = self.recorded.format("%Y-%m-%dT%H:%M:%S").to_string();
+        let frames = [
+            ("TIT2", self.title.as_deref()),
+            ("TPE1", self.artist.as_deref()),
+            (
+                "TRSN",
+                Some(self.station.as_str()).filter(|x| !x.is_empty()),
+            ),
+            ("TDRC", Some(recorded.as_str())),
+        ];
+        let mut body = vec![];
+        for (id, text) in frames.iter() {
+            let text = match text {
+                Some(text) => text,
+                None => continue,
+            };
+            body.extend(id.as_bytes());
+            body.extend(synchsafe(text.len() + 1));
+            // No flags, then the encoding: 3 is UTF-8.
+            body.extend([0, 0, 3].iter());
+            body.extend(text.as_bytes());
+        }
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend(synchsafe(body.len()));
+        tag.extend(body);
+        tag
+    }
+}
+
+/// Tags for a recording of a proxy starting now. The station is named as the proxy introduced
+/// itself, or as it's listed if it hasn't.
+pub fn for_proxy(proxy: &ProxyInfo, name: &str, recorded: NaiveDateTime) -> Tags {
+    let station = if proxy.info.trim().is_empty() {
+        name
+    } else {
+        &proxy.info
+    };
+    Tags::new(station, &proxy.meta, recorded)
+}
+
+/// ID3 sizes use 7 bits of each byte, so that they can't look like the start of an MPEG frame.
+fn synchsafe(size: usize) -> [u8; 4] {
+    [21, 14, 7, 0].map(|shift| (size >> shift & 0x7f) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time() -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2024-01-03 20:00", "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn artists_and_titles_are_split() {
+        let tags = Tags::new(" Radio Jazz ", "Miles Davis - So What", time());
+        assert_eq!(tags.station, "Radio Jazz");
+        assert_eq!(tags.artist.as_deref(), Some("Miles Davis"));
+        assert_eq!(tags.title.as_deref(), Some("So What"));
+        let tags = Tags::new("", "News", time());
+        assert_eq!((tags.artist, tags.title.as_deref()), (None, Some("News")));
+        let tags = Tags::new("", " ", time());
+        assert_eq!((tags.artist, tags.title), (None, None));
+    }
+
+    #[test]
+    fn id3_tags_are_encoded() {
+        let tag = Tags::new("", "Kult - Arahja", time()).id3v2();
+        let mut expected = b"ID3\x04\x00\x00\x00\x00\x00\x3e".to_vec();
+        expected.extend(b"TIT2\x00\x00\x00\x07\x00\x00\x03Arahja");
+        expected.extend(b"TPE1\x00\x00\x00\x05\x00\x00\x03Kult");
+        expected.extend(b"TDRC\x00\x00\x00\x14\x00\x00\x032024-01-03T20:00:00");
+        assert_eq!(tag, expected);
+        assert_eq!(synchsafe(300), [0, 0, 2, 44]);
+    }
+}
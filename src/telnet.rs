@@ -1,50 +1,373 @@
-use crate::channels::{CHANNEL_MODEL_S, CHANNEL_TELNET_R};
+use crate::access::AccessPolicy;
+use crate::bus::{Bus, Subscription};
+use crate::config::Config;
 use crate::events::{EventModel, EventTelnet};
-use anyhow::{Context, Result};
-use lazy_static::lazy_static;
+use crate::i18n::{Lang, Msg};
+#[cfg(feature = "async")]
+use crate::runtime;
+use crate::session::SessionId;
+use anyhow::{anyhow, Context, Result};
+use crossbeam::crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashMap;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "async")]
+use tokio::sync::{mpsc, watch};
+#[cfg(feature = "async")]
+use tokio::task::JoinHandle;
 
 const BUFFER_SIZE: usize = 1024;
+/// How many writes may wait for a slow session. Screens are updated incrementally, so a session
+/// that falls this far behind is disconnected rather than sent a garbled screen.
+const SESSION_QUEUE: usize = 256;
+/// How long a session may stall a write before it's disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Terminal types and environments are short, so longer subnegotiations are discarded.
+const MAX_SUBNEGOTIATION: usize = 4096;
 
-lazy_static! {
-    static ref WRITE_HANDLE: Arc<Mutex<Option<TcpStream>>> = Arc::from(Mutex::new(None));
+mod telnet_codes {
+    pub const SE: u8 = 240;
+    pub const SB: u8 = 250;
+    pub const WILL: u8 = 251;
+    pub const IAC: u8 = 255;
+    pub const NEW_ENVIRON: u8 = 39;
+    pub const TERMINAL_TYPE: u8 = 24;
+}
+
+mod terminal_type_codes {
+    pub const IS: u8 = 0;
+    pub const SEND: u8 = 1;
+}
+
+mod environ_codes {
+    pub const IS: u8 = 0;
+    pub const SEND: u8 = 1;
+    pub const INFO: u8 = 2;
+    pub const VAR: u8 = 0;
+    pub const VALUE: u8 = 1;
+    pub const ESC: u8 = 2;
+    pub const USERVAR: u8 = 3;
+}
+
+// Asks the client for the variables that determine the language of the session.
+const ENVIRON_REQUEST: &[&[u8]] = &[
+    &[
+        telnet_codes::IAC,
+        telnet_codes::SB,
+        telnet_codes::NEW_ENVIRON,
+    ],
+    &[environ_codes::SEND, environ_codes::USERVAR],
+    b"LC_ALL",
+    &[environ_codes::USERVAR],
+    b"LC_MESSAGES",
+    &[environ_codes::USERVAR],
+    b"LANG",
+    &[telnet_codes::IAC, telnet_codes::SE],
+];
+
+const TERMINAL_TYPE_REQUEST: &[u8] = &[
+    telnet_codes::IAC,
+    telnet_codes::SB,
+    telnet_codes::TERMINAL_TYPE,
+    terminal_type_codes::SEND,
+    telnet_codes::IAC,
+    telnet_codes::SE,
+];
+
+#[derive(Debug, Eq, PartialEq)]
+enum TelnetInput {
+    Data(Vec<u8>),
+    // A WILL, WONT, DO or DONT command with its option.
+    Command(u8, u8),
+    Subnegotiation(u8, Vec<u8>),
+}
+
+enum ParserState {
+    Data(),
+    Iac(),
+    Command(u8),
+    Subnegotiation(Vec<u8>),
+    SubnegotiationIac(Vec<u8>),
+    // An oversized subnegotiation, skipped until its end.
+    Discard(),
+    DiscardIac(),
+}
+
+/// Separates user input from telnet commands.
+struct TelnetParser {
+    state: ParserState,
+}
+
+impl TelnetParser {
+    fn new() -> TelnetParser {
+        TelnetParser {
+            state: ParserState::Data(),
+        }
+    }
+
+    fn feed(&mut self, input: &[u8]) -> Vec<TelnetInput> {
+        use telnet_codes::*;
+        let mut result = vec![];
+        let mut data = vec![];
+        for byte in input {
+            let state = std::mem::replace(&mut self.state, ParserState::Data());
+            self.state = match (state, *byte) {
+                (ParserState::Data(), IAC) => ParserState::Iac(),
+                (ParserState::Data(), byte) => {
+                    data.push(byte);
+                    ParserState::Data()
+                }
+                (ParserState::Iac(), IAC) => {
+                    data.push(IAC);
+                    ParserState::Data()
+                }
+                (ParserState::Iac(), SB) => ParserState::Subnegotiation(vec![]),
+                (ParserState::Iac(), cmd @ WILL..=254) => ParserState::Command(cmd),
+                (ParserState::Iac(), _) => ParserState::Data(),
+                (ParserState::Command(cmd), option) => {
+                    flush_data(&mut result, &mut data);
+                    result.push(TelnetInput::Command(cmd, option));
+                    ParserState::Data()
+                }
+                (ParserState::Subnegotiation(buf), IAC) => ParserState::SubnegotiationIac(buf),
+                (ParserState::Subnegotiation(buf), _) if buf.len() >= MAX_SUBNEGOTIATION => {
+                    ParserState::Discard()
+                }
+                (ParserState::Subnegotiation(mut buf), byte) => {
+                    buf.push(byte);
+                    ParserState::Subnegotiation(buf)
+                }
+                (ParserState::SubnegotiationIac(buf), SE) => {
+                    if let Some((option, content)) = buf.split_first() {
+                        flush_data(&mut result, &mut data);
+                        result.push(TelnetInput::Subnegotiation(*option, content.to_vec()));
+                    }
+                    ParserState::Data()
+                }
+                (ParserState::SubnegotiationIac(buf), _) if buf.len() >= MAX_SUBNEGOTIATION => {
+                    ParserState::Discard()
+                }
+                (ParserState::SubnegotiationIac(mut buf), byte) => {
+                    buf.push(byte);
+                    ParserState::Subnegotiation(buf)
+                }
+                (ParserState::Discard(), IAC) => ParserState::DiscardIac(),
+                (ParserState::DiscardIac(), SE) => ParserState::Data(),
+                (ParserState::Discard(), _) | (ParserState::DiscardIac(), _) => {
+                    ParserState::Discard()
+                }
+            };
+        }
+        flush_data(&mut result, &mut data);
+        result
+    }
+}
+
+fn flush_data(result: &mut Vec<TelnetInput>, data: &mut Vec<u8>) {
+    if !data.is_empty() {
+        result.push(TelnetInput::Data(std::mem::take(data)));
+    }
+}
+
+/// Parses the variables sent in a NEW-ENVIRON IS or INFO subnegotiation (RFC 1572).
+fn parse_environment(content: &[u8]) -> Vec<(String, String)> {
+    use environ_codes::*;
+    let mut vars: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+    match content.first() {
+        Some(&IS) | Some(&INFO) => (),
+        _ => return vec![],
+    }
+    let mut in_value = false;
+    let mut escaped = false;
+    for byte in &content[1..] {
+        match (escaped, *byte) {
+            (false, ESC) => {
+                escaped = true;
+                continue;
+            }
+            (false, VAR) | (false, USERVAR) => {
+                vars.push((vec![], vec![]));
+                in_value = false;
+            }
+            (false, VALUE) => in_value = true,
+            (_, byte) => match vars.last_mut() {
+                Some((name, _)) if !in_value => name.push(byte),
+                Some((_, value)) => value.push(byte),
+                None => (),
+            },
+        }
+        escaped = false;
+    }
+    vars.into_iter()
+        .map(|(name, value)| {
+            (
+                String::from_utf8_lossy(&name).to_string(),
+                String::from_utf8_lossy(&value).to_string(),
+            )
+        })
+        .collect()
+}
+
+/// A session's stream and the queue of the thread writing to it.
+struct Handle {
+    stream: TcpStream,
+    writes: Sender<EventTelnet>,
+}
+
+type Handles = Arc<Mutex<HashMap<SessionId, Handle>>>;
+
+/// Limits of the telnet server, read from the `[telnet]` section of the config file:
+///
+/// ```text
+/// [telnet]
+/// idle-timeout = 900   # seconds, 0 disables it
+/// idle-warning = 60    # seconds before the disconnect
+/// max-sessions = 8
+/// keepalive = 60       # seconds, 0 disables it
+/// ```
+#[derive(Clone, Debug)]
+pub struct TelnetSettings {
+    pub idle_timeout: Option<Duration>,
+    pub idle_warning: Duration,
+    pub max_sessions: usize,
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for TelnetSettings {
+    fn default() -> TelnetSettings {
+        TelnetSettings {
+            idle_timeout: Some(Duration::from_secs(15 * 60)),
+            idle_warning: Duration::from_secs(60),
+            max_sessions: 8,
+            keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl TelnetSettings {
+    pub fn new(config: &Config) -> Result<TelnetSettings> {
+        let mut settings = TelnetSettings::default();
+        for (key, value) in config.section("telnet") {
+            let number = value
+                .parse::<u64>()
+                .with_context(|| format!("invalid value of {}: {}", key, value))?;
+            let duration = match number {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            };
+            match key {
+                "idle-timeout" => settings.idle_timeout = duration,
+                "idle-warning" => settings.idle_warning = Duration::from_secs(number),
+                "max-sessions" => settings.max_sessions = number as usize,
+                "keepalive" => settings.keepalive = duration,
+                _ => return Err(anyhow!("unknown telnet setting: {}", key)),
+            }
+        }
+        Ok(settings)
+    }
 }
 
 pub struct TelnetServer<'a> {
     host: &'a str,
     port: u16,
-    buffer: [u8; BUFFER_SIZE],
+    access: AccessPolicy,
+    settings: TelnetSettings,
+    lang: Lang,
+    bus: Bus,
+    /// The sessions, which the writer thread passes writes to.
+    handles: Handles,
 }
 
 impl TelnetServer<'_> {
-    pub fn new(host: &str, port: u16) -> TelnetServer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        access: AccessPolicy,
+        settings: TelnetSettings,
+        lang: Lang,
+        bus: Bus,
+    ) -> TelnetServer<'_> {
         TelnetServer {
             host,
             port,
-            buffer: [0; BUFFER_SIZE],
+            access,
+            settings,
+            lang,
+            bus,
+            handles: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn start(&mut self) {
+    /// Serves sessions, writing to them what's published to the bus's telnet topic through
+    /// `writes`.
+    pub fn start(&mut self, writes: Subscription<EventTelnet>) {
         match || -> Result<()> {
             let listener = TcpListener::bind((self.host, self.port)).context("bind failed")?;
+            {
+                let handles = self.handles.clone();
+                thread::spawn(move || start_writer(&handles, writes));
+            }
+            let mut last_id: SessionId = 0;
 
             for result in listener.incoming() {
                 match result {
                     Ok(mut stream) => {
-                        *WRITE_HANDLE.lock().unwrap() = Some(continue_on_err!(
-                            stream.try_clone(),
-                            "failed to clone a TCP stream"
-                        ));
-                        CHANNEL_MODEL_S
-                            .send(EventModel::NewTelnetConnection())
-                            .unwrap();
-                        if let Err(err) = self.handle_client(&mut stream) {
-                            log!("TCP connection dropped: {:?}", err);
+                        let addr =
+                            continue_on_err!(stream.peer_addr(), "failed to get a peer address");
+                        if !self.access.allows(addr.ip()) {
+                            log!("rejected a telnet connection from {}", addr);
+                            continue;
+                        }
+                        if self.handles.lock().unwrap().len() >= self.settings.max_sessions {
+                            log!("rejected a telnet connection from {}: server busy", addr);
+                            let busy = format!("{}\r\n", self.lang.tr(Msg::ServerBusy()));
+                            stream
+                                .write_all(busy.as_bytes())
+                                .unwrap_or_else(|err| log!("telnet write failure: {:?}", err));
+                            continue;
+                        }
+                        if let Some(time) = self.settings.keepalive {
+                            let keepalive = TcpKeepalive::new().with_time(time).with_interval(time);
+                            SockRef::from(&stream)
+                                .set_tcp_keepalive(&keepalive)
+                                .unwrap_or_else(|err| log!("failed to set keepalive: {:?}", err));
                         }
-                        *WRITE_HANDLE.lock().unwrap() = None;
+                        continue_on_err!(
+                            stream.set_write_timeout(Some(WRITE_TIMEOUT)),
+                            "failed to set a write timeout"
+                        );
+                        let handle =
+                            continue_on_err!(stream.try_clone(), "failed to clone a TCP stream");
+                        let writer =
+                            continue_on_err!(stream.try_clone(), "failed to clone a TCP stream");
+                        last_id += 1;
+                        let id = last_id;
+                        let (writes, queue) = bounded(SESSION_QUEUE);
+                        thread::spawn(move || write_session(id, writer, queue));
+                        self.handles.lock().unwrap().insert(
+                            id,
+                            Handle {
+                                stream: handle,
+                                writes,
+                            },
+                        );
+                        let (handles, bus) = (self.handles.clone(), self.bus.clone());
+                        thread::spawn(move || {
+                            bus.model.publish(EventModel::NewTelnetConnection(id));
+                            if let Err(err) = handle_client(id, &mut stream, &bus) {
+                                log!("TCP connection dropped: {:?}", err);
+                            }
+                            handles.lock().unwrap().remove(&id);
+                            bus.model.publish(EventModel::TelnetConnectionClosed(id));
+                        });
                     }
                     Err(err) => log!("failed to unpack a new TCP stream: {:?}", err),
                 }
@@ -53,32 +376,264 @@ impl TelnetServer<'_> {
             Ok(())
         }() {
             Ok(()) => (),
-            Err(e) => CHANNEL_MODEL_S
-                .send(EventModel::TelnetServerCrashed(Arc::from(e.to_string())))
-                .unwrap(),
+            Err(e) => self
+                .bus
+                .model
+                .publish(EventModel::TelnetServerCrashed(Arc::from(e.to_string()))),
         }
     }
+}
 
-    pub fn start_writer() {
-        loop {
-            match CHANNEL_TELNET_R.recv().unwrap() {
-                EventTelnet::Write(data) => match WRITE_HANDLE.lock().unwrap().as_mut() {
-                    Some(handle) => handle
-                        .write_all(data.as_ref())
-                        .unwrap_or_else(|err| log!("telnet write failure: {:?}", err)),
-                    None => log!("tried to write when stream was None"),
-                },
+/// Passes writes and closes to the sessions' queues. It never waits for a session.
+fn start_writer(handles: &Handles, writes: Subscription<EventTelnet>) {
+    while let Ok(event) = writes.recv() {
+        let id = match &event {
+            EventTelnet::Write((id, _)) | EventTelnet::Close(id) => *id,
+        };
+        match handles.lock().unwrap().get(&id) {
+            Some(handle) => match handle.writes.try_send(event) {
+                Err(TrySendError::Full(_)) => {
+                    log!("telnet session {} is too slow, disconnecting it", id);
+                    handle
+                        .stream
+                        .shutdown(Shutdown::Both)
+                        .unwrap_or_else(|err| log!("telnet shutdown failure: {:?}", err));
+                }
+                // The session is being closed.
+                Ok(()) | Err(TrySendError::Disconnected(_)) => (),
+            },
+            None => log!("tried to write to a closed session {}", id),
+        }
+    }
+}
+
+/// Writes what's queued for a session, closing it after the writes that precede a close. A
+/// session that stalls is disconnected, which also ends the thread reading from it.
+fn write_session(id: SessionId, mut stream: TcpStream, queue: Receiver<EventTelnet>) {
+    for event in queue.iter() {
+        if let EventTelnet::Write((_, data)) = event {
+            match stream.write_all(&data) {
+                Ok(()) => continue,
+                Err(err) => log!("telnet write failure for session {}: {:?}", id, err),
+            }
+        }
+        stream
+            .shutdown(Shutdown::Both)
+            .unwrap_or_else(|err| log!("telnet shutdown failure: {:?}", err));
+        return;
+    }
+}
+
+fn handle_client(id: SessionId, stream: &mut TcpStream, bus: &Bus) -> Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut parser = TelnetParser::new();
+    loop {
+        let read_size = stream.read(&mut buffer).context("read failed")?;
+        if read_size == 0 {
+            return Ok(());
+        }
+        dispatch(id, &mut parser, &buffer[0..read_size], bus);
+    }
+}
+
+/// Passes what a session sent to the model and answers telnet negotiation.
+fn dispatch(id: SessionId, parser: &mut TelnetParser, data: &[u8], bus: &Bus) {
+    for input in parser.feed(data) {
+        match input {
+            TelnetInput::Data(data) => bus
+                .model
+                .publish(EventModel::UserInput((id, Arc::from(data)))),
+            TelnetInput::Command(telnet_codes::WILL, telnet_codes::NEW_ENVIRON) => {
+                bus.telnet.publish(EventTelnet::Write((
+                    id,
+                    Arc::from(ENVIRON_REQUEST.concat()),
+                )))
+            }
+            TelnetInput::Command(telnet_codes::WILL, telnet_codes::TERMINAL_TYPE) => bus
+                .telnet
+                .publish(EventTelnet::Write((id, Arc::from(TERMINAL_TYPE_REQUEST)))),
+            TelnetInput::Subnegotiation(telnet_codes::TERMINAL_TYPE, content) => {
+                if let Some((&terminal_type_codes::IS, name)) = content.split_first() {
+                    bus.model.publish(EventModel::TelnetTerminalType((
+                        id,
+                        String::from_utf8_lossy(name).to_string(),
+                    )))
+                }
+            }
+            TelnetInput::Subnegotiation(telnet_codes::NEW_ENVIRON, content) => bus.model.publish(
+                EventModel::TelnetEnvironment((id, parse_environment(&content))),
+            ),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl TelnetServer<'_> {
+    /// Like `start`, but on tokio, with a task for every session. Returns once `shutdown`
+    /// changes.
+    pub fn serve(
+        &self,
+        writes: mpsc::Receiver<EventTelnet>,
+        shutdown: watch::Receiver<bool>,
+    ) -> impl Future<Output = ()> {
+        let addr = (self.host.to_string(), self.port);
+        let busy = format!("{}\r\n", self.lang.tr(Msg::ServerBusy()));
+        let (access, settings) = (self.access.clone(), self.settings.clone());
+        let bus = self.bus.clone();
+        async move {
+            let listener = Listener {
+                access,
+                settings,
+                busy,
+                bus: bus.clone(),
+            };
+            if let Err(e) = listener.serve(addr, writes, shutdown).await {
+                let crashed = EventModel::TelnetServerCrashed(Arc::from(e.to_string()));
+                runtime::publish(&bus.model, crashed);
             }
         }
     }
+}
+
+/// What the tasks of the async server need to accept sessions.
+#[cfg(feature = "async")]
+struct Listener {
+    access: AccessPolicy,
+    settings: TelnetSettings,
+    /// Sent to clients turned away because of `max-sessions`.
+    busy: String,
+    bus: Bus,
+}
 
-    fn handle_client(&mut self, stream: &mut TcpStream) -> Result<()> {
+#[cfg(feature = "async")]
+impl Listener {
+    async fn serve(
+        &self,
+        addr: (String, u16),
+        mut writes: mpsc::Receiver<EventTelnet>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .context("bind failed")?;
+        let sessions: Arc<Mutex<HashMap<SessionId, mpsc::Sender<EventTelnet>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let mut tasks: Vec<JoinHandle<()>> = vec![];
+        let mut last_id: SessionId = 0;
         loop {
-            let read_size = stream.read(&mut self.buffer).context("read failed")?;
-            if read_size == 0 {
-                return Ok(());
+            // Each of these futures is cancellation safe, so nothing is lost when another one
+            // completes first.
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                event = writes.recv() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    let id = match &event {
+                        EventTelnet::Write((id, _)) | EventTelnet::Close(id) => *id,
+                    };
+                    let session = sessions.lock().unwrap().get(&id).cloned();
+                    match (session, &event) {
+                        (Some(session), _) => {
+                            if let Err(mpsc::error::TrySendError::Full(_)) = session.try_send(event) {
+                                // Closing the queue ends the session once it's written what's
+                                // queued, or its write times out.
+                                log!("telnet session {} is too slow, disconnecting it", id);
+                                sessions.lock().unwrap().remove(&id);
+                            }
+                        }
+                        (None, EventTelnet::Write(_)) => {
+                            log!("tried to write to a closed session {}", id)
+                        }
+                        (None, EventTelnet::Close(_)) => (),
+                    }
+                }
+                accepted = listener.accept() => {
+                    let (mut stream, addr) =
+                        continue_on_err!(accepted, "failed to unpack a new TCP stream");
+                    if !self.access.allows(addr.ip()) {
+                        log!("rejected a telnet connection from {}", addr);
+                        continue;
+                    }
+                    let count = sessions.lock().unwrap().len();
+                    if count >= self.settings.max_sessions {
+                        log!("rejected a telnet connection from {}: server busy", addr);
+                        stream
+                            .write_all(self.busy.as_bytes())
+                            .await
+                            .unwrap_or_else(|err| log!("telnet write failure: {:?}", err));
+                        continue;
+                    }
+                    if let Some(time) = self.settings.keepalive {
+                        let keepalive = TcpKeepalive::new().with_time(time).with_interval(time);
+                        SockRef::from(&stream)
+                            .set_tcp_keepalive(&keepalive)
+                            .unwrap_or_else(|err| log!("failed to set keepalive: {:?}", err));
+                    }
+                    last_id += 1;
+                    let id = last_id;
+                    let (sender, receiver) = mpsc::channel(SESSION_QUEUE);
+                    sessions.lock().unwrap().insert(id, sender);
+                    let (sessions, bus) = (sessions.clone(), self.bus.clone());
+                    let shutdown = shutdown.clone();
+                    tasks.retain(|x| !x.is_finished());
+                    tasks.push(tokio::spawn(async move {
+                        runtime::publish(&bus.model, EventModel::NewTelnetConnection(id));
+                        if let Err(err) = handle_session(id, stream, receiver, &bus, shutdown).await {
+                            log!("TCP connection dropped: {:?}", err);
+                        }
+                        sessions.lock().unwrap().remove(&id);
+                        runtime::publish(&bus.model, EventModel::TelnetConnectionClosed(id));
+                    }));
+                }
+            }
+        }
+        // The sessions see the shutdown too, and end on their own.
+        for task in tasks {
+            let _ = task.await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+async fn handle_session(
+    id: SessionId,
+    stream: tokio::net::TcpStream,
+    mut events: mpsc::Receiver<EventTelnet>,
+    bus: &Bus,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut parser = TelnetParser::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => return Ok(()),
+            read = reader.read(&mut buffer) => {
+                let read_size = read.context("read failed")?;
+                if read_size == 0 {
+                    return Ok(());
+                }
+                // Like `runtime::publish`, since dispatching publishes.
+                tokio::task::block_in_place(|| {
+                    dispatch(id, &mut parser, &buffer[0..read_size], bus)
+                });
             }
-            CHANNEL_MODEL_S.send(EventModel::UserInput(Arc::from(&self.buffer[0..read_size])))?;
+            // Writes happen here rather than in a future of the select, so that they can't be
+            // cut short.
+            event = events.recv() => match event {
+                Some(EventTelnet::Write((_, data))) => {
+                    tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(&data))
+                        .await
+                        .context("write timed out")?
+                        .context("write failed")?
+                }
+                // Dropping both halves closes the connection.
+                Some(EventTelnet::Close(_)) | None => return Ok(()),
+            },
         }
     }
 }
@@ -86,91 +641,254 @@ impl TelnetServer<'_> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::channels::{CHANNEL_MODEL_R, CHANNEL_TELNET_S};
-    use rusty_fork::rusty_fork_test;
-    use std::thread;
-    use std::time::Duration;
 
-    static SERVER_HOST: &'static str = "localhost";
+    static SERVER_HOST: &str = "localhost";
     static SERVER_PORT: u16 = 16789;
 
-    rusty_fork_test! {
-        #[test]
-        fn start_sends_crash_event() {
-            thread::spawn(|| TelnetServer::new("invalidhost", 0).start());
-            match CHANNEL_MODEL_R.recv_timeout(Duration::from_secs(5)) {
-                Ok(EventModel::TelnetServerCrashed(_)) => (),
-                Ok(event) => panic!(
-                    "expected the telnet server to crash, but got {:?}",
-                    event
-                ),
-                Err(e) => panic!("expected the telnet server to crash, {:?}", e),
-            }
+    #[test]
+    fn parser_separates_commands_from_data() {
+        let mut parser = TelnetParser::new();
+        let input = [
+            b'a', 255, 251, 39, b'b', 255, 255, 255, 250, 24, 0, 255, 255, 255, 240,
+        ];
+        assert_eq!(
+            parser.feed(&input),
+            vec![
+                TelnetInput::Data(vec![b'a']),
+                TelnetInput::Command(251, 39),
+                TelnetInput::Data(vec![b'b', 255]),
+                TelnetInput::Subnegotiation(24, vec![0, 255]),
+            ]
+        );
+        assert_eq!(parser.feed(&[255]), vec![]);
+        assert_eq!(
+            parser.feed(&[253, 1, b'c']),
+            vec![TelnetInput::Command(253, 1), TelnetInput::Data(vec![b'c'])]
+        );
+    }
+
+    #[test]
+    fn parser_discards_oversized_subnegotiations() {
+        let mut parser = TelnetParser::new();
+        parser.feed(&[255, 250, 24]);
+        for _ in 0..MAX_SUBNEGOTIATION {
+            assert_eq!(parser.feed(&[b'x', 255, 255]), vec![]);
         }
+        assert_eq!(
+            parser.feed(&[255, 240, b'a', 255, 250, 24, 0, 255, 240]),
+            vec![
+                TelnetInput::Data(vec![b'a']),
+                TelnetInput::Subnegotiation(24, vec![0]),
+            ]
+        );
+    }
 
-        #[test]
-        fn handle_client_sends_user_input_event() {
-            const INPUT: &'static [u8] = &[1, 2, 3, 4, 5];
+    #[test]
+    fn parse_environment_reads_variables() {
+        let content = [
+            &[0, 3][..],
+            b"LANG",
+            &[1],
+            b"pl_PL",
+            &[0],
+            b"USER",
+            &[1],
+            b"a",
+            &[2, 1],
+            b"b",
+            &[3],
+            b"EMPTY",
+        ]
+        .concat();
+        assert_eq!(
+            parse_environment(&content),
+            vec![
+                ("LANG".to_string(), "pl_PL".to_string()),
+                ("USER".to_string(), "a\u{1}b".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+            ]
+        );
+    }
 
-            thread::spawn(|| TelnetServer::new(SERVER_HOST, SERVER_PORT + 1).start());
-            for _ in 0..100 {
-                match TcpStream::connect((SERVER_HOST, SERVER_PORT + 1)) {
-                    Ok(mut stream) => {
-                        stream.write_all(&INPUT).unwrap();
-                        break;
-                    }
-                    Err(_) => thread::sleep(Duration::from_millis(5)),
-                }
-            }
+    #[test]
+    fn settings_are_read_from_config() {
+        let config =
+            Config::parse("[telnet]\nidle-timeout = 0\nmax-sessions = 2\nkeepalive = 30\n")
+                .unwrap();
+        let settings = TelnetSettings::new(&config).unwrap();
+        assert_eq!(settings.idle_timeout, None);
+        assert_eq!(settings.max_sessions, 2);
+        assert_eq!(settings.keepalive, Some(Duration::from_secs(30)));
+        assert!(
+            TelnetSettings::new(&Config::parse("[telnet]\nmax-sessions = x\n").unwrap()).is_err()
+        );
+    }
 
-            match CHANNEL_MODEL_R.recv_timeout(Duration::from_secs(1)) {
-                Ok(EventModel::NewTelnetConnection()) => (),
-                _ => panic!("expected a new connection event"),
-            }
-            match CHANNEL_MODEL_R.recv_timeout(Duration::from_secs(1)) {
-                Ok(EventModel::UserInput(recv_input)) => match &recv_input[..] {
-                    INPUT => (),
-                    _ => panic!("wrong user input received: {:?}", recv_input),
-                },
-                _ => panic!("expected a user input event"),
+    /// Starts a server on a port and returns the bus it talks to the model through.
+    fn start_server(port: u16, settings: TelnetSettings) -> (Bus, Subscription<EventModel>) {
+        let bus = Bus::default();
+        let (events, writes) = (bus.model.subscribe(), bus.telnet.subscribe());
+        let shared = bus.clone();
+        thread::spawn(move || {
+            TelnetServer::new(
+                SERVER_HOST,
+                port,
+                AccessPolicy::default(),
+                settings,
+                Lang::En(),
+                shared,
+            )
+            .start(writes)
+        });
+        (bus, events)
+    }
+
+    fn connect(port: u16) -> TcpStream {
+        for _ in 0..100 {
+            match TcpStream::connect((SERVER_HOST, port)) {
+                Ok(stream) => return stream,
+                Err(_) => thread::sleep(Duration::from_millis(5)),
             }
         }
+        panic!("failed to connect to tcp stream");
+    }
 
-        #[test]
-        fn telnet_writer_reacts_to_events() {
-            const INPUT: &'static [u8] = &[6, 7, 8, 9, 10];
+    fn expect_connection(events: &Subscription<EventModel>) -> SessionId {
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(EventModel::NewTelnetConnection(id)) => id,
+            _ => panic!("expected a new connection event"),
+        }
+    }
 
-            *WRITE_HANDLE.lock().unwrap() = None;
+    #[test]
+    fn busy_server_rejects_connections() {
+        let settings = TelnetSettings {
+            max_sessions: 0,
+            ..TelnetSettings::default()
+        };
+        let _server = start_server(SERVER_PORT + 3, settings);
+        let mut response = String::new();
+        connect(SERVER_PORT + 3)
+            .read_to_string(&mut response)
+            .unwrap();
+        assert_eq!(
+            response,
+            format!("{}\r\n", Lang::En().tr(Msg::ServerBusy()))
+        );
+    }
 
-            thread::spawn(|| TelnetServer::new(SERVER_HOST, SERVER_PORT + 2).start());
-            thread::spawn(|| TelnetServer::start_writer());
+    #[test]
+    fn start_sends_crash_event() {
+        let bus = Bus::default();
+        let (events, writes) = (bus.model.subscribe(), bus.telnet.subscribe());
+        thread::spawn(|| {
+            TelnetServer::new(
+                "invalidhost",
+                0,
+                AccessPolicy::default(),
+                TelnetSettings::default(),
+                Lang::En(),
+                bus,
+            )
+            .start(writes)
+        });
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(EventModel::TelnetServerCrashed(_)) => (),
+            Ok(event) => panic!("expected the telnet server to crash, but got {:?}", event),
+            Err(e) => panic!("expected the telnet server to crash, {:?}", e),
+        }
+    }
 
-            for _ in 0..100 {
-                match TcpStream::connect((SERVER_HOST, SERVER_PORT + 2)) {
-                    Ok(mut stream) => {
-                        let mut buf: [u8; INPUT.len()] = [0; INPUT.len()];
-                        let range = 1000;
-                        for i in 0..range {
-                            if let Some(_) = WRITE_HANDLE.lock().unwrap().as_ref() {
-                                CHANNEL_TELNET_S.send(EventTelnet::Write(Arc::from(INPUT))).unwrap();
-                                break;
-                            } else {
-                                thread::sleep(Duration::from_millis(5));
-                            }
-                            if i + 1 == range {
-                                panic!("could not obtain a write handle");
-                            }
-                        }
-                        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
-                        stream.read_exact(&mut buf).unwrap();
-                        assert_eq!(INPUT, &buf[..]);
-                        return ();
-                    }
-                    Err(_) => thread::sleep(Duration::from_millis(5)),
-                }
-            }
+    #[test]
+    fn handle_client_sends_user_input_event() {
+        const INPUT: &[u8] = &[1, 2, 3, 4, 5];
+
+        let (_bus, events) = start_server(SERVER_PORT + 1, TelnetSettings::default());
+        connect(SERVER_PORT + 1).write_all(INPUT).unwrap();
+        expect_connection(&events);
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(EventModel::UserInput((_, recv_input))) => match &recv_input[..] {
+                INPUT => (),
+                _ => panic!("wrong user input received: {:?}", recv_input),
+            },
+            _ => panic!("expected a user input event"),
+        }
+    }
+
+    #[test]
+    fn telnet_writer_reacts_to_events() {
+        const INPUT: &[u8] = &[6, 7, 8, 9, 10];
+
+        let (bus, events) = start_server(SERVER_PORT + 2, TelnetSettings::default());
+        let mut stream = connect(SERVER_PORT + 2);
+        let id = expect_connection(&events);
+        bus.telnet
+            .publish(EventTelnet::Write((id, Arc::from(INPUT))));
+        let mut buf: [u8; INPUT.len()] = [0; INPUT.len()];
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(INPUT, &buf[..]);
+    }
+
+    #[test]
+    fn telnet_writer_closes_sessions_after_pending_writes() {
+        const INPUT: &[u8] = &[11, 12, 13];
+
+        let (bus, events) = start_server(SERVER_PORT + 5, TelnetSettings::default());
+        let mut stream = connect(SERVER_PORT + 5);
+        let id = expect_connection(&events);
+        bus.telnet
+            .publish(EventTelnet::Write((id, Arc::from(INPUT))));
+        bus.telnet.publish(EventTelnet::Close(id));
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(INPUT, &buf[..]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_server_serves_sessions_until_stopped() {
+        use crate::runtime::Runtime;
+        const INPUT: &[u8] = &[1, 2, 3];
+
+        let runtime = Runtime::new().unwrap();
+        let bus = Bus::default();
+        let events = bus.model.subscribe();
+        let server = TelnetServer::new(
+            SERVER_HOST,
+            SERVER_PORT + 4,
+            AccessPolicy::default(),
+            TelnetSettings::default(),
+            Lang::En(),
+            bus.clone(),
+        );
+        runtime.spawn(server.serve(runtime.forward(bus.telnet.subscribe()), runtime.shutdown()));
+        let mut stream = connect(SERVER_PORT + 4);
+        let id = expect_connection(&events);
+        stream.write_all(INPUT).unwrap();
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(EventModel::UserInput((_, input))) => assert_eq!(&input[..], INPUT),
+            result => panic!("expected a user input event but got {:?}", result),
+        }
+        bus.telnet
+            .publish(EventTelnet::Write((id, Arc::from(INPUT))));
+        let mut buf = [0; INPUT.len()];
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(INPUT, &buf[..]);
 
-            panic!("failed to connect to tcp stream");
+        runtime.stop();
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(EventModel::TelnetConnectionClosed(closed)) => assert_eq!(closed, id),
+            result => panic!("expected the session to close but got {:?}", result),
         }
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
     }
 }
@@ -0,0 +1,113 @@
+//! A minimal radio proxy speaking the client's UDP protocol, including the authenticated
+//! extension, so that the client can be tested end to end.
+
+use crate::proxy::{message_codes, prepare_msg, prepare_msgs, split_msg};
+use crate::secure::{iam_mac, random_nonce, Channel, Mode, SessionNonce, OFFER_MAGIC};
+use anyhow::{anyhow, Result};
+use hmac::Mac;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+pub struct TestProxy {
+    socket: UdpSocket,
+    name: String,
+    key: Option<Vec<u8>>,
+    clients: HashMap<SocketAddr, Channel>,
+    fragmented_id: u32,
+}
+
+impl TestProxy {
+    /// Without a key the proxy answers every DISCOVER with a plain IAM.
+    pub fn bind<A: ToSocketAddrs>(addr: A, name: &str, key: Option<&str>) -> Result<TestProxy> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(TestProxy {
+            socket,
+            name: name.to_string(),
+            key: key.map(|x| x.as_bytes().to_vec()),
+            clients: HashMap::new(),
+            fragmented_id: 0,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Receives one datagram, answering a DISCOVER with an IAM and a PING with a PONG. Returns
+    /// the sender and the code of the (unwrapped) message.
+    pub fn serve_one(&mut self) -> Result<(SocketAddr, u16)> {
+        let mut buf = [0; 65535];
+        let (size, src) = self.socket.recv_from(&mut buf)?;
+        let (code, content) = split_msg(&buf[..size])?;
+        match code {
+            message_codes::DISCOVER => {
+                self.clients.remove(&src);
+                let reply = match (&self.key, parse_offer(content)) {
+                    (Some(key), Some((modes, client_nonce))) => {
+                        let mode = *modes.first().unwrap();
+                        let proxy_nonce = random_nonce()?;
+                        let tag =
+                            iam_mac(key, &client_nonce, mode, &proxy_nonce, self.name.as_bytes());
+                        let mut content = vec![mode.code()];
+                        content.extend_from_slice(&proxy_nonce);
+                        content.extend_from_slice(&tag.finalize().into_bytes());
+                        content.extend_from_slice(self.name.as_bytes());
+                        let channel = Channel::for_proxy(key, mode, &client_nonce, &proxy_nonce);
+                        self.clients.insert(src, channel);
+                        prepare_msg(message_codes::SECURE_IAM, &content)?
+                    }
+                    _ => prepare_msg(message_codes::IAM, self.name.as_bytes())?,
+                };
+                self.socket.send_to(&reply, src)?;
+                Ok((src, code))
+            }
+            message_codes::SECURED => match self.clients.get_mut(&src) {
+                Some(channel) => Ok((src, split_msg(&channel.open(content)?)?.0)),
+                None => Err(anyhow!("secured message from an unknown client")),
+            },
+            _ if self.clients.contains_key(&src) => Err(anyhow!("plain message from {}", src)),
+            message_codes::PING => {
+                self.send(src, message_codes::PONG, content)?;
+                Ok((src, code))
+            }
+            _ => Ok((src, code)),
+        }
+    }
+
+    /// Sends a message, fragmented if it's long and secured if the client negotiated the
+    /// extension.
+    pub fn send(&mut self, client: SocketAddr, code: u16, content: &[u8]) -> Result<()> {
+        for msg in prepare_msgs(code, content, &mut self.fragmented_id)? {
+            let msg = match self.clients.get_mut(&client) {
+                Some(channel) => prepare_msg(message_codes::SECURED, &channel.seal(&msg))?,
+                None => msg,
+            };
+            self.socket.send_to(&msg, client)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a plain message even to clients that negotiated the extension.
+    pub fn send_plain(&self, client: SocketAddr, code: u16, content: &[u8]) -> Result<()> {
+        self.socket.send_to(&prepare_msg(code, content)?, client)?;
+        Ok(())
+    }
+}
+
+/// The modes offered in a DISCOVER, strongest first, and the client's nonce.
+fn parse_offer(content: &[u8]) -> Option<(Vec<Mode>, SessionNonce)> {
+    let rest = content.strip_prefix(OFFER_MAGIC)?;
+    let (modes, nonce) = rest.split_first()?;
+    let modes = [Mode::Aead(), Mode::Hmac()]
+        .iter()
+        .copied()
+        .filter(|x| modes & 1 << x.code() != 0)
+        .collect::<Vec<_>>();
+    if modes.is_empty() {
+        return None;
+    }
+    Some((modes, nonce.try_into().ok()?))
+}
@@ -0,0 +1,143 @@
+use crate::config::Config;
+use anyhow::{anyhow, Result};
+
+/// How the telnet interface is styled. Each style is a list of ANSI SGR parameters, e.g. `1;32`
+/// for bold green. The monochrome theme uses no escape sequences at all and marks the cursor and
+/// the active station with text instead.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Theme {
+    pub active: String,
+    pub cursor: String,
+    pub header: String,
+    pub highlight: String,
+    pub monochrome: bool,
+    pub notice: String,
+    pub stale: String,
+}
+
+impl Theme {
+    pub fn named(name: &str) -> Result<Theme> {
+        let theme = match name {
+            "default" => Theme {
+                active: "1;32".to_string(),
+                cursor: "7".to_string(),
+                header: "1;37;44".to_string(),
+                highlight: "4;33".to_string(),
+                monochrome: false,
+                notice: "1;31".to_string(),
+                stale: "2".to_string(),
+            },
+            "light" => Theme {
+                active: "1;34".to_string(),
+                cursor: "7".to_string(),
+                header: "30;47".to_string(),
+                highlight: "4;35".to_string(),
+                monochrome: false,
+                notice: "31".to_string(),
+                stale: "2".to_string(),
+            },
+            "mono" => Theme::monochrome(),
+            _ => return Err(anyhow!("unknown theme: {}", name)),
+        };
+        Ok(theme)
+    }
+
+    pub fn monochrome() -> Theme {
+        Theme {
+            active: "".to_string(),
+            cursor: "".to_string(),
+            header: "".to_string(),
+            highlight: "".to_string(),
+            monochrome: true,
+            notice: "".to_string(),
+            stale: "".to_string(),
+        }
+    }
+
+    /// Reads `theme` from the `[ui]` section and applies overrides from the `[theme]` section,
+    /// such as `active = 1;35`.
+    pub fn new(config: &Config) -> Result<Theme> {
+        let mut theme = Theme::named(config.get("ui", "theme").unwrap_or("default"))?;
+        for (key, value) in config.section("theme") {
+            if !value.chars().all(|x| x.is_ascii_digit() || x == ';') {
+                return Err(anyhow!("invalid SGR parameters for {}: {}", key, value));
+            }
+            let style = match key {
+                "active" => &mut theme.active,
+                "cursor" => &mut theme.cursor,
+                "header" => &mut theme.header,
+                "highlight" => &mut theme.highlight,
+                "notice" => &mut theme.notice,
+                "stale" => &mut theme.stale,
+                _ => return Err(anyhow!("unknown theme element: {}", key)),
+            };
+            *style = value.to_string();
+        }
+        Ok(theme)
+    }
+
+    /// Terminals that report one of these types can't display escape sequences.
+    pub fn is_dumb_terminal(terminal_type: &str) -> bool {
+        let terminal_type = terminal_type.to_lowercase();
+        ["dumb", "unknown", "network", "tty33", "glasstty"]
+            .iter()
+            .any(|x| terminal_type == *x)
+    }
+
+    /// Combines several styles, skipping empty ones.
+    pub fn combine(styles: &[&str]) -> String {
+        styles
+            .iter()
+            .filter(|x| !x.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Starts a run of text in `style`, discarding any previous style.
+    pub fn set(&self, style: &str) -> String {
+        if self.monochrome {
+            "".to_string()
+        } else {
+            format!("\x1b[0;{}m", style)
+        }
+    }
+
+    pub fn paint(&self, style: &str, text: &str) -> String {
+        if self.monochrome || style.is_empty() {
+            text.to_string()
+        } else {
+            format!("{}{}\x1b[0m", self.set(style), text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_selects_and_overrides_themes() {
+        let config = Config::parse("[ui]\ntheme = light\n[theme]\nactive = 1;35\n").unwrap();
+        let theme = Theme::new(&config).unwrap();
+        assert_eq!(theme.active, "1;35");
+        assert_eq!(theme.header, Theme::named("light").unwrap().header);
+        assert!(Theme::new(&Config::parse("[theme]\nactive = red\n").unwrap()).is_err());
+        assert!(Theme::new(&Config::parse("[ui]\ntheme = neon\n").unwrap()).is_err());
+    }
+
+    #[test]
+    fn monochrome_theme_prints_plain_text() {
+        let theme = Theme::monochrome();
+        assert_eq!(theme.paint("1", "text"), "text");
+        let theme = Theme::named("default").unwrap();
+        assert_eq!(theme.paint("1", "text"), "\x1b[0;1mtext\x1b[0m");
+        assert_eq!(Theme::combine(&["7", "", "2"]), "7;2");
+    }
+
+    #[test]
+    fn dumb_terminals_are_detected() {
+        assert!(Theme::is_dumb_terminal("DUMB"));
+        assert!(!Theme::is_dumb_terminal("xterm-256color"));
+    }
+}
@@ -0,0 +1,316 @@
+use anyhow::{Context, Result};
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The disk buffer is compacted once at least this many bytes at its front are no longer used.
+const COMPACTION_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+enum Payload {
+    Memory(Arc<[u8]>),
+    Disk { offset: u64, len: usize },
+}
+
+struct Chunk {
+    received: Instant,
+    payload: Payload,
+}
+
+struct DiskStorage {
+    file: File,
+    path: PathBuf,
+    write_offset: u64,
+}
+
+impl Drop for DiskStorage {
+    fn drop(&mut self) {
+        if let Err(err) = remove_file(&self.path) {
+            log!("could not remove timeshift file {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+/// Keeps the last `window` of audio received from the active station, so that playback can be
+/// paused, rewound and brought back to the live stream. Chunks are numbered consecutively;
+/// `playhead` is the number of the next chunk to be played.
+pub struct Timeshift {
+    chunks: VecDeque<Chunk>,
+    disk: Option<DiskStorage>,
+    first_chunk: u64,
+    paused: bool,
+    playhead: u64,
+    window: Duration,
+}
+
+impl Timeshift {
+    pub fn new(window: Duration, dir: Option<&Path>) -> Result<Timeshift> {
+        let disk = match dir {
+            Some(dir) => {
+                let path = dir.join(format!("skclient-timeshift-{}.bin", std::process::id()));
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)
+                    .with_context(|| format!("could not create timeshift file {:?}", path))?;
+                Some(DiskStorage {
+                    file,
+                    path,
+                    write_offset: 0,
+                })
+            }
+            None => None,
+        };
+        Ok(Timeshift {
+            chunks: VecDeque::new(),
+            disk,
+            first_chunk: 0,
+            paused: false,
+            playhead: 0,
+            window,
+        })
+    }
+
+    fn live_chunk(&self) -> u64 {
+        self.first_chunk + self.chunks.len() as u64
+    }
+
+    pub fn is_live(&self) -> bool {
+        !self.paused && self.playhead == self.live_chunk()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// How much audio is kept.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// How far behind the live stream the playback is.
+    pub fn delay(&self, now: Instant) -> Duration {
+        match self.chunks.get((self.playhead - self.first_chunk) as usize) {
+            Some(chunk) => now.duration_since(chunk.received),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Stores a new chunk of audio and returns the audio that should be played right now.
+    pub fn push(&mut self, data: Arc<[u8]>, now: Instant) -> Result<Option<Arc<[u8]>>> {
+        let payload = match self.disk.as_mut() {
+            Some(disk) => {
+                disk.file.seek(SeekFrom::Start(disk.write_offset))?;
+                disk.file.write_all(&data)?;
+                let offset = disk.write_offset;
+                disk.write_offset += data.len() as u64;
+                Payload::Disk {
+                    offset,
+                    len: data.len(),
+                }
+            }
+            None => Payload::Memory(data),
+        };
+        self.chunks.push_back(Chunk {
+            received: now,
+            payload,
+        });
+        self.trim(now)?;
+        if self.paused {
+            return Ok(None);
+        }
+        let played = self.read((self.playhead - self.first_chunk) as usize)?;
+        self.playhead += 1;
+        Ok(Some(played))
+    }
+
+    fn read(&mut self, index: usize) -> Result<Arc<[u8]>> {
+        match (&self.chunks[index].payload, self.disk.as_mut()) {
+            (Payload::Memory(data), _) => Ok(data.clone()),
+            (Payload::Disk { offset, len }, Some(disk)) => {
+                let mut buf = vec![0; *len];
+                disk.file.seek(SeekFrom::Start(*offset))?;
+                disk.file.read_exact(&mut buf)?;
+                Ok(Arc::from(buf))
+            }
+            (Payload::Disk { .. }, None) => unreachable!("disk chunk without disk storage"),
+        }
+    }
+
+    fn trim(&mut self, now: Instant) -> Result<()> {
+        while let Some(chunk) = self.chunks.front() {
+            if now.duration_since(chunk.received) <= self.window {
+                break;
+            }
+            self.chunks.pop_front();
+            self.first_chunk += 1;
+        }
+        if self.playhead < self.first_chunk {
+            self.playhead = self.first_chunk;
+        }
+        self.compact()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let disk = match self.disk.as_mut() {
+            Some(disk) => disk,
+            None => return Ok(()),
+        };
+        let start = match self.chunks.front().map(|x| &x.payload) {
+            Some(Payload::Disk { offset, .. }) => *offset,
+            _ => disk.write_offset,
+        };
+        if start < COMPACTION_THRESHOLD || start < disk.write_offset / 2 {
+            return Ok(());
+        }
+        let mut buf = vec![0; (disk.write_offset - start) as usize];
+        disk.file.seek(SeekFrom::Start(start))?;
+        disk.file.read_exact(&mut buf)?;
+        disk.file.seek(SeekFrom::Start(0))?;
+        disk.file.write_all(&buf)?;
+        disk.file.set_len(buf.len() as u64)?;
+        disk.write_offset = buf.len() as u64;
+        for chunk in self.chunks.iter_mut() {
+            if let Payload::Disk { offset, .. } = &mut chunk.payload {
+                *offset -= start;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Moves the playhead back by `by`, but not further than the oldest buffered chunk.
+    pub fn rewind(&mut self, by: Duration, now: Instant) {
+        let target = self
+            .delay(now)
+            .checked_add(by)
+            .and_then(|x| now.checked_sub(x));
+        let skipped = match target {
+            Some(target) => self
+                .chunks
+                .iter()
+                .take_while(|x| x.received < target)
+                .count() as u64,
+            None => 0,
+        };
+        self.playhead = min(self.playhead, self.first_chunk + skipped);
+    }
+
+    pub fn skip_to_live(&mut self) {
+        self.paused = false;
+        self.playhead = self.live_chunk();
+    }
+
+    /// Drops the buffered audio, e.g. when the active station changes.
+    pub fn reset(&mut self) -> Result<()> {
+        self.first_chunk = self.live_chunk();
+        self.chunks.clear();
+        self.paused = false;
+        self.playhead = self.first_chunk;
+        if let Some(disk) = self.disk.as_mut() {
+            disk.file.set_len(0)?;
+            disk.write_offset = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(val: u8) -> Arc<[u8]> {
+        Arc::from(vec![val; 4])
+    }
+
+    fn fill(timeshift: &mut Timeshift, start: Instant, count: u8) -> Vec<Option<Arc<[u8]>>> {
+        (0..count)
+            .map(|i| {
+                timeshift
+                    .push(chunk(i), start + Duration::from_secs(i as u64))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn live_playback_passes_audio_through() {
+        let mut timeshift = Timeshift::new(Duration::from_secs(60), None).unwrap();
+        let played = fill(&mut timeshift, Instant::now(), 3);
+        assert_eq!(played, vec![Some(chunk(0)), Some(chunk(1)), Some(chunk(2))]);
+        assert!(timeshift.is_live());
+    }
+
+    #[test]
+    fn pause_holds_audio_until_resume() {
+        let start = Instant::now();
+        let mut timeshift = Timeshift::new(Duration::from_secs(60), None).unwrap();
+        fill(&mut timeshift, start, 2);
+        timeshift.pause();
+        let now = start + Duration::from_secs(2);
+        assert_eq!(timeshift.push(chunk(2), now).unwrap(), None);
+        assert_eq!(timeshift.delay(now), Duration::from_secs(0));
+        timeshift.resume();
+        let now = start + Duration::from_secs(3);
+        assert_eq!(timeshift.push(chunk(3), now).unwrap(), Some(chunk(2)));
+        assert_eq!(timeshift.delay(now), Duration::from_secs(0));
+        assert!(!timeshift.is_live());
+        timeshift.skip_to_live();
+        assert!(timeshift.is_live());
+        let now = start + Duration::from_secs(4);
+        assert_eq!(timeshift.push(chunk(4), now).unwrap(), Some(chunk(4)));
+    }
+
+    #[test]
+    fn rewind_replays_buffered_audio() {
+        let start = Instant::now();
+        let mut timeshift = Timeshift::new(Duration::from_secs(60), None).unwrap();
+        fill(&mut timeshift, start, 5);
+        let now = start + Duration::from_secs(5);
+        timeshift.rewind(Duration::from_secs(3), now);
+        assert_eq!(timeshift.delay(now), Duration::from_secs(3));
+        assert_eq!(timeshift.push(chunk(5), now).unwrap(), Some(chunk(2)));
+        timeshift.rewind(Duration::from_secs(100), now);
+        assert_eq!(timeshift.push(chunk(6), now).unwrap(), Some(chunk(0)));
+        // Playback is behind live now, and huge rewinds stop at the oldest chunk.
+        timeshift.rewind(Duration::MAX, now);
+        assert_eq!(timeshift.push(chunk(7), now).unwrap(), Some(chunk(0)));
+    }
+
+    #[test]
+    fn old_audio_is_dropped() {
+        let start = Instant::now();
+        let mut timeshift = Timeshift::new(Duration::from_secs(2), None).unwrap();
+        timeshift.pause();
+        fill(&mut timeshift, start, 5);
+        timeshift.resume();
+        let now = start + Duration::from_secs(5);
+        assert_eq!(timeshift.push(chunk(5), now).unwrap(), Some(chunk(3)));
+    }
+
+    #[test]
+    fn disk_storage_returns_stored_audio() {
+        let start = Instant::now();
+        let dir = std::env::temp_dir();
+        let mut timeshift = Timeshift::new(Duration::from_secs(60), Some(&dir)).unwrap();
+        timeshift.pause();
+        fill(&mut timeshift, start, 3);
+        timeshift.resume();
+        let now = start + Duration::from_secs(3);
+        assert_eq!(timeshift.push(chunk(3), now).unwrap(), Some(chunk(0)));
+        timeshift.reset().unwrap();
+        assert_eq!(timeshift.push(chunk(4), now).unwrap(), Some(chunk(4)));
+    }
+}
@@ -1,83 +1,594 @@
-use crate::channels::CHANNEL_TELNET_S;
+use crate::bus::Topic;
 use crate::events::EventTelnet;
+use crate::i18n::{Lang, Msg};
 use crate::model::ProxyInfo;
+use crate::recorder::Recorder;
+use crate::schedule::{Job, JobState};
+use crate::session::{find_matches, Session, SessionId};
+use crate::sort::{self, SortOrder};
+use crate::stations::{healthiest, Station};
+use crate::theme::Theme;
+use crate::timeshift::Timeshift;
+use chrono::Local;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How far back a single rewind moves the playback.
+pub const REWIND_STEP: Duration = Duration::from_secs(10);
+
+// The header is padded to this many characters, so that its background spans the usual terminal.
+const HEADER_WIDTH: usize = 80;
 
 mod telnet_sequence {
-    pub const CLEAR_SCREEN: &[u8] = &[27, 91, 72, 27, 91, 50, 74];
     pub const SCREEN_OPTIONS: &[u8] = &[
         255, 253, 34, // do linemode
         255, 250, 34, 1, 0, 255, 240, // linemode options
         255, 251, 1, // will echo
+        255, 253, 39, // do new-environ
+        255, 253, 24, // do terminal-type
     ];
 }
 
-pub enum UserInput {
-    Up(),
-    Down(),
-    Select(),
-    Unrecognized(),
+pub fn prepare_screen(telnet: &Topic<EventTelnet>, id: SessionId) {
+    telnet.publish(EventTelnet::Write((
+        id,
+        Arc::from(telnet_sequence::SCREEN_OPTIONS),
+    )));
 }
 
-pub fn prepare_screen() {
-    CHANNEL_TELNET_S
-        .send(EventTelnet::Write(Arc::from(
-            telnet_sequence::SCREEN_OPTIONS,
-        )))
-        .unwrap();
+/// A selectable row of the menu.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MenuItem {
+    Discover(),
+    /// A station, by its index in the list of stations.
+    Station(usize),
+    /// A single relay of an expanded station.
+    Proxy(SocketAddr),
+    Pause(),
+    Rewind(),
+    SkipToLive(),
+    Quit(),
 }
 
-pub fn generate_ui(
-    proxies: &Vec<ProxyInfo>,
+/// A selectable row of the detail screen of a proxy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DetailItem {
+    Play(),
+    Record(),
+    Favourite(),
+    Alias(),
+    Forget(),
+    Back(),
+}
+
+pub const DETAIL_ITEMS: [DetailItem; 6] = [
+    DetailItem::Play(),
+    DetailItem::Record(),
+    DetailItem::Favourite(),
+    DetailItem::Alias(),
+    DetailItem::Forget(),
+    DetailItem::Back(),
+];
+
+pub fn menu(
+    proxies: &[ProxyInfo],
+    stations: &[Station],
     active_proxy: &Option<SocketAddr>,
-    cursor_line: i64,
+    session: &Session,
+) -> Vec<MenuItem> {
+    let mut items = vec![MenuItem::Discover()];
+    for index in sort::order(stations, proxies, session.sort) {
+        let station = &stations[index];
+        let matches = relays(station, proxies).any(|x| session.matches(x));
+        if !matches {
+            continue;
+        }
+        items.push(MenuItem::Station(index));
+        if station.relays.len() > 1 && session.expanded.contains(&station.key) {
+            items.extend(station.relays.iter().map(|x| MenuItem::Proxy(*x)));
+        }
+    }
+    // Only the main output has a timeshift buffer.
+    if active_proxy.is_some() && session.output.is_none() {
+        items.extend(&[
+            MenuItem::Pause(),
+            MenuItem::Rewind(),
+            MenuItem::SkipToLive(),
+        ]);
+    }
+    items.push(MenuItem::Quit());
+    items
+}
+
+/// Identifies a menu item across changes of the menu.
+pub fn item_key(item: &MenuItem, stations: &[Station]) -> String {
+    match item {
+        MenuItem::Station(index) => format!("station {}", stations[*index].key),
+        MenuItem::Proxy(addr) => format!("relay {}", addr),
+        _ => format!("{:?}", item),
+    }
+}
+
+fn relays<'a>(
+    station: &'a Station,
+    proxies: &'a [ProxyInfo],
+) -> impl Iterator<Item = &'a ProxyInfo> {
+    proxies
+        .iter()
+        .filter(move |x| station.relays.contains(&x.addr))
+}
+
+fn is_stale(proxy: &ProxyInfo, stale_after: Duration) -> bool {
+    SystemTime::now()
+        .duration_since(proxy.last_contact)
+        .is_ok_and(|silence| silence >= stale_after)
+}
+
+fn format_delay(delay: Duration) -> String {
+    let secs = delay.as_secs();
+    format!("-{}:{:02}", secs / 60, secs % 60)
+}
+
+fn format_ms(duration: Duration) -> String {
+    let ms = duration.as_secs_f64() * 1000.0;
+    if ms < 10.0 {
+        format!("{:.1}", ms)
+    } else {
+        format!("{:.0}", ms)
+    }
+}
+
+fn highlight(text: &str, query: &str, theme: &Theme, base_style: &str) -> String {
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, end) in find_matches(text, query) {
+        result.push_str(&text[last..start]);
+        if theme.monochrome {
+            result.push_str(&format!("[{}]", &text[start..end]));
+        } else {
+            result.push_str(&theme.set(&Theme::combine(&[base_style, &theme.highlight])));
+            result.push_str(&text[start..end]);
+            result.push_str(&theme.set(base_style));
+        }
+        last = end;
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+/// `proxy` is the relay that is playing or would be played.
+fn station_row(
+    session: &Session,
+    number: usize,
+    station: &Station,
+    proxy: &ProxyInfo,
+    active: bool,
+    recording: bool,
+    style: &str,
 ) -> String {
-    let mut rows: Vec<String> = vec![];
-    rows.push("Szukaj pośrednika".to_string());
-    for proxy in proxies {
-        rows.push(format!(
-            "Pośrednik {}{}",
-            proxy.info,
-            match active_proxy {
-                Some(addr) if *addr == proxy.addr => " *",
-                _ => "",
-            }
-        ))
-    }
-    rows.push("Koniec".to_string());
-    rows.push(
-        match proxies.iter().find(|x| Some(x.addr) == *active_proxy) {
-            Some(proxy) => proxy.meta.clone(),
-            None => "".to_string(),
-        },
+    let (lang, theme, query) = (session.lang, &session.theme, &session.filter);
+    let mut row = format!(
+        "{}. {} {}",
+        number,
+        lang.tr(Msg::Proxy()),
+        highlight(&station.name, query, theme, style)
     );
-    rows[cursor_line as usize].push_str(" <-");
+    if find_matches(&station.name, query).is_empty() && !find_matches(&proxy.meta, query).is_empty()
+    {
+        row.push_str(&format!(
+            " ({})",
+            highlight(&proxy.meta, query, theme, style)
+        ));
+    }
+    if station.favourite {
+        row.push_str(favourite_mark(theme));
+    }
+    if station.relays.len() > 1 {
+        row.push_str(&format!(
+            " ({})",
+            lang.format(Msg::Relays(), &[&station.relays.len()])
+        ));
+    }
+    row.push_str(&relay_status(lang, proxy, active, recording, theme));
+    row
+}
+
+fn relay_row(
+    number: usize,
+    lang: Lang,
+    proxy: &ProxyInfo,
+    active: bool,
+    recording: bool,
+    theme: &Theme,
+) -> String {
+    format!(
+        "   {}. {}{}",
+        number,
+        proxy.addr,
+        relay_status(lang, proxy, active, recording, theme)
+    )
+}
+
+fn relay_status(
+    lang: Lang,
+    proxy: &ProxyInfo,
+    active: bool,
+    recording: bool,
+    theme: &Theme,
+) -> String {
+    let mut row = String::new();
+    if let Some((_, avg, _)) = proxy.latency.stats() {
+        row.push_str(&format!(" [{} ms]", format_ms(avg)));
+    }
+    if recording {
+        row.push_str(&format!(" [{}]", lang.tr(Msg::Recording())));
+    }
+    if let Some(dead_air) = proxy.dead_air.state() {
+        row.push_str(&format!(" [{}]", lang.tr(dead_air.msg())));
+    }
+    if active && theme.monochrome {
+        row.push_str(" *");
+    }
+    row
+}
+
+/// Dumb terminals may not have the star.
+fn favourite_mark(theme: &Theme) -> &'static str {
+    if theme.monochrome {
+        " (*)"
+    } else {
+        " ★"
+    }
+}
+
+fn stream_status(lang: Lang, proxy: &ProxyInfo) -> String {
+    let mut status = match proxy.stream.info() {
+        Some(info) => info.to_string(),
+        None => lang.tr(Msg::UnknownFormat()).to_string(),
+    };
+    let stats = proxy.sequence.stats();
+    let counters = [
+        (Msg::CorruptedFrames(), proxy.stream.corrupted()),
+        (Msg::LostPackets(), stats.lost),
+        (Msg::DuplicatePackets(), stats.duplicates),
+    ];
+    for (msg, count) in counters.iter() {
+        if *count > 0 {
+            status.push_str(", ");
+            status.push_str(&lang.format(*msg, &[count]));
+        }
+    }
+    if let Some((min, avg, max)) = proxy.latency.stats() {
+        status.push_str(", ");
+        status.push_str(&lang.format(
+            Msg::RoundTrip(),
+            &[&format_ms(min), &format_ms(avg), &format_ms(max)],
+        ));
+    }
+    status
+}
+
+fn header(
+    proxies: &[ProxyInfo],
+    active_proxy: &Option<SocketAddr>,
+    timeshift: &Timeshift,
+    session: &Session,
+) -> String {
+    let lang = session.lang;
+    let state = match active_proxy.map(|addr| proxies.iter().find(|x| x.addr == addr)) {
+        None => lang.tr(Msg::StateIdle()).to_string(),
+        Some(None) => lang.tr(Msg::StateLost()).to_string(),
+        Some(Some(proxy)) if timeshift.is_paused() => {
+            lang.format(Msg::StatePaused(), &[&proxy.info])
+        }
+        Some(Some(proxy)) => lang.format(Msg::StatePlaying(), &[&proxy.info]),
+    };
+    let mut text = format!(
+        " {} | {} | ",
+        state,
+        lang.format(Msg::ProxyCount(), &[&proxies.len()])
+    );
+    if let Some(output) = &session.output {
+        text.push_str(&lang.format(Msg::Output(), &[output]));
+        text.push_str(" | ");
+    }
+    text.push_str(&Local::now().format("%H:%M:%S").to_string());
+    let padding = HEADER_WIDTH.saturating_sub(text.chars().count());
+    text.push_str(&" ".repeat(padding));
+    session.theme.paint(&session.theme.header, &text)
+}
+
+fn login_screen(session: &Session) -> String {
+    let mut screen = session.lang.format(
+        Msg::PasswordPrompt(),
+        &[&"*".repeat(session.password.chars().count())],
+    );
+    screen.push_str("\r\n");
+    if let Some(notice) = session.notice {
+        screen.push_str(
+            &session
+                .theme
+                .paint(&session.theme.notice, session.lang.tr(notice)),
+        );
+        screen.push_str("\r\n");
+    }
+    if let Some(warning) = idle_warning(session) {
+        screen.push_str(&warning);
+        screen.push_str("\r\n");
+    }
+    screen
+}
+
+fn idle_warning(session: &Session) -> Option<String> {
+    let remaining = session
+        .disconnect_at?
+        .saturating_duration_since(Instant::now());
+    Some(
+        session.theme.paint(
+            &session.theme.notice,
+            &session
+                .lang
+                .format(Msg::IdleWarning(), &[&remaining.as_secs()]),
+        ),
+    )
+}
+
+fn list_rows(
+    proxies: &[ProxyInfo],
+    stations: &[Station],
+    active_proxy: &Option<SocketAddr>,
+    timeshift: &Timeshift,
+    recorder: &Recorder,
+    session: &Session,
+    stale_after: Duration,
+) -> Vec<String> {
+    let (lang, theme) = (session.lang, &session.theme);
+    let mut rows = vec![];
+    for (i, item) in menu(proxies, stations, active_proxy, session)
+        .iter()
+        .enumerate()
+    {
+        let mut styles = vec![];
+        if i as i64 == session.cursor_line {
+            styles.push(theme.cursor.as_str());
+        }
+        let text = match item {
+            MenuItem::Discover() => lang.tr(Msg::Discover()).to_string(),
+            MenuItem::Station(index) => {
+                let station = &stations[*index];
+                let playing = relays(station, proxies).find(|x| Some(x.addr) == *active_proxy);
+                if playing.is_some() {
+                    styles.push(&theme.active);
+                }
+                if relays(station, proxies).all(|x| is_stale(x, stale_after)) {
+                    styles.push(&theme.stale);
+                }
+                let best = healthiest(station, proxies, stale_after);
+                let proxy = playing
+                    .or_else(|| proxies.iter().find(|x| x.addr == best))
+                    .unwrap();
+                let recording = station.relays.iter().any(|x| recorder.is_recording(x));
+                let style = Theme::combine(&styles);
+                station_row(
+                    session,
+                    i,
+                    station,
+                    proxy,
+                    playing.is_some(),
+                    recording,
+                    &style,
+                )
+            }
+            MenuItem::Proxy(addr) => {
+                let proxy = proxies.iter().find(|x| x.addr == *addr).unwrap();
+                let active = Some(*addr) == *active_proxy;
+                if active {
+                    styles.push(&theme.active);
+                }
+                if is_stale(proxy, stale_after) {
+                    styles.push(&theme.stale);
+                }
+                relay_row(i, lang, proxy, active, recorder.is_recording(addr), theme)
+            }
+            MenuItem::Pause() if timeshift.is_paused() => lang.tr(Msg::Resume()).to_string(),
+            MenuItem::Pause() => lang.tr(Msg::Pause()).to_string(),
+            MenuItem::Rewind() => lang.format(Msg::Rewind(), &[&REWIND_STEP.as_secs()]),
+            MenuItem::SkipToLive() if timeshift.is_live() => lang.tr(Msg::Live()).to_string(),
+            MenuItem::SkipToLive() => lang.format(
+                Msg::LiveDelayed(),
+                &[&format_delay(timeshift.delay(Instant::now()))],
+            ),
+            MenuItem::Quit() => lang.tr(Msg::Quit()).to_string(),
+        };
+        let mut row = theme.paint(&Theme::combine(&styles), &text);
+        if theme.monochrome && i as i64 == session.cursor_line {
+            row.push_str(" <-");
+        }
+        rows.push(row);
+    }
+    match proxies.iter().find(|x| Some(x.addr) == *active_proxy) {
+        Some(proxy) => {
+            rows.push(proxy.meta.clone());
+            rows.push(stream_status(lang, proxy));
+        }
+        None => rows.push("".to_string()),
+    }
+    if session.sort != SortOrder::Arrival() {
+        rows.push(lang.format(Msg::SortedBy(), &[&lang.tr(session.sort.msg())]));
+    }
+    if session.searching {
+        rows.push(lang.format(Msg::Searching(), &[&session.filter]));
+    } else if !session.filter.is_empty() {
+        rows.push(lang.format(Msg::Filter(), &[&session.filter]));
+    }
+    rows
+}
+
+/// Everything known about a single proxy, followed by what can be done with it.
+fn detail_rows(
+    proxy: &ProxyInfo,
+    stations: &[Station],
+    active_proxy: &Option<SocketAddr>,
+    recorder: &Recorder,
+    session: &Session,
+) -> Vec<String> {
+    let (lang, theme) = (session.lang, &session.theme);
+    let station = stations.iter().find(|x| x.relays.contains(&proxy.addr));
+    let mut station_name = station.map_or("".to_string(), |x| x.name.clone());
+    let favourite = station.is_some_and(|x| x.favourite);
+    if favourite {
+        station_name.push_str(favourite_mark(theme));
+    }
+    let silence = SystemTime::now()
+        .duration_since(proxy.last_contact)
+        .unwrap_or_default();
+    let stats = proxy.sequence.stats();
+    let mut rows = vec![
+        lang.format(Msg::DetailAddress(), &[&proxy.addr]),
+        lang.format(Msg::DetailStation(), &[&station_name]),
+        lang.format(Msg::DetailInfo(), &[&proxy.info]),
+        lang.format(Msg::DetailMeta(), &[&proxy.meta]),
+        lang.format(Msg::DetailLastContact(), &[&silence.as_secs()]),
+        lang.format(Msg::DetailStream(), &[&stream_status(lang, proxy)]),
+        lang.format(Msg::DetailPackets(), &[&stats.received, &stats.late]),
+        "".to_string(),
+    ];
+    let active = *active_proxy == Some(proxy.addr);
+    for (i, item) in DETAIL_ITEMS.iter().enumerate() {
+        let msg = match item {
+            DetailItem::Play() if active => Msg::Stop(),
+            DetailItem::Play() => Msg::Play(),
+            DetailItem::Record() if recorder.is_recording(&proxy.addr) => Msg::StopRecording(),
+            DetailItem::Record() => Msg::Record(),
+            DetailItem::Favourite() if favourite => Msg::RemoveFavourite(),
+            DetailItem::Favourite() => Msg::AddFavourite(),
+            DetailItem::Alias() => Msg::SetAlias(),
+            DetailItem::Forget() => Msg::Forget(),
+            DetailItem::Back() => Msg::Back(),
+        };
+        if i as i64 == session.details_line {
+            let mut row = theme.paint(&theme.cursor, lang.tr(msg));
+            if theme.monochrome {
+                row.push_str(" <-");
+            }
+            rows.push(row);
+        } else {
+            rows.push(lang.tr(msg).to_string());
+        }
+    }
+    if let Some(alias) = &session.alias {
+        rows.push(lang.format(Msg::AliasPrompt(), &[alias]));
+    }
+    rows
+}
+
+pub fn generate_ui(
+    proxies: &[ProxyInfo],
+    stations: &[Station],
+    active_proxy: &Option<SocketAddr>,
+    timeshift: &Timeshift,
+    recorder: &Recorder,
+    session: &Session,
+    stale_after: Duration,
+) -> String {
+    if session.role.is_none() {
+        return login_screen(session);
+    }
+    let mut rows = vec![header(proxies, active_proxy, timeshift, session)];
+    match session
+        .details
+        .and_then(|addr| proxies.iter().find(|x| x.addr == addr))
+    {
+        Some(proxy) => rows.extend(detail_rows(
+            proxy,
+            stations,
+            active_proxy,
+            recorder,
+            session,
+        )),
+        None => rows.extend(list_rows(
+            proxies,
+            stations,
+            active_proxy,
+            timeshift,
+            recorder,
+            session,
+            stale_after,
+        )),
+    }
+    finish(rows, session)
+}
+
+/// Planned and recent recordings, followed by a prompt for a new one.
+pub fn generate_schedule_ui(
+    proxies: &[ProxyInfo],
+    active_proxy: &Option<SocketAddr>,
+    timeshift: &Timeshift,
+    jobs: &[Job],
+    session: &Session,
+) -> String {
+    let lang = session.lang;
+    if session.role.is_none() {
+        return login_screen(session);
+    }
+    let mut rows = vec![
+        header(proxies, active_proxy, timeshift, session),
+        lang.tr(Msg::ScheduleTitle()).to_string(),
+    ];
+    if jobs.is_empty() {
+        rows.push(lang.tr(Msg::NoJobs()).to_string());
+    }
+    for job in jobs {
+        let state = match job.state {
+            JobState::Pending() => Msg::JobPending(),
+            JobState::Waiting() => Msg::JobWaiting(),
+            JobState::Recording() => Msg::JobRecording(),
+            JobState::Finished() => Msg::JobFinished(),
+            JobState::Missed() => Msg::JobMissed(),
+            JobState::Failed() => Msg::JobFailed(),
+        };
+        let mut row = format!(
+            "{} - {}  {} [{}]",
+            job.start.format("%Y-%m-%d %H:%M"),
+            job.end.format("%H:%M"),
+            job.station,
+            lang.tr(state)
+        );
+        if !job.name.is_empty() {
+            row.push_str(&format!(" ({})", job.name));
+        }
+        rows.push(row);
+    }
+    rows.push("".to_string());
+    rows.push(lang.format(
+        Msg::SchedulePrompt(),
+        &[&session.schedule.as_deref().unwrap_or_default()],
+    ));
+    finish(rows, session)
+}
+
+/// Adds the notice and the idle warning below a screen's rows and joins them.
+fn finish(mut rows: Vec<String>, session: &Session) -> String {
+    let theme = &session.theme;
+    if let Some(notice) = session.notice {
+        rows.push(theme.paint(&theme.notice, session.lang.tr(notice)));
+    }
+    rows.extend(idle_warning(session));
     for row in &mut rows {
         row.push_str("\r\n");
     }
     rows.concat()
 }
 
-pub fn render(text: &str) {
-    CHANNEL_TELNET_S
-        .send(EventTelnet::Write(Arc::from(
-            &[telnet_sequence::CLEAR_SCREEN, text.as_bytes()].concat()[..],
-        )))
-        .unwrap();
+/// Ends the current telnet session.
+pub fn disconnect(telnet: &Topic<EventTelnet>, id: SessionId) {
+    telnet.publish(EventTelnet::Close(id));
 }
 
-pub fn interpret_input(buf: &mut Vec<u8>, input: u8) -> UserInput {
-    let max_sequence_length = 3;
-    if max_sequence_length <= buf.len() {
-        buf.pop();
-    }
-    buf.insert(0, input);
-
-    match buf.as_slice() {
-        [65, 91, 27, ..] => UserInput::Up(),
-        [66, 91, 27, ..] => UserInput::Down(),
-        [0, 13, ..] | [10, 13, ..] => UserInput::Select(),
-        _ => UserInput::Unrecognized(),
+/// Sends a screen update produced by `Screen::update`.
+pub fn render(telnet: &Topic<EventTelnet>, id: SessionId, update: &str) {
+    if !update.is_empty() {
+        telnet.publish(EventTelnet::Write((id, Arc::from(update.as_bytes()))));
     }
 }
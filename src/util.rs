@@ -14,7 +14,7 @@ macro_rules! continue_on_err {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::channels::CHANNEL_LOG_R;
+    use crate::log::CHANNEL_LOG_R;
     use anyhow::anyhow;
     use rusty_fork::rusty_fork_test;
     use std::time::Duration;
@@ -22,8 +22,8 @@ pub mod tests {
     rusty_fork_test! {
         #[test]
         fn continue_on_err_works() {
-            const MSG: &'static str = "something";
-            const ERR_MSG: &'static str = "test message";
+            const MSG: &str = "something";
+            const ERR_MSG: &str = "test message";
             let expected_log = format!("{}: {:?}", MSG, anyhow!(ERR_MSG));
             let mut last_i = 2;
             for i in 0..2 {
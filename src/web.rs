@@ -0,0 +1,637 @@
+use crate::access::{AccessPolicy, Role};
+use crate::bus::{Bus, Subscription, TopicStats};
+use crate::events::{ControlCommand, EventModel, EventWeb};
+use crate::model::ProxyInfo;
+use crate::schedule::{Job, JobState};
+use crate::timeshift::Timeshift;
+use anyhow::{anyhow, Context, Result};
+use crossbeam::crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const PAGE: &str = include_str!("web.html");
+
+// Appended to the client's key to prove that the server understands WebSockets (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Commands are tiny, so anything bigger than this is a misbehaving client.
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+const MAX_REQUEST_LINES: usize = 100;
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+/// Connections beyond this are closed right away, so that they can't exhaust threads.
+const MAX_CONNECTIONS: usize = 64;
+/// How many frames may wait for a slow client. States are complete snapshots, so a client
+/// that falls behind only misses some of them.
+const CLIENT_QUEUE: usize = 16;
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client may stall a write before it's dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+mod opcodes {
+    pub const TEXT: u8 = 1;
+    pub const CLOSE: u8 = 8;
+    pub const PING: u8 = 9;
+    pub const PONG: u8 = 10;
+}
+
+/// The WebSocket clients, which the writer thread sends states to. Each client has a thread
+/// writing its queued frames.
+type Clients = Arc<Mutex<Vec<(SocketAddr, Sender<Arc<[u8]>>)>>>;
+
+/// A station as shown in the web interface.
+#[derive(Serialize)]
+struct Station {
+    addr: String,
+    name: String,
+    meta: String,
+    active: bool,
+    stream: Option<String>,
+    corrupted_frames: u64,
+    lost_packets: u64,
+    duplicate_packets: u64,
+    /// Minimum, average and maximum round-trip time in milliseconds.
+    rtt: Option<[f64; 3]>,
+    silence: f64,
+    /// `silence`, `stalled` or `repeating`.
+    dead_air: Option<&'static str>,
+}
+
+/// A planned or recent recording as shown in the web interface.
+#[derive(Serialize)]
+struct ScheduledJob {
+    name: String,
+    station: String,
+    start: String,
+    end: String,
+    state: &'static str,
+}
+
+/// Everything the web interface displays. It's sent to all clients whenever it may have changed.
+#[derive(Serialize)]
+struct State {
+    stations: Vec<Station>,
+    jobs: Vec<ScheduledJob>,
+    paused: bool,
+    live: bool,
+    delay: f64,
+    /// The client's internal queues, for diagnosing a slow interface.
+    queues: Vec<TopicStats>,
+}
+
+pub fn state(
+    proxies: &[ProxyInfo],
+    active_proxy: &Option<SocketAddr>,
+    timeshift: &Timeshift,
+    jobs: &[Job],
+    queues: Vec<TopicStats>,
+) -> String {
+    let now = SystemTime::now();
+    let state = State {
+        stations: proxies
+            .iter()
+            .map(|proxy| Station {
+                addr: proxy.addr.to_string(),
+                name: proxy.info.clone(),
+                meta: proxy.meta.clone(),
+                active: Some(proxy.addr) == *active_proxy,
+                stream: proxy.stream.info().map(|x| x.to_string()),
+                corrupted_frames: proxy.stream.corrupted(),
+                lost_packets: proxy.sequence.stats().lost,
+                duplicate_packets: proxy.sequence.stats().duplicates,
+                rtt: proxy
+                    .latency
+                    .stats()
+                    .map(|(min, avg, max)| [min, avg, max].map(|x| x.as_secs_f64() * 1000.0)),
+                silence: now
+                    .duration_since(proxy.last_contact)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+                dead_air: proxy.dead_air.state().map(|x| x.name()),
+            })
+            .collect(),
+        jobs: jobs
+            .iter()
+            .map(|job| ScheduledJob {
+                name: job.name.clone(),
+                station: job.station.clone(),
+                start: job.start.format("%Y-%m-%d %H:%M").to_string(),
+                end: job.end.format("%Y-%m-%d %H:%M").to_string(),
+                state: match job.state {
+                    JobState::Pending() => "pending",
+                    JobState::Waiting() => "waiting",
+                    JobState::Recording() => "recording",
+                    JobState::Finished() => "finished",
+                    JobState::Missed() => "missed",
+                    JobState::Failed() => "failed",
+                },
+            })
+            .collect(),
+        paused: timeshift.is_paused(),
+        live: timeshift.is_live(),
+        delay: timeshift.delay(Instant::now()).as_secs_f64(),
+        queues,
+    };
+    serde_json::to_string(&state).unwrap()
+}
+
+/// A command sent by the web interface, e.g. `{"cmd": "play", "addr": "10.0.0.1:5000"}` or
+/// `{"cmd": "schedule", "spec": "20:00; 1h; Radio Jazz"}`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+enum Command {
+    Discover,
+    Play { addr: SocketAddr },
+    Stop,
+    Pause,
+    Resume,
+    Rewind { seconds: u64 },
+    Live,
+    Schedule { spec: String },
+}
+
+impl Command {
+    fn into_control(self, max_rewind: Duration) -> ControlCommand {
+        match self {
+            Command::Discover => ControlCommand::Discover(),
+            Command::Play { addr } => ControlCommand::Play(addr),
+            Command::Stop => ControlCommand::Stop(),
+            Command::Pause => ControlCommand::Pause(),
+            Command::Resume => ControlCommand::Resume(),
+            // Rewinding further than the buffer reaches makes no difference.
+            Command::Rewind { seconds } => {
+                ControlCommand::Rewind(Duration::from_secs(seconds).min(max_rewind))
+            }
+            Command::Live => ControlCommand::SkipToLive(),
+            Command::Schedule { spec } => ControlCommand::Schedule(spec),
+        }
+    }
+}
+
+/// What the threads serving connections share.
+struct Shared {
+    access: AccessPolicy,
+    bus: Bus,
+    clients: Clients,
+    connections: AtomicUsize,
+    /// The timeshift window, which rewinds are clamped to.
+    max_rewind: Duration,
+}
+
+pub struct WebServer<'a> {
+    host: &'a str,
+    port: u16,
+    shared: Arc<Shared>,
+}
+
+impl WebServer<'_> {
+    pub fn new(
+        host: &str,
+        port: u16,
+        access: AccessPolicy,
+        bus: Bus,
+        max_rewind: Duration,
+    ) -> WebServer<'_> {
+        WebServer {
+            host,
+            port,
+            shared: Arc::new(Shared {
+                access,
+                bus,
+                clients: Arc::new(Mutex::new(vec![])),
+                connections: AtomicUsize::new(0),
+                max_rewind,
+            }),
+        }
+    }
+
+    /// Serves the interface, sending the states published to the bus's web topic through
+    /// `updates` to its clients.
+    pub fn start(&mut self, updates: Subscription<EventWeb>) {
+        match || -> Result<()> {
+            let listener = TcpListener::bind((self.host, self.port)).context("bind failed")?;
+            {
+                let clients = self.shared.clients.clone();
+                thread::spawn(move || start_writer(&clients, updates));
+            }
+
+            for result in listener.incoming() {
+                match result {
+                    Ok(stream) => {
+                        let shared = self.shared.clone();
+                        if shared.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            shared.connections.fetch_sub(1, Ordering::SeqCst);
+                            log!("too many HTTP connections, rejected one");
+                            continue;
+                        }
+                        thread::spawn(move || {
+                            if let Err(err) = handle_connection(stream, &shared) {
+                                log!("HTTP connection dropped: {:?}", err);
+                            }
+                            shared.connections.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(err) => log!("failed to unpack a new TCP stream: {:?}", err),
+                }
+            }
+
+            Ok(())
+        }() {
+            Ok(()) => (),
+            Err(e) => self
+                .shared
+                .bus
+                .model
+                .publish(EventModel::WebServerCrashed(Arc::from(e.to_string()))),
+        }
+    }
+}
+
+fn start_writer(clients: &Clients, updates: Subscription<EventWeb>) {
+    while let Ok(EventWeb::Publish(state)) = updates.recv() {
+        let frame: Arc<[u8]> = Arc::from(encode_frame(opcodes::TEXT, state.as_bytes()));
+        clients.lock().unwrap().retain(|(_, frames)| {
+            !matches!(
+                frames.try_send(frame.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+}
+
+/// Writes the frames queued for a client. A client that stalls is disconnected, which also
+/// ends the thread reading from it.
+fn write_frames(addr: SocketAddr, mut stream: TcpStream, frames: Receiver<Arc<[u8]>>) {
+    for frame in frames.iter() {
+        if let Err(err) = stream.write_all(&frame) {
+            log!("websocket write failure for {}: {:?}", addr, err);
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, shared: &Shared) -> Result<()> {
+    let access = &shared.access;
+    let peer = stream.peer_addr()?;
+    if !access.allows(peer.ip()) {
+        log!("rejected an HTTP connection from {}", peer);
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    let mut request_line = String::new();
+    read_line(&mut reader, &mut request_line)?;
+    let headers = read_headers(&mut reader)?;
+    let header = |name: &str| find_header(&headers, name);
+    let role = access.default_role().or_else(|| {
+        header("authorization")
+            .and_then(basic_auth_password)
+            .and_then(|password| access.role_for(&password))
+    });
+    let role = match role {
+        Some(role) => role,
+        None => {
+            stream.write_all(
+                b"HTTP/1.1 401 Unauthorized\r\n\
+                  WWW-Authenticate: Basic realm=\"skclient\"\r\n\
+                  Content-Length: 0\r\n\
+                  Connection: close\r\n\r\n",
+            )?;
+            return Ok(());
+        }
+    };
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    match (path, header("sec-websocket-key")) {
+        ("/", _) => write_response(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE),
+        ("/ws", Some(_)) if !same_origin(header("origin"), header("host")) => {
+            log!(
+                "rejected a websocket from {} for origin {:?}",
+                peer,
+                header("origin")
+            );
+            write_response(&mut stream, "403 Forbidden", "text/plain", "Forbidden")
+        }
+        ("/ws", Some(key)) => {
+            stream.write_all(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(key)
+                )
+                .as_bytes(),
+            )?;
+            handle_websocket(reader, stream, role, shared)
+        }
+        _ => write_response(&mut stream, "404 Not Found", "text/plain", "Not Found"),
+    }
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    stream.write_all(
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+fn handle_websocket(
+    mut reader: impl Read,
+    stream: TcpStream,
+    role: Role,
+    shared: &Shared,
+) -> Result<()> {
+    let (clients, bus) = (&shared.clients, &shared.bus);
+    let addr = stream.peer_addr()?;
+    // An open interface may stay idle for as long as the user wants.
+    stream.set_read_timeout(None)?;
+    let (sender, frames) = bounded(CLIENT_QUEUE);
+    thread::spawn(move || write_frames(addr, stream, frames));
+    clients.lock().unwrap().push((addr, sender));
+    bus.model.publish(EventModel::NewWebClient());
+    let result = || -> Result<()> {
+        loop {
+            let (opcode, payload) = read_frame(&mut reader)?;
+            match opcode {
+                opcodes::TEXT => match serde_json::from_slice::<Command>(&payload) {
+                    Ok(_) if role == Role::ReadOnly() => {
+                        log!("ignored a command from read-only client {}", addr)
+                    }
+                    Ok(cmd) => bus
+                        .model
+                        .publish(EventModel::Control(cmd.into_control(shared.max_rewind))),
+                    Err(err) => log!("invalid command from {}: {:?}", addr, err),
+                },
+                opcodes::PING => send_to(clients, addr, &encode_frame(opcodes::PONG, &payload))?,
+                opcodes::CLOSE => {
+                    send_to(clients, addr, &encode_frame(opcodes::CLOSE, &[]))?;
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+    }();
+    clients.lock().unwrap().retain(|(x, _)| *x != addr);
+    result
+}
+
+// Writes go through the client's queue, so that they don't interleave with the states.
+fn send_to(clients: &Clients, addr: SocketAddr, frame: &[u8]) -> Result<()> {
+    match clients.lock().unwrap().iter().find(|(x, _)| *x == addr) {
+        Some((_, frames)) => match frames.try_send(Arc::from(frame)) {
+            Err(TrySendError::Disconnected(_)) => Err(anyhow!("client is gone")),
+            _ => Ok(()),
+        },
+        None => Err(anyhow!("client is gone")),
+    }
+}
+
+/// Reads a line like `BufRead::read_line`, but fails on lines longer than `MAX_LINE_LENGTH`.
+pub fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let len = reader.take(MAX_LINE_LENGTH).read_line(line)?;
+    if len as u64 == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(anyhow!("request line too long"));
+    }
+    Ok(len)
+}
+
+/// Browsers send the page's origin with WebSocket requests, which lets other sites' pages be
+/// told apart from the interface. Clients that aren't browsers don't send one.
+fn same_origin(origin: Option<&str>, host: Option<&str>) -> bool {
+    match (origin, host) {
+        (None, _) => true,
+        (Some(origin), Some(host)) => origin
+            .split("://")
+            .nth(1)
+            .is_some_and(|x| x.eq_ignore_ascii_case(host)),
+        (Some(_), None) => false,
+    }
+}
+
+/// Reads the headers of a request up to the empty line that ends them. Names are lowercased,
+/// as they are case-insensitive.
+pub fn read_headers(reader: &mut impl BufRead) -> Result<Vec<(String, String)>> {
+    let mut headers = vec![];
+    for _ in 0..MAX_REQUEST_LINES {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some(pos) = line.find(':') {
+            headers.push((
+                line[..pos].trim().to_lowercase(),
+                line[pos + 1..].trim().to_string(),
+            ));
+        }
+    }
+    Ok(headers)
+}
+
+/// Returns the value of the header with the given lowercase name.
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Extracts the password from an `Authorization: Basic ...` header. The user name is ignored.
+pub fn basic_auth_password(header: &str) -> Option<String> {
+    let credentials = base64::decode(header.strip_prefix("Basic ")?).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let pos = credentials.find(':')?;
+    Some(credentials[pos + 1..].to_string())
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
+
+/// Reads a single frame sent by a client and returns its opcode and unmasked payload.
+/// Fragmented messages aren't supported, as no command needs more than one frame.
+fn read_frame(reader: &mut impl Read) -> Result<(u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let opcode = head[0] & 0x0F;
+    // Clients must mask their frames, see RFC 6455, section 5.1.
+    if head[1] & 0x80 == 0 {
+        return Err(anyhow!("unmasked client frame"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut buf = [0; 2];
+            reader.read_exact(&mut buf)?;
+            u16::from_be_bytes(buf) as u64
+        }
+        127 => {
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf)
+        }
+        len => len as u64,
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!("frame too large: {} bytes", len));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+/// Builds an unmasked, unfragmented frame, as sent by servers.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn extracts_basic_auth_passwords() {
+        assert_eq!(
+            basic_auth_password("Basic dXNlcjpzZTpjcmV0"),
+            Some("se:cret".to_string())
+        );
+        assert_eq!(basic_auth_password("Bearer xyz"), None);
+    }
+
+    #[test]
+    fn reads_masked_frames() {
+        // The "Hello" example from RFC 6455, section 5.7.
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (opcode, payload) = read_frame(&mut Cursor::new(&frame[..])).unwrap();
+        assert_eq!(opcode, opcodes::TEXT);
+        assert_eq!(payload, b"Hello");
+        let mut long = encode_frame(opcodes::TEXT, &[b'x'; 300]);
+        assert_eq!(&long[..4], &[0x81, 126, 1, 44]);
+        assert!(read_frame(&mut Cursor::new(&long)).is_err());
+        // The same frame masked with zeros.
+        long[1] |= 0x80;
+        long.splice(4..4, [0; 4].iter().copied());
+        assert_eq!(
+            read_frame(&mut Cursor::new(long)).unwrap().1,
+            vec![b'x'; 300]
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        let cmd: Command =
+            serde_json::from_str(r#"{"cmd": "play", "addr": "10.0.0.1:5000"}"#).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Play {
+                addr: "10.0.0.1:5000".parse().unwrap()
+            }
+        );
+        let cmd: Command = serde_json::from_str(r#"{"cmd": "rewind", "seconds": 10}"#).unwrap();
+        assert_eq!(cmd, Command::Rewind { seconds: 10 });
+        let cmd: Command =
+            serde_json::from_str(r#"{"cmd": "schedule", "spec": "20:00; 1h; Jedynka"}"#).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Schedule {
+                spec: "20:00; 1h; Jedynka".to_string()
+            }
+        );
+        assert!(serde_json::from_str::<Command>(r#"{"cmd": "fly"}"#).is_err());
+    }
+
+    #[test]
+    fn checks_websocket_origins() {
+        assert!(same_origin(None, Some("radio:8080")));
+        assert!(same_origin(Some("http://radio:8080"), Some("radio:8080")));
+        assert!(same_origin(Some("https://Radio:8080"), Some("radio:8080")));
+        assert!(!same_origin(
+            Some("http://evil.example"),
+            Some("radio:8080")
+        ));
+        assert!(!same_origin(Some("null"), Some("radio:8080")));
+        assert!(!same_origin(Some("http://radio:8080"), None));
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let mut line = String::new();
+        read_line(&mut &b"GET / HTTP/1.1\r\nHost: x\r\n"[..], &mut line).unwrap();
+        assert_eq!(line, "GET / HTTP/1.1\r\n");
+        let long = vec![b'a'; MAX_LINE_LENGTH as usize + 1];
+        assert!(read_line(&mut &long[..], &mut String::new()).is_err());
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let request = b"AUTHORIZATION: Basic OnNlY3JldA==\r\nHost: x\r\n\r\nbody: x\r\n";
+        let headers = read_headers(&mut &request[..]).unwrap();
+        assert_eq!(
+            find_header(&headers, "authorization").and_then(basic_auth_password),
+            Some("secret".to_string())
+        );
+        assert_eq!(find_header(&headers, "host"), Some("x"));
+        assert_eq!(find_header(&headers, "body"), None);
+    }
+
+    #[test]
+    fn clamps_rewinds_to_the_window() {
+        let window = Duration::from_secs(60);
+        let cmd = Command::Rewind { seconds: 10 }.into_control(window);
+        assert!(matches!(cmd, ControlCommand::Rewind(x) if x == Duration::from_secs(10)));
+        let cmd = Command::Rewind { seconds: u64::MAX }.into_control(window);
+        assert!(matches!(cmd, ControlCommand::Rewind(x) if x == window));
+    }
+}